@@ -0,0 +1,28 @@
+// Runs a ROM without a window and saves the last frame.
+// cargo run --example headless --no-default-features -- <rom> [frames] [out.png]
+
+use rusty_nes::Nes;
+
+fn main() -> Result<(), String> {
+    let mut args = std::env::args().skip(1);
+    let rom = args
+        .next()
+        .ok_or("Usage: headless <rom> [frames] [out.png]")?;
+    let frames: usize = match args.next() {
+        Some(n) => n.parse().map_err(|e| format!("Bad frame count: {}", e))?,
+        None => 60,
+    };
+    let out = args.next().unwrap_or_else(|| String::from("frame.png"));
+
+    let mut nes = Nes::new();
+    nes.load_rom(rom)?;
+    if let Some(info) = nes.rom_info() {
+        println!("Mapper {}, {:?}", info.mapper, info.region);
+    }
+    for _ in 0..frames {
+        nes.run_frame();
+    }
+    nes.frame().save(&out).map_err(|e| e.to_string())?;
+    println!("Saved frame {} to {}", frames, out);
+    Ok(())
+}
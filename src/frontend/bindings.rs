@@ -0,0 +1,218 @@
+// Turns key presses into controller buttons and hotkeys, following the
+// [keys] section of the config.
+
+use iced::keyboard::Key;
+
+use rusty_nes::{ControllerState, KEYBOARD_LAYOUT};
+
+use super::config::KeyConfig;
+use super::keys;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    Pause,
+    FrameAdvance,
+    Reset,
+    PowerCycle,
+    Open,
+    // Held
+    FastForward,
+    SlowMotion,
+    // Held
+    Rewind,
+    SaveState,
+    LoadState,
+    Screenshot,
+    KeyboardCapture,
+    RecordMacro,
+    PlayMacro,
+}
+
+impl Hotkey {
+    fn from_name(name: &str) -> Option<Hotkey> {
+        Some(match name {
+            "pause" => Hotkey::Pause,
+            "frame_advance" => Hotkey::FrameAdvance,
+            "reset" => Hotkey::Reset,
+            "power_cycle" => Hotkey::PowerCycle,
+            "open" => Hotkey::Open,
+            "fast_forward" => Hotkey::FastForward,
+            "slow_motion" => Hotkey::SlowMotion,
+            "rewind" => Hotkey::Rewind,
+            "save_state" => Hotkey::SaveState,
+            "load_state" => Hotkey::LoadState,
+            "screenshot" => Hotkey::Screenshot,
+            "keyboard_capture" => Hotkey::KeyboardCapture,
+            "record_macro" => Hotkey::RecordMacro,
+            "play_macro" => Hotkey::PlayMacro,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    // Player (0 to 3) and button name as in the config
+    Pad(usize, &'static str),
+    // 0 for button 1
+    PowerPad(usize),
+    Hotkey(Hotkey),
+}
+
+#[derive(Default)]
+pub struct Bindings {
+    keys: Vec<(Key, Action)>,
+}
+
+impl Bindings {
+    // Bindings are validated with the config, bad ones are skipped
+    pub fn new(config: &KeyConfig) -> Bindings {
+        let pad1 = config
+            .player1
+            .iter()
+            .map(|(button, name)| (Action::Pad(0, button), name));
+        let pad2 = config
+            .player2
+            .iter()
+            .map(|(button, name)| (Action::Pad(1, button), name));
+        let pad3 = config
+            .player3
+            .iter()
+            .map(|(button, name)| (Action::Pad(2, button), name));
+        let pad4 = config
+            .player4
+            .iter()
+            .map(|(button, name)| (Action::Pad(3, button), name));
+        let power_pad = config.power_pad.iter().filter_map(|(button, name)| {
+            let number: usize = button.strip_prefix("button")?.parse().ok()?;
+            Some((Action::PowerPad(number - 1), name))
+        });
+        let hotkeys = config
+            .hotkeys
+            .iter()
+            .filter_map(|(hotkey, name)| Some((Action::Hotkey(Hotkey::from_name(hotkey)?), name)));
+        Bindings {
+            keys: pad1
+                .chain(pad2)
+                .chain(pad3)
+                .chain(pad4)
+                .chain(power_pad)
+                .chain(hotkeys)
+                .filter_map(|(action, name)| Some((keys::parse_key(name)?, action)))
+                .collect(),
+        }
+    }
+
+    pub fn action(&self, key: &Key) -> Option<Action> {
+        let key = keys::normalize(key.clone());
+        self.keys
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, action)| *action)
+    }
+}
+
+// The Family BASIC key in the same place on a PC keyboard
+pub fn family_key(key: &Key) -> Option<&'static str> {
+    use iced::keyboard::key::Named;
+
+    let name = match keys::normalize(key.clone()) {
+        Key::Character(c) => match c.as_str() {
+            "\\" => "Yen",
+            c => {
+                return KEYBOARD_LAYOUT
+                    .iter()
+                    .copied()
+                    .find(|k| k.eq_ignore_ascii_case(c));
+            }
+        },
+        Key::Named(named) => match named {
+            Named::Enter => "Return",
+            Named::Shift => "LShift",
+            Named::Control => "Ctr",
+            Named::Alt => "Grph",
+            Named::Tab => "Kana",
+            Named::End => "Stop",
+            Named::Escape => "Esc",
+            Named::Home => "ClrHome",
+            Named::Insert => "Ins",
+            Named::Delete | Named::Backspace => "Del",
+            Named::Space => "Space",
+            Named::ArrowUp => "Up",
+            Named::ArrowDown => "Down",
+            Named::ArrowLeft => "Left",
+            Named::ArrowRight => "Right",
+            Named::F1 => "F1",
+            Named::F2 => "F2",
+            Named::F3 => "F3",
+            Named::F4 => "F4",
+            Named::F5 => "F5",
+            Named::F6 => "F6",
+            Named::F7 => "F7",
+            Named::F8 => "F8",
+            _ => return None,
+        },
+        Key::Unidentified => return None,
+    };
+    Some(name)
+}
+
+pub fn set_button(state: &mut ControllerState, button: &str, pressed: bool) {
+    let field = match button {
+        "up" => &mut state.up,
+        "down" => &mut state.down,
+        "left" => &mut state.left,
+        "right" => &mut state.right,
+        "a" => &mut state.a,
+        "b" => &mut state.b,
+        "select" => &mut state.select,
+        "start" => &mut state.start,
+        _ => return,
+    };
+    *field = pressed;
+}
+
+#[cfg(test)]
+mod tests {
+    use iced::keyboard::{Key, key::Named};
+
+    use super::super::config::KeyConfig;
+    use super::{Action, Bindings, Hotkey, family_key};
+
+    #[test]
+    fn test_bindings() {
+        let mut config = KeyConfig::default();
+        config.hotkeys.screenshot = String::new();
+        let bindings = Bindings::new(&config);
+        assert_eq!(
+            bindings.action(&Key::Character("X".into())),
+            Some(Action::Pad(0, "a"))
+        );
+        assert_eq!(
+            bindings.action(&Key::Character("w".into())),
+            Some(Action::Pad(1, "up"))
+        );
+        assert_eq!(
+            bindings.action(&Key::Character("c".into())),
+            Some(Action::Pad(0, "turbo_a"))
+        );
+        assert_eq!(
+            bindings.action(&Key::Named(Named::F7)),
+            Some(Action::Hotkey(Hotkey::PlayMacro))
+        );
+        assert_eq!(
+            bindings.action(&Key::Named(Named::Backspace)),
+            Some(Action::Hotkey(Hotkey::Rewind))
+        );
+        assert_eq!(
+            bindings.action(&Key::Character("=".into())),
+            Some(Action::PowerPad(11))
+        );
+        assert_eq!(bindings.action(&Key::Named(Named::F12)), None);
+
+        assert_eq!(family_key(&Key::Character("q".into())), Some("Q"));
+        assert_eq!(family_key(&Key::Character("@".into())), Some("@"));
+        assert_eq!(family_key(&Key::Named(Named::Enter)), Some("Return"));
+        assert_eq!(family_key(&Key::Named(Named::F9)), None);
+    }
+}
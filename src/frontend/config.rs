@@ -0,0 +1,638 @@
+// Frontend and core options, stored as TOML in the config directory. Missing
+// fields take their defaults, unknown ones are an error so typos don't go
+// unnoticed.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::de::{Error, IgnoredAny};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use rusty_nes::runner::Speed;
+use rusty_nes::{Palette, Region, SYSTEM_PALETTE};
+
+use super::{gamepad, keys};
+
+pub const MAX_SCALE: u32 = 8;
+// Any faster and a 60Hz game sees the button held down
+const MAX_TURBO_RATE: u32 = 30;
+const MAX_FAST_FORWARD: f64 = 16.0;
+const MIN_SLOW_MOTION: f64 = 0.1;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub keys: KeyConfig,
+    pub video: VideoConfig,
+    pub paths: PathConfig,
+    pub input: InputConfig,
+    pub gamepad: GamepadConfig,
+    pub speed: SpeedConfig,
+    pub region: RegionSetting,
+    // Older versions saved an [audio] section that nothing read. Skipped so
+    // those files still load, and dropped the next time the config is saved
+    #[serde(skip_serializing)]
+    audio: IgnoredAny,
+}
+
+// Key names as understood by keys::parse_key, empty for unbound
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyConfig {
+    #[serde(deserialize_with = "player_keys::<_, 0>")]
+    pub player1: PadBindings,
+    #[serde(deserialize_with = "player_keys::<_, 1>")]
+    pub player2: PadBindings,
+    // Only used with a multitap
+    #[serde(deserialize_with = "player_keys::<_, 2>")]
+    pub player3: PadBindings,
+    #[serde(deserialize_with = "player_keys::<_, 3>")]
+    pub player4: PadBindings,
+    // Power Pad or Family Trainer, laid out like the mat
+    pub power_pad: PowerPadBindings,
+    pub hotkeys: HotkeyBindings,
+}
+
+impl Default for KeyConfig {
+    fn default() -> Self {
+        KeyConfig {
+            player1: PadBindings::for_player(0),
+            player2: PadBindings::for_player(1),
+            player3: PadBindings::for_player(2),
+            player4: PadBindings::for_player(3),
+            power_pad: PowerPadBindings::default(),
+            hotkeys: HotkeyBindings::default(),
+        }
+    }
+}
+
+impl KeyConfig {
+    // ("player1.up", "ArrowUp") and so on
+    pub fn iter(&self) -> impl Iterator<Item = (String, &str)> {
+        self.player1
+            .iter()
+            .map(|(action, key)| (format!("player1.{}", action), key))
+            .chain(
+                self.player2
+                    .iter()
+                    .map(|(action, key)| (format!("player2.{}", action), key)),
+            )
+            .chain(
+                self.player3
+                    .iter()
+                    .map(|(action, key)| (format!("player3.{}", action), key)),
+            )
+            .chain(
+                self.player4
+                    .iter()
+                    .map(|(action, key)| (format!("player4.{}", action), key)),
+            )
+            .chain(
+                self.power_pad
+                    .iter()
+                    .map(|(action, key)| (format!("power_pad.{}", action), key)),
+            )
+            .chain(
+                self.hotkeys
+                    .iter()
+                    .map(|(action, key)| (format!("hotkeys.{}", action), key)),
+            )
+    }
+
+    // Takes names as returned by iter
+    pub fn set(&mut self, name: &str, key: String) -> bool {
+        let Some((section, action)) = name.split_once('.') else {
+            return false;
+        };
+        let slot = match section {
+            "player1" => self.player1.slot(action),
+            "player2" => self.player2.slot(action),
+            "player3" => self.player3.slot(action),
+            "player4" => self.player4.slot(action),
+            "power_pad" => self.power_pad.slot(action),
+            "hotkeys" => self.hotkeys.slot(action),
+            _ => None,
+        };
+        match slot {
+            Some(slot) => {
+                *slot = key;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Declares a struct of key names with its defaults and an iterator over
+// (field, key)
+macro_rules! bindings {
+    ($name:ident { $($field:ident: $default:expr),* $(,)? }) => {
+        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        pub struct $name {
+            $(pub $field: String,)*
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name {
+                    $($field: String::from($default),)*
+                }
+            }
+        }
+
+        impl $name {
+            pub fn iter(&self) -> impl Iterator<Item = (&'static str, &str)> {
+                [$((stringify!($field), self.$field.as_str())),*].into_iter()
+            }
+
+            fn slot(&mut self, action: &str) -> Option<&mut String> {
+                match action {
+                    $(stringify!($field) => Some(&mut self.$field),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+// One type for every player, PadBindings::for_player picks the defaults
+bindings!(PadBindings {
+    up: "ArrowUp",
+    down: "ArrowDown",
+    left: "ArrowLeft",
+    right: "ArrowRight",
+    a: "x",
+    b: "z",
+    select: "Shift",
+    start: "Enter",
+    // A and B with autofire
+    turbo_a: "c",
+    turbo_b: "v",
+});
+
+// Players 2 to 4 in field order, player 1 has the defaults above
+const OTHER_PAD_DEFAULTS: [[&str; 10]; 3] = [
+    ["w", "s", "a", "d", "g", "f", "q", "e", "", ""],
+    ["", "", "", "", "", "", "", "", "", ""],
+    ["", "", "", "", "", "", "", "", "", ""],
+];
+
+impl PadBindings {
+    // Player 0 to 3
+    pub fn for_player(player: usize) -> Self {
+        let mut pad = PadBindings::default();
+        if let Some(keys) = player.checked_sub(1).map(|i| OTHER_PAD_DEFAULTS[i]) {
+            let fields: Vec<&str> = pad.iter().map(|(field, _)| field).collect();
+            for (field, key) in fields.into_iter().zip(keys) {
+                *pad.slot(field).unwrap() = String::from(key);
+            }
+        }
+        pad
+    }
+}
+
+// A partial [keys.playerN] table falls back to that player's defaults
+fn player_keys<'de, D: Deserializer<'de>, const PLAYER: usize>(
+    deserializer: D,
+) -> Result<PadBindings, D::Error> {
+    let mut pad = PadBindings::for_player(PLAYER);
+    for (action, key) in BTreeMap::<String, String>::deserialize(deserializer)? {
+        let slot = pad
+            .slot(&action)
+            .ok_or_else(|| D::Error::custom(format!("unknown field `{}`", action)))?;
+        *slot = key;
+    }
+    Ok(pad)
+}
+
+bindings!(PowerPadBindings {
+    button1: "1",
+    button2: "2",
+    button3: "3",
+    button4: "4",
+    button5: "5",
+    button6: "6",
+    button7: "7",
+    button8: "8",
+    button9: "9",
+    button10: "0",
+    button11: "-",
+    button12: "=",
+});
+
+bindings!(HotkeyBindings {
+    pause: "p",
+    frame_advance: "n",
+    reset: "r",
+    power_cycle: "t",
+    open: "o",
+    fast_forward: "Tab",
+    slow_motion: "\\",
+    rewind: "Backspace",
+    save_state: "F5",
+    load_state: "F8",
+    screenshot: "F12",
+    // Sends every key to the Family BASIC keyboard until pressed again
+    keyboard_capture: "F10",
+    // Player 1's buttons, saved per game next to the save state
+    record_macro: "F6",
+    play_macro: "F7",
+});
+
+// SDL GameController names as understood by gamepad::parse_inputs, comma
+// separated, with +/- picking a direction of an axis
+bindings!(GamepadBindings {
+    up: "dpup,-lefty",
+    down: "dpdown,+lefty",
+    left: "dpleft,-leftx",
+    right: "dpright,+leftx",
+    // Where A and B sit on a NES pad
+    a: "b",
+    b: "a",
+    select: "back",
+    start: "start",
+    turbo_a: "y",
+    turbo_b: "x",
+});
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GamepadConfig {
+    // How far a stick or trigger has to move to count, 0.0 to under 1.0
+    pub deadzone: f32,
+    // Extra SDL mappings in gamecontrollerdb.txt format, read at startup.
+    // Empty for none
+    pub mappings: PathBuf,
+    // Every pad uses the same buttons
+    pub buttons: GamepadBindings,
+}
+
+impl Default for GamepadConfig {
+    fn default() -> Self {
+        GamepadConfig {
+            deadzone: 0.25,
+            mappings: PathBuf::new(),
+            buttons: GamepadBindings::default(),
+        }
+    }
+}
+
+impl GamepadConfig {
+    // Takes names as returned by GamepadBindings::iter
+    pub fn set_button(&mut self, name: &str, inputs: String) -> bool {
+        match self.buttons.slot(name) {
+            Some(slot) => {
+                *slot = inputs;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The mappings file's text once it parses
+    pub fn load_mappings(&self) -> Result<String, String> {
+        if self.mappings.as_os_str().is_empty() {
+            return Ok(String::new());
+        }
+        let text = fs::read_to_string(&self.mappings)
+            .map_err(|e| format!("Failed to read {}: {}", self.mappings.display(), e))?;
+        gamepad::parse_mappings(&text)?;
+        Ok(text)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    pub scale: u32,
+    // "default", or the path of a .pal file
+    pub palette: String,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            scale: 2,
+            palette: String::from("default"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathConfig {
+    pub rom_dirs: Vec<PathBuf>,
+    pub save_dir: PathBuf,
+    pub screenshot_dir: PathBuf,
+}
+
+impl Default for PathConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("rusty-nes");
+        PathConfig {
+            rom_dirs: vec![PathBuf::from(".")],
+            save_dir: data_dir.join("saves"),
+            screenshot_dir: data_dir.join("screenshots"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    pub port2: Port2Device,
+    // Players 3 and 4
+    pub multitap: MultitapSetting,
+    pub expansion: ExpansionSetting,
+    // Presses per second on the turbo buttons
+    pub turbo_rate: u32,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        InputConfig {
+            port2: Port2Device::default(),
+            multitap: MultitapSetting::default(),
+            expansion: ExpansionSetting::default(),
+            turbo_rate: 15,
+        }
+    }
+}
+
+// Port 1 always has a standard controller
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Port2Device {
+    #[default]
+    Controller,
+    // Aimed and fired with the mouse
+    Zapper,
+    // Mouse movement turns the knob
+    Arkanoid,
+    // On the power_pad keys
+    PowerPad,
+}
+
+// Famicom expansion port
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpansionSetting {
+    #[default]
+    None,
+    Arkanoid,
+    // The Power Pad's Famicom twin
+    FamilyTrainer,
+    // Family BASIC keyboard and data recorder, typed on while captured
+    Keyboard,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MultitapSetting {
+    #[default]
+    None,
+    // NES Four Score or Satellite, takes over both ports
+    FourScore,
+    // Hori adapter on the Famicom expansion port
+    Hori,
+}
+
+// Multiples of normal speed while fast forward is held or slow motion is on
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpeedConfig {
+    // 0 runs as fast as the host can
+    pub fast_forward: f64,
+    pub slow_motion: f64,
+}
+
+impl Default for SpeedConfig {
+    fn default() -> Self {
+        SpeedConfig {
+            fast_forward: 0.0,
+            slow_motion: 0.5,
+        }
+    }
+}
+
+impl SpeedConfig {
+    pub fn fast_forward_speed(&self) -> Speed {
+        if self.fast_forward == 0.0 {
+            Speed::Uncapped
+        } else {
+            Speed::Scaled(self.fast_forward)
+        }
+    }
+
+    pub fn slow_motion_speed(&self) -> Speed {
+        Speed::Scaled(self.slow_motion)
+    }
+}
+
+// Auto follows the ROM header, picking NTSC for dual region ROMs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionSetting {
+    #[default]
+    Auto,
+    Ntsc,
+    Pal,
+}
+
+impl RegionSetting {
+    pub fn region(&self) -> Option<Region> {
+        match self {
+            RegionSetting::Auto => None,
+            RegionSetting::Ntsc => Some(Region::Ntsc),
+            RegionSetting::Pal => Some(Region::Pal),
+        }
+    }
+}
+
+impl Config {
+    pub fn default_file() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rusty-nes").join("config.toml"))
+    }
+
+    // A missing file gives the defaults
+    pub fn load(path: &Path) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::from_toml(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = toml::to_string_pretty(self).map_err(|e| e.to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Reports every problem, one per line
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        let mut bound: Vec<(String, iced::keyboard::Key)> = Vec::new();
+        for (button, name) in self.keys.iter() {
+            if name.is_empty() {
+                continue;
+            }
+            match keys::parse_key(name) {
+                None => errors.push(format!("keys.{}: unknown key \"{}\"", button, name)),
+                Some(key) => match bound.iter().find(|(_, k)| *k == key) {
+                    Some((other, _)) => errors.push(format!(
+                        "keys.{}: \"{}\" is already bound to {}",
+                        button, name, other
+                    )),
+                    None => bound.push((button, key)),
+                },
+            }
+        }
+        if self.input.multitap == MultitapSetting::FourScore
+            && self.input.port2 != Port2Device::Controller
+        {
+            errors.push(String::from(
+                "input.multitap: port 2 is taken by the multitap, set input.port2 to controller",
+            ));
+        }
+        if self.input.multitap == MultitapSetting::Hori
+            && self.input.expansion != ExpansionSetting::None
+        {
+            errors.push(String::from(
+                "input.expansion: the Hori adapter is using the expansion port",
+            ));
+        }
+        if !(1..=MAX_TURBO_RATE).contains(&self.input.turbo_rate) {
+            errors.push(format!(
+                "input.turbo_rate: must be between 1 and {}",
+                MAX_TURBO_RATE
+            ));
+        }
+        if self.speed.fast_forward != 0.0
+            && !(1.0..=MAX_FAST_FORWARD).contains(&self.speed.fast_forward)
+        {
+            errors.push(format!(
+                "speed.fast_forward: must be 0 for uncapped or between 1 and {}",
+                MAX_FAST_FORWARD
+            ));
+        }
+        if !(MIN_SLOW_MOTION..=1.0).contains(&self.speed.slow_motion) {
+            errors.push(format!(
+                "speed.slow_motion: must be between {} and 1",
+                MIN_SLOW_MOTION
+            ));
+        }
+        for (button, inputs) in self.gamepad.buttons.iter() {
+            if let Err(e) = gamepad::parse_inputs(inputs) {
+                errors.push(format!("gamepad.buttons.{}: {}", button, e));
+            }
+        }
+        if !(0.0..1.0).contains(&self.gamepad.deadzone) {
+            errors.push(String::from(
+                "gamepad.deadzone: must be at least 0 and less than 1",
+            ));
+        }
+        if let Err(e) = self.gamepad.load_mappings() {
+            errors.push(format!("gamepad.mappings: {}", e));
+        }
+        if !(1..=MAX_SCALE).contains(&self.video.scale) {
+            errors.push(format!("video.scale: must be between 1 and {}", MAX_SCALE));
+        }
+        if let Err(e) = self.palette() {
+            errors.push(format!("video.palette: {}", e));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    pub fn palette(&self) -> Result<Palette, String> {
+        if self.video.palette == "default" {
+            return Ok(SYSTEM_PALETTE);
+        }
+        let bytes = fs::read(&self.video.palette)
+            .map_err(|e| format!("Failed to read {}: {}", self.video.palette, e))?;
+        rusty_nes::parse_pal(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusty_nes::runner::Speed;
+
+    use super::{Config, Port2Device, RegionSetting};
+
+    #[test]
+    fn test_config() {
+        let config = Config::default();
+        let text = toml::to_string_pretty(&config).unwrap();
+        assert_eq!(Config::from_toml(&text), Ok(config.clone()));
+
+        // Anything left out keeps its default
+        let partial = Config::from_toml(
+            "region = \"pal\"\n[keys.player2]\na = \"k\"\nb = \"\"\n[input]\nport2 = \"zapper\"\n",
+        )
+        .unwrap();
+        assert_eq!(partial.region, RegionSetting::Pal);
+        assert_eq!(partial.input.port2, Port2Device::Zapper);
+        assert_eq!(partial.keys.player2.a, "k");
+        assert_eq!(partial.keys.player2.b, "");
+        assert_eq!(partial.keys.player2.up, "w");
+        assert_eq!(partial.keys.hotkeys, config.keys.hotkeys);
+        assert_eq!(config.keys.player3.up, "");
+        assert_eq!(config.speed.fast_forward_speed(), Speed::Uncapped);
+
+        let speed = Config::from_toml("[speed]\nfast_forward = 4\nslow_motion = 0.25\n").unwrap();
+        assert_eq!(speed.speed.fast_forward_speed(), Speed::Scaled(4.0));
+        assert_eq!(speed.speed.slow_motion_speed(), Speed::Scaled(0.25));
+
+        let mut keys = config.keys.clone();
+        assert!(keys.set("hotkeys.rewind", String::from("F1")));
+        assert!(!keys.set("hotkeys.rewnid", String::from("F1")));
+        assert_eq!(keys.hotkeys.rewind, "F1");
+
+        assert!(Config::from_toml("[video]\nscael = 2\n").is_err());
+        // The Hori adapter leaves port 2 alone but needs the expansion port
+        assert!(Config::from_toml("[input]\nport2 = \"zapper\"\nmultitap = \"hori\"\n").is_ok());
+        assert!(
+            Config::from_toml("[input]\nmultitap = \"hori\"\nexpansion = \"keyboard\"\n").is_err()
+        );
+        assert_eq!(
+            Config::from_toml("[audio]\nsample_rate = 48000\nlatency_ms = 50\n"),
+            Ok(Config::default())
+        );
+        assert!(Config::from_toml("[keys.player3]\njump = \"x\"\n").is_err());
+        let errors = Config::from_toml(
+            "[keys.player1]\na = \"Hyper\"\n[keys.hotkeys]\npause = \"Enter\"\n[video]\nscale = 0\n[input]\nport2 = \"zapper\"\nmultitap = \"fourscore\"\nturbo_rate = 60\n[speed]\nfast_forward = 0.5\nslow_motion = 2\n",
+        )
+        .unwrap_err();
+        let errors: Vec<&str> = errors.lines().collect();
+        assert_eq!(
+            errors,
+            [
+                "keys.player1.a: unknown key \"Hyper\"",
+                "keys.hotkeys.pause: \"Enter\" is already bound to player1.start",
+                "input.multitap: port 2 is taken by the multitap, set input.port2 to controller",
+                "input.turbo_rate: must be between 1 and 30",
+                "speed.fast_forward: must be 0 for uncapped or between 1 and 16",
+                "speed.slow_motion: must be between 0.1 and 1",
+                "video.scale: must be between 1 and 8",
+            ]
+        );
+    }
+}
@@ -0,0 +1,632 @@
+// Host gamepads. Backends report buttons and axes by their SDL GameController
+// names, "a" for the bottom face button, "dpup", "leftx" and so on, so a pad
+// only needs describing once in an SDL mapping string. Gamepads turns them
+// into NES buttons following [gamepad] in the config, handing pads to
+// players in the order they're plugged in.
+
+use rusty_nes::ControllerState;
+
+use super::bindings;
+use super::config::GamepadConfig;
+
+const PLAYERS: usize = 4;
+
+pub type GamepadId = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    A,
+    B,
+    X,
+    Y,
+    Back,
+    Guide,
+    Start,
+    LeftStick,
+    RightStick,
+    LeftShoulder,
+    RightShoulder,
+    DpUp,
+    DpDown,
+    DpLeft,
+    DpRight,
+}
+
+const BUTTONS: [(&str, Button); 15] = [
+    ("a", Button::A),
+    ("b", Button::B),
+    ("x", Button::X),
+    ("y", Button::Y),
+    ("back", Button::Back),
+    ("guide", Button::Guide),
+    ("start", Button::Start),
+    ("leftstick", Button::LeftStick),
+    ("rightstick", Button::RightStick),
+    ("leftshoulder", Button::LeftShoulder),
+    ("rightshoulder", Button::RightShoulder),
+    ("dpup", Button::DpUp),
+    ("dpdown", Button::DpDown),
+    ("dpleft", Button::DpLeft),
+    ("dpright", Button::DpRight),
+];
+
+// Sticks go from -1.0 to 1.0 with right and down positive, triggers from 0.0
+// to 1.0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Axis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+const AXES: [(&str, Axis); 6] = [
+    ("leftx", Axis::LeftX),
+    ("lefty", Axis::LeftY),
+    ("rightx", Axis::RightX),
+    ("righty", Axis::RightY),
+    ("lefttrigger", Axis::LeftTrigger),
+    ("righttrigger", Axis::RightTrigger),
+];
+
+fn button_by_name(name: &str) -> Option<Button> {
+    BUTTONS.iter().find(|(n, _)| *n == name).map(|(_, b)| *b)
+}
+
+fn axis_by_name(name: &str) -> Option<Axis> {
+    AXES.iter().find(|(n, _)| *n == name).map(|(_, a)| *a)
+}
+
+// Only the gilrs backend and the tests produce events
+#[cfg_attr(not(any(feature = "gamepad", test)), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GamepadEvent {
+    Connected(GamepadId),
+    Disconnected(GamepadId),
+    Button(GamepadId, Button, bool),
+    Axis(GamepadId, Axis, f32),
+}
+
+pub trait GamepadBackend {
+    // Everything that happened since the last call
+    fn poll(&mut self) -> Vec<GamepadEvent>;
+}
+
+// What a NES button is bound to: a button, or one direction of an axis
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PadInput {
+    Button(Button),
+    // True for the positive half
+    Axis(Axis, bool),
+}
+
+// "dpup,-lefty" and so on, empty for unbound
+pub fn parse_inputs(text: &str) -> Result<Vec<PadInput>, String> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let input = match (name.strip_prefix('+'), name.strip_prefix('-')) {
+                (Some(axis), _) => axis_by_name(axis).map(|a| PadInput::Axis(a, true)),
+                (_, Some(axis)) => axis_by_name(axis).map(|a| PadInput::Axis(a, false)),
+                _ => button_by_name(name).map(PadInput::Button),
+            };
+            input.ok_or_else(|| format!("unknown gamepad input \"{}\"", name))
+        })
+        .collect()
+}
+
+// Where a mapping reads an element from on the raw device
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+    Button(usize),
+    // Hat number and direction bit: 1 up, 2 right, 4 down, 8 left
+    Hat(usize, u8),
+    // Axis number, which half (None for all of it) and whether it's inverted
+    Axis(usize, Option<bool>, bool),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    Button(Button),
+    Axis(Axis),
+}
+
+// One line of an SDL gamecontrollerdb.txt, like
+// "03000000...,Name,a:b0,dpup:h0.1,leftx:a0,lefty:a1~,platform:Linux,"
+#[derive(Clone, Debug, PartialEq)]
+pub struct SdlMapping {
+    // Lowercase hex
+    pub guid: String,
+    pub name: String,
+    elements: Vec<(Target, Source)>,
+}
+
+impl SdlMapping {
+    pub fn parse(line: &str) -> Result<SdlMapping, String> {
+        let mut fields = line.trim().split(',');
+        let guid = fields.next().unwrap_or_default();
+        if guid.len() != 32 || !guid.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("\"{}\" is not a 32 digit GUID", guid));
+        }
+        let name = fields
+            .next()
+            .filter(|name| !name.is_empty())
+            .ok_or("missing controller name")?;
+        let mut elements = Vec::new();
+        for field in fields.filter(|field| !field.is_empty()) {
+            let (target, source) = field
+                .split_once(':')
+                .ok_or_else(|| format!("\"{}\" is not name:input", field))?;
+            let target = match (button_by_name(target), axis_by_name(target)) {
+                (Some(button), _) => Target::Button(button),
+                (_, Some(axis)) => Target::Axis(axis),
+                // platform, and buttons the NES has no use for
+                _ => continue,
+            };
+            let source = parse_source(source)
+                .ok_or_else(|| format!("bad input \"{}\" in \"{}\"", source, field))?;
+            elements.push((target, source));
+        }
+        Ok(SdlMapping {
+            guid: guid.to_lowercase(),
+            name: name.to_string(),
+            elements,
+        })
+    }
+}
+
+// "b3", "h0.4", or "a2" with an optional +/- half and ~ to invert
+fn parse_source(text: &str) -> Option<Source> {
+    let (half, text) = match (text.strip_prefix('+'), text.strip_prefix('-')) {
+        (Some(rest), _) => (Some(true), rest),
+        (_, Some(rest)) => (Some(false), rest),
+        _ => (None, text),
+    };
+    let (text, inverted) = match text.strip_suffix('~') {
+        Some(rest) => (rest, true),
+        None => (text, false),
+    };
+    let plain = half.is_none() && !inverted;
+    if let Some(button) = text.strip_prefix('b')
+        && plain
+    {
+        Some(Source::Button(button.parse().ok()?))
+    } else if let Some(hat) = text.strip_prefix('h')
+        && plain
+    {
+        let (hat, bit) = hat.split_once('.')?;
+        Some(Source::Hat(hat.parse().ok()?, bit.parse().ok()?))
+    } else {
+        let axis = text.strip_prefix('a')?;
+        Some(Source::Axis(axis.parse().ok()?, half, inverted))
+    }
+}
+
+// A gamecontrollerdb.txt file, skipping blank lines and # comments
+pub fn parse_mappings(text: &str) -> Result<Vec<SdlMapping>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(number, line)| {
+            SdlMapping::parse(line).map_err(|e| format!("line {}: {}", number + 1, e))
+        })
+        .collect()
+}
+
+// The host's gamepads, through gilrs when built with the gamepad feature.
+// Otherwise no pads ever show up
+pub fn host_backend(mappings: &str) -> Result<Box<dyn GamepadBackend>, String> {
+    #[cfg(feature = "gamepad")]
+    {
+        Ok(Box::new(GilrsBackend::new(mappings)?))
+    }
+    #[cfg(not(feature = "gamepad"))]
+    {
+        // Still checked so mistakes show up without the feature too
+        parse_mappings(mappings)?;
+        Ok(Box::new(NoGamepads))
+    }
+}
+
+// A host without gamepads
+pub struct NoGamepads;
+
+impl GamepadBackend for NoGamepads {
+    fn poll(&mut self) -> Vec<GamepadEvent> {
+        Vec::new()
+    }
+}
+
+// Pads that only exist in software, driven by raw button, hat and axis numbers
+// and translated through SDL mappings like a real device would be
+#[cfg(test)]
+pub struct VirtualBackend {
+    mappings: Vec<SdlMapping>,
+    // Index into mappings for each pad ever connected, None once unplugged
+    // or when there's no mapping for it
+    pads: Vec<Option<usize>>,
+    events: Vec<GamepadEvent>,
+}
+
+#[cfg(test)]
+impl VirtualBackend {
+    pub fn new(mappings: Vec<SdlMapping>) -> Self {
+        VirtualBackend {
+            mappings,
+            pads: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    // Like SDL, a pad without a mapping isn't treated as a gamepad
+    pub fn connect(&mut self, guid: &str) -> GamepadId {
+        let id = self.pads.len();
+        let mapping = self
+            .mappings
+            .iter()
+            .position(|m| m.guid.eq_ignore_ascii_case(guid));
+        self.pads.push(mapping);
+        if mapping.is_some() {
+            self.events.push(GamepadEvent::Connected(id));
+        }
+        id
+    }
+
+    pub fn disconnect(&mut self, id: GamepadId) {
+        if let Some(pad) = self.pads.get_mut(id)
+            && pad.take().is_some()
+        {
+            self.events.push(GamepadEvent::Disconnected(id));
+        }
+    }
+
+    pub fn press(&mut self, id: GamepadId, button: usize, pressed: bool) {
+        self.raw_input(id, |source| match *source {
+            Source::Button(b) if b == button => Some(if pressed { 1.0 } else { 0.0 }),
+            _ => None,
+        });
+    }
+
+    // Bits as in Source::Hat, 0 when centered
+    pub fn set_hat(&mut self, id: GamepadId, hat: usize, value: u8) {
+        self.raw_input(id, |source| match *source {
+            Source::Hat(h, bit) if h == hat => Some(if value & bit != 0 { 1.0 } else { 0.0 }),
+            _ => None,
+        });
+    }
+
+    pub fn move_axis(&mut self, id: GamepadId, axis: usize, value: f32) {
+        self.raw_input(id, |source| match *source {
+            Source::Axis(a, half, inverted) if a == axis => {
+                let value = if inverted { -value } else { value };
+                Some(match half {
+                    None => value,
+                    Some(true) => value.max(0.0),
+                    Some(false) => (-value).max(0.0),
+                })
+            }
+            _ => None,
+        });
+    }
+
+    // Sends every element `value` gives a reading for
+    fn raw_input(&mut self, id: GamepadId, value: impl Fn(&Source) -> Option<f32>) {
+        let Some(&Some(mapping)) = self.pads.get(id) else {
+            return;
+        };
+        for (target, source) in &self.mappings[mapping].elements {
+            let Some(value) = value(source) else {
+                continue;
+            };
+            self.events.push(match *target {
+                Target::Button(button) => GamepadEvent::Button(id, button, value > 0.5),
+                // A whole axis spans the trigger's 0 to 1
+                Target::Axis(axis @ (Axis::LeftTrigger | Axis::RightTrigger)) => {
+                    let full = matches!(source, Source::Axis(_, None, _));
+                    GamepadEvent::Axis(id, axis, if full { (value + 1.0) / 2.0 } else { value })
+                }
+                Target::Axis(axis) => GamepadEvent::Axis(id, axis, value),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+impl GamepadBackend for VirtualBackend {
+    fn poll(&mut self) -> Vec<GamepadEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+#[cfg(feature = "gamepad")]
+pub struct GilrsBackend {
+    gilrs: gilrs::Gilrs,
+    pending: Vec<GamepadEvent>,
+}
+
+#[cfg(feature = "gamepad")]
+impl GilrsBackend {
+    // `mappings` go on top of gilrs's own SDL database
+    pub fn new(mappings: &str) -> Result<Self, String> {
+        let gilrs = gilrs::GilrsBuilder::new()
+            .add_mappings(mappings)
+            .build()
+            .map_err(|e| format!("Gamepads unavailable: {}", e))?;
+        // Pads plugged in before startup don't get an event
+        let pending = gilrs
+            .gamepads()
+            .map(|(id, _)| GamepadEvent::Connected(id.into()))
+            .collect();
+        Ok(GilrsBackend { gilrs, pending })
+    }
+}
+
+#[cfg(feature = "gamepad")]
+impl GamepadBackend for GilrsBackend {
+    fn poll(&mut self) -> Vec<GamepadEvent> {
+        use gilrs::EventType;
+
+        let mut events = std::mem::take(&mut self.pending);
+        while let Some(gilrs::Event { id, event, .. }) = self.gilrs.next_event() {
+            let id = id.into();
+            events.push(match event {
+                EventType::Connected => GamepadEvent::Connected(id),
+                EventType::Disconnected => GamepadEvent::Disconnected(id),
+                EventType::ButtonPressed(button, _) | EventType::ButtonReleased(button, _) => {
+                    let Some(button) = gilrs_button(button) else {
+                        continue;
+                    };
+                    let pressed = matches!(event, EventType::ButtonPressed(..));
+                    GamepadEvent::Button(id, button, pressed)
+                }
+                // Analog triggers
+                EventType::ButtonChanged(gilrs::Button::LeftTrigger2, value, _) => {
+                    GamepadEvent::Axis(id, Axis::LeftTrigger, value)
+                }
+                EventType::ButtonChanged(gilrs::Button::RightTrigger2, value, _) => {
+                    GamepadEvent::Axis(id, Axis::RightTrigger, value)
+                }
+                // gilrs has up positive
+                EventType::AxisChanged(axis, value, _) => match axis {
+                    gilrs::Axis::LeftStickX => GamepadEvent::Axis(id, Axis::LeftX, value),
+                    gilrs::Axis::LeftStickY => GamepadEvent::Axis(id, Axis::LeftY, -value),
+                    gilrs::Axis::RightStickX => GamepadEvent::Axis(id, Axis::RightX, value),
+                    gilrs::Axis::RightStickY => GamepadEvent::Axis(id, Axis::RightY, -value),
+                    _ => continue,
+                },
+                _ => continue,
+            });
+        }
+        events
+    }
+}
+
+#[cfg(feature = "gamepad")]
+fn gilrs_button(button: gilrs::Button) -> Option<Button> {
+    use gilrs::Button as G;
+
+    Some(match button {
+        G::South => Button::A,
+        G::East => Button::B,
+        G::West => Button::X,
+        G::North => Button::Y,
+        G::Select => Button::Back,
+        G::Mode => Button::Guide,
+        G::Start => Button::Start,
+        G::LeftThumb => Button::LeftStick,
+        G::RightThumb => Button::RightStick,
+        G::LeftTrigger => Button::LeftShoulder,
+        G::RightTrigger => Button::RightShoulder,
+        G::DPadUp => Button::DpUp,
+        G::DPadDown => Button::DpDown,
+        G::DPadLeft => Button::DpLeft,
+        G::DPadRight => Button::DpRight,
+        _ => return None,
+    })
+}
+
+#[derive(Clone, Copy, Default)]
+struct PadState {
+    buttons: [bool; BUTTONS.len()],
+    axes: [f32; AXES.len()],
+}
+
+pub struct Gamepads {
+    backend: Box<dyn GamepadBackend>,
+    // NES button names as in the config, turbo ones included
+    bindings: Vec<(&'static str, Vec<PadInput>)>,
+    deadzone: f32,
+    // The pad each player is holding
+    players: [Option<GamepadId>; PLAYERS],
+    states: [PadState; PLAYERS],
+}
+
+impl Gamepads {
+    pub fn new(backend: Box<dyn GamepadBackend>, config: &GamepadConfig) -> Gamepads {
+        let mut gamepads = Gamepads {
+            backend,
+            bindings: Vec::new(),
+            deadzone: 0.0,
+            players: [None; PLAYERS],
+            states: [PadState::default(); PLAYERS],
+        };
+        gamepads.set_config(config);
+        gamepads
+    }
+
+    // Bindings are validated with the config, bad ones are skipped
+    pub fn set_config(&mut self, config: &GamepadConfig) {
+        self.bindings = config
+            .buttons
+            .iter()
+            .filter_map(|(button, inputs)| Some((button, parse_inputs(inputs).ok()?)))
+            .collect();
+        self.deadzone = config.deadzone;
+    }
+
+    // Players whose buttons changed, with their buttons and turbo buttons
+    pub fn poll(&mut self) -> Vec<(usize, ControllerState, ControllerState)> {
+        let events = self.backend.poll();
+        self.update(events)
+    }
+
+    fn update(
+        &mut self,
+        events: Vec<GamepadEvent>,
+    ) -> Vec<(usize, ControllerState, ControllerState)> {
+        let mut changed = Vec::new();
+        for event in events {
+            let id = match event {
+                GamepadEvent::Connected(id)
+                | GamepadEvent::Disconnected(id)
+                | GamepadEvent::Button(id, _, _)
+                | GamepadEvent::Axis(id, _, _) => id,
+            };
+            let player = self.players.iter().position(|&p| p == Some(id));
+            if let GamepadEvent::Connected(_) = event {
+                // Extra pads wait for a free slot to be plugged back in
+                if player.is_none()
+                    && let Some(slot) = self.players.iter_mut().find(|p| p.is_none())
+                {
+                    *slot = Some(id);
+                }
+                continue;
+            }
+            let Some(player) = player else {
+                continue;
+            };
+            let state = &mut self.states[player];
+            match event {
+                GamepadEvent::Disconnected(_) => {
+                    self.players[player] = None;
+                    *state = PadState::default();
+                }
+                GamepadEvent::Button(_, button, pressed) => {
+                    state.buttons[button as usize] = pressed
+                }
+                GamepadEvent::Axis(_, axis, value) => state.axes[axis as usize] = value,
+                GamepadEvent::Connected(_) => (),
+            }
+            if !changed.contains(&player) {
+                changed.push(player);
+            }
+        }
+        changed.sort();
+        changed
+            .into_iter()
+            .map(|player| {
+                let (pad, turbo) = self.buttons(player);
+                (player, pad, turbo)
+            })
+            .collect()
+    }
+
+    fn buttons(&self, player: usize) -> (ControllerState, ControllerState) {
+        let state = &self.states[player];
+        let mut pad = ControllerState::default();
+        let mut turbo = ControllerState::default();
+        for (button, inputs) in &self.bindings {
+            let pressed = inputs.iter().any(|input| match *input {
+                PadInput::Button(b) => state.buttons[b as usize],
+                PadInput::Axis(axis, true) => state.axes[axis as usize] > self.deadzone,
+                PadInput::Axis(axis, false) => state.axes[axis as usize] < -self.deadzone,
+            });
+            match button.strip_prefix("turbo_") {
+                Some(button) => bindings::set_button(&mut turbo, button, pressed),
+                None => bindings::set_button(&mut pad, button, pressed),
+            }
+        }
+        (pad, turbo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusty_nes::ControllerState;
+
+    use super::super::config::GamepadConfig;
+    use super::{
+        Axis, Button, GamepadBackend, Gamepads, PadInput, SdlMapping, VirtualBackend, parse_inputs,
+        parse_mappings,
+    };
+
+    const GUID: &str = "030000005e0400008e02000010010000";
+
+    #[test]
+    fn test_gamepads() {
+        let text = format!(
+            "# Test pads\n{},Test Pad,a:b0,b:b1,x:b2,y:b3,back:b6,start:b7,dpup:h0.1,dpdown:h0.4,dpleft:h0.8,dpright:h0.2,leftx:a0,lefty:a1~,lefttrigger:a2,platform:Linux,\n",
+            GUID
+        );
+        let mappings = parse_mappings(&text).unwrap();
+        assert_eq!(mappings[0].name, "Test Pad");
+        assert!(SdlMapping::parse("1234,Short GUID,a:b0").is_err());
+        assert_eq!(
+            parse_mappings(&text.replace("b:b1", "b:q1")),
+            Err(String::from("line 2: bad input \"q1\" in \"b:q1\""))
+        );
+        assert_eq!(
+            parse_inputs(" dpup, -lefty"),
+            Ok(vec![
+                PadInput::Button(Button::DpUp),
+                PadInput::Axis(Axis::LeftY, false)
+            ])
+        );
+        assert!(parse_inputs("up").is_err());
+
+        let mut gamepads = Gamepads::new(
+            Box::new(VirtualBackend::new(Vec::new())),
+            &GamepadConfig::default(),
+        );
+        let mut backend = VirtualBackend::new(mappings);
+        let first = backend.connect(GUID);
+        // No mapping, so it never shows up
+        let unknown = backend.connect("00000000000000000000000000000000");
+        let second = backend.connect(GUID);
+        backend.press(first, 0, true);
+        backend.press(unknown, 0, true);
+        backend.set_hat(first, 0, 0x02);
+        // Inverted, so pushing the stick down is up
+        backend.move_axis(second, 1, 0.5);
+        // Inside the deadzone
+        backend.move_axis(second, 0, 0.1);
+        backend.press(second, 3, true);
+        let right_b = ControllerState {
+            right: true,
+            b: true,
+            ..Default::default()
+        };
+        let up = ControllerState {
+            up: true,
+            ..Default::default()
+        };
+        let turbo_a = ControllerState {
+            a: true,
+            ..Default::default()
+        };
+        let none = ControllerState::default();
+        assert_eq!(
+            gamepads.update(backend.poll()),
+            [(0, right_b, none), (1, up, turbo_a)]
+        );
+
+        // A new pad takes the first free slot
+        backend.disconnect(first);
+        let third = backend.connect(GUID);
+        backend.set_hat(third, 0, 0x04);
+        assert_eq!(
+            gamepads.update(backend.poll()),
+            [(
+                0,
+                ControllerState {
+                    down: true,
+                    ..Default::default()
+                },
+                none
+            )]
+        );
+    }
+}
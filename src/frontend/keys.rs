@@ -0,0 +1,97 @@
+// Key names used in the config file: "ArrowUp", "Enter", "x" and so on.
+
+use iced::keyboard::{Key, key::Named};
+
+const NAMED_KEYS: [(&str, Named); 30] = [
+    ("ArrowUp", Named::ArrowUp),
+    ("ArrowDown", Named::ArrowDown),
+    ("ArrowLeft", Named::ArrowLeft),
+    ("ArrowRight", Named::ArrowRight),
+    ("Enter", Named::Enter),
+    ("Shift", Named::Shift),
+    ("Control", Named::Control),
+    ("Alt", Named::Alt),
+    ("Space", Named::Space),
+    ("Tab", Named::Tab),
+    ("Backspace", Named::Backspace),
+    ("Escape", Named::Escape),
+    ("Insert", Named::Insert),
+    ("Delete", Named::Delete),
+    ("Home", Named::Home),
+    ("End", Named::End),
+    ("PageUp", Named::PageUp),
+    ("PageDown", Named::PageDown),
+    ("F1", Named::F1),
+    ("F2", Named::F2),
+    ("F3", Named::F3),
+    ("F4", Named::F4),
+    ("F5", Named::F5),
+    ("F6", Named::F6),
+    ("F7", Named::F7),
+    ("F8", Named::F8),
+    ("F9", Named::F9),
+    ("F10", Named::F10),
+    ("F11", Named::F11),
+    ("F12", Named::F12),
+];
+
+// Single characters stand for themselves, case doesn't matter
+pub fn parse_key(name: &str) -> Option<Key> {
+    if let Some((_, named)) = NAMED_KEYS
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+    {
+        return Some(Key::Named(*named));
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if !c.is_whitespace() => {
+            Some(normalize(Key::Character(c.to_string().into())))
+        }
+        _ => None,
+    }
+}
+
+// The config name of a pressed key, None for keys that can't be bound
+pub fn key_name(key: &Key) -> Option<String> {
+    match normalize(key.clone()) {
+        Key::Named(named) => NAMED_KEYS
+            .iter()
+            .find(|(_, n)| *n == named)
+            .map(|(name, _)| name.to_string()),
+        Key::Character(c) => parse_key(&c).map(|_| c.to_string()),
+        Key::Unidentified => None,
+    }
+}
+
+// So "X" with shift held still matches a binding for "x"
+pub fn normalize(key: Key) -> Key {
+    match key {
+        Key::Character(c) => Key::Character(c.to_lowercase().into()),
+        key => key,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iced::keyboard::{Key, key::Named};
+
+    use super::{key_name, normalize, parse_key};
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("arrowup"), Some(Key::Named(Named::ArrowUp)));
+        assert_eq!(parse_key("X"), Some(Key::Character("x".into())));
+        assert_eq!(
+            normalize(Key::Character("X".into())),
+            parse_key("x").unwrap()
+        );
+        assert_eq!(parse_key("Hyper"), None);
+        assert_eq!(parse_key(""), None);
+        assert_eq!(parse_key(" "), None);
+
+        assert_eq!(key_name(&Key::Named(Named::F12)).as_deref(), Some("F12"));
+        assert_eq!(key_name(&Key::Character("Q".into())).as_deref(), Some("q"));
+        assert_eq!(key_name(&Key::Named(Named::CapsLock)), None);
+    }
+}
@@ -0,0 +1,108 @@
+// ROM library: finds .nes files in the configured directories and reads
+// their headers for the browser view.
+
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use rusty_nes::RomInfo;
+
+const HEADER_SIZE: usize = 16;
+// Don't wander off too far through symlinks or huge trees
+const MAX_DEPTH: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct RomEntry {
+    pub path: PathBuf,
+    pub title: String,
+    // Err when the header can't be read or parsed
+    pub info: Result<RomInfo, String>,
+}
+
+impl RomEntry {
+    pub fn read(path: &Path) -> RomEntry {
+        let title = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        RomEntry {
+            path: path.to_owned(),
+            title,
+            info: read_header(path).and_then(|header| RomInfo::parse(&header)),
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.info.as_ref().is_ok_and(|info| info.is_supported())
+    }
+}
+
+fn read_header(path: &Path) -> Result<Vec<u8>, String> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    fs::File::open(path)
+        .and_then(|f| f.take(HEADER_SIZE as u64).read_to_end(&mut header))
+        .map_err(|e| e.to_string())?;
+    Ok(header)
+}
+
+fn is_rom(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("nes"))
+}
+
+fn scan_dir(dir: &Path, depth: usize, entries: &mut Vec<RomEntry>) {
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return;
+    };
+    for path in read_dir.flatten().map(|e| e.path()) {
+        if path.is_dir() {
+            if depth < MAX_DEPTH {
+                scan_dir(&path, depth + 1, entries);
+            }
+        } else if is_rom(&path) {
+            entries.push(RomEntry::read(&path));
+        }
+    }
+}
+
+// Sorted by title. Missing directories are skipped
+pub fn scan(dirs: &[PathBuf]) -> Vec<RomEntry> {
+    let mut entries = Vec::new();
+    for dir in dirs {
+        scan_dir(dir, 0, &mut entries);
+    }
+    entries.sort_by_key(|e| e.title.to_lowercase());
+    entries.dedup_by(|a, b| a.path == b.path);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::scan;
+
+    #[test]
+    fn test_scan() {
+        let dir = std::env::temp_dir().join(format!("rusty-nes-library-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let mut header = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0x10, 0, 0, 0x01];
+        header.resize(16, 0);
+        fs::write(dir.join("sub/Beta.NES"), &header).unwrap();
+        header[6] = 0x40; // Mapper 4
+        fs::write(dir.join("alpha.nes"), &header).unwrap();
+        fs::write(dir.join("broken.nes"), b"NES").unwrap();
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+
+        let entries = scan(&[dir.clone(), dir.join("missing")]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let titles: Vec<&str> = entries.iter().map(|e| e.title.as_str()).collect();
+        assert_eq!(titles, ["alpha", "Beta", "broken"]);
+        assert!(!entries[0].is_supported());
+        let beta = entries[1].info.as_ref().unwrap();
+        assert_eq!((beta.mapper, beta.region), (1, rusty_nes::Region::Pal));
+        assert!(entries[1].is_supported());
+        assert!(entries[2].info.is_err());
+    }
+}
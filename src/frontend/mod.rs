@@ -0,0 +1,8 @@
+// Pieces of the desktop frontend, main.rs ties them together
+pub mod bindings;
+pub mod config;
+pub mod gamepad;
+pub mod keys;
+pub mod library;
+pub mod recent;
+pub mod settings;
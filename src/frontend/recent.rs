@@ -0,0 +1,89 @@
+// Recently opened ROMs, newest first, kept as one path per line in the data
+// directory.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MAX_RECENT: usize = 10;
+
+#[derive(Default)]
+pub struct RecentFiles {
+    paths: Vec<PathBuf>,
+    // Where to save, None to keep them in memory only
+    file: Option<PathBuf>,
+}
+
+impl RecentFiles {
+    pub fn default_file() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("rusty-nes").join("recent.txt"))
+    }
+
+    // A missing or unreadable file starts an empty list
+    pub fn load(file: Option<PathBuf>) -> RecentFiles {
+        let paths = file
+            .as_ref()
+            .and_then(|f| fs::read_to_string(f).ok())
+            .map(|text| {
+                text.lines()
+                    .filter(|line| !line.is_empty())
+                    .map(PathBuf::from)
+                    .take(MAX_RECENT)
+                    .collect()
+            })
+            .unwrap_or_default();
+        RecentFiles { paths, file }
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    pub fn add(&mut self, path: &Path) {
+        self.paths.retain(|p| p != path);
+        self.paths.insert(0, path.to_owned());
+        self.paths.truncate(MAX_RECENT);
+        if let Err(e) = self.save() {
+            println!("Failed to save recent files: {}", e);
+        }
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text: String = self
+            .paths
+            .iter()
+            .map(|p| format!("{}\n", p.display()))
+            .collect();
+        fs::write(file, text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{MAX_RECENT, RecentFiles};
+
+    #[test]
+    fn test_recent_files() {
+        let file = std::env::temp_dir().join(format!("rusty-nes-recent-{}", std::process::id()));
+        let mut recent = RecentFiles::load(Some(file.clone()));
+        assert!(recent.paths().is_empty());
+        for i in 0..MAX_RECENT + 2 {
+            recent.add(Path::new(&format!("{}.nes", i)));
+        }
+        recent.add(Path::new("5.nes"));
+
+        let loaded = RecentFiles::load(Some(file.clone()));
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(loaded.paths().len(), MAX_RECENT);
+        assert_eq!(loaded.paths()[0], PathBuf::from("5.nes"));
+        assert_eq!(loaded.paths()[1], PathBuf::from("11.nes"));
+        assert!(!loaded.paths().contains(&PathBuf::from("1.nes")));
+    }
+}
@@ -0,0 +1,351 @@
+// The settings screen edits every option as text, then turns it back into a
+// Config that goes through the same validation as the config file.
+
+use std::path::PathBuf;
+
+use super::config::{Config, ExpansionSetting, MultitapSetting, Port2Device, RegionSetting};
+
+pub struct SettingsForm {
+    // (option name as in the config file, value being edited)
+    pub fields: Vec<(String, String)>,
+}
+
+impl SettingsForm {
+    pub fn new(config: &Config) -> SettingsForm {
+        let mut fields: Vec<(String, String)> = config
+            .keys
+            .iter()
+            .map(|(name, key)| (format!("keys.{}", name), key.to_string()))
+            .collect();
+        let rom_dirs = std::env::join_paths(&config.paths.rom_dirs)
+            .map(|dirs| dirs.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        fields.extend(
+            [
+                ("video.scale", config.video.scale.to_string()),
+                ("video.palette", config.video.palette.clone()),
+                ("paths.rom_dirs", rom_dirs),
+                (
+                    "paths.save_dir",
+                    config.paths.save_dir.display().to_string(),
+                ),
+                (
+                    "paths.screenshot_dir",
+                    config.paths.screenshot_dir.display().to_string(),
+                ),
+                ("input.port2", port2_name(config.input.port2).to_string()),
+                (
+                    "input.multitap",
+                    multitap_name(config.input.multitap).to_string(),
+                ),
+                (
+                    "input.expansion",
+                    expansion_name(config.input.expansion).to_string(),
+                ),
+                ("input.turbo_rate", config.input.turbo_rate.to_string()),
+                ("speed.fast_forward", config.speed.fast_forward.to_string()),
+                ("speed.slow_motion", config.speed.slow_motion.to_string()),
+                ("gamepad.deadzone", config.gamepad.deadzone.to_string()),
+                (
+                    "gamepad.mappings",
+                    config.gamepad.mappings.display().to_string(),
+                ),
+                ("region", region_name(config.region).to_string()),
+            ]
+            .map(|(name, value)| (name.to_string(), value)),
+        );
+        fields.extend(
+            config
+                .gamepad
+                .buttons
+                .iter()
+                .map(|(name, inputs)| (format!("gamepad.buttons.{}", name), inputs.to_string())),
+        );
+        SettingsForm { fields }
+    }
+
+    pub fn set(&mut self, index: usize, value: String) {
+        if let Some((_, field)) = self.fields.get_mut(index) {
+            *field = value;
+        }
+    }
+
+    pub fn is_key(&self, index: usize) -> bool {
+        self.fields
+            .get(index)
+            .is_some_and(|(name, _)| name.starts_with("keys."))
+    }
+
+    pub fn to_config(&self) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut errors = Vec::new();
+        for (name, value) in &self.fields {
+            let value = value.trim();
+            let number = || {
+                value
+                    .parse::<u32>()
+                    .map_err(|_| format!("{}: \"{}\" is not a number", name, value))
+            };
+            let decimal = || {
+                value
+                    .parse::<f64>()
+                    .map_err(|_| format!("{}: \"{}\" is not a number", name, value))
+            };
+            let unknown = || format!("{}: unknown setting", name);
+            if let Some(key) = name.strip_prefix("keys.") {
+                if !config.keys.set(key, value.to_string()) {
+                    errors.push(unknown());
+                }
+                continue;
+            }
+            if let Some(button) = name.strip_prefix("gamepad.buttons.") {
+                if !config.gamepad.set_button(button, value.to_string()) {
+                    errors.push(unknown());
+                }
+                continue;
+            }
+            match name.as_str() {
+                "video.scale" => {
+                    number().map_or_else(|e| errors.push(e), |n| config.video.scale = n)
+                }
+                "video.palette" => config.video.palette = value.to_string(),
+                "paths.rom_dirs" => {
+                    config.paths.rom_dirs = std::env::split_paths(value)
+                        .filter(|p| !p.as_os_str().is_empty())
+                        .collect()
+                }
+                "paths.save_dir" => config.paths.save_dir = PathBuf::from(value),
+                "paths.screenshot_dir" => config.paths.screenshot_dir = PathBuf::from(value),
+                "input.port2" => {
+                    parse_port2(value).map_or_else(|e| errors.push(e), |d| config.input.port2 = d)
+                }
+                "input.multitap" => parse_multitap(value)
+                    .map_or_else(|e| errors.push(e), |m| config.input.multitap = m),
+                "input.expansion" => parse_expansion(value)
+                    .map_or_else(|e| errors.push(e), |d| config.input.expansion = d),
+                "speed.fast_forward" => {
+                    decimal().map_or_else(|e| errors.push(e), |n| config.speed.fast_forward = n)
+                }
+                "speed.slow_motion" => {
+                    decimal().map_or_else(|e| errors.push(e), |n| config.speed.slow_motion = n)
+                }
+                "gamepad.deadzone" => match value.parse() {
+                    Ok(deadzone) => config.gamepad.deadzone = deadzone,
+                    Err(_) => errors.push(format!("{}: \"{}\" is not a number", name, value)),
+                },
+                "gamepad.mappings" => config.gamepad.mappings = PathBuf::from(value),
+                "input.turbo_rate" => {
+                    number().map_or_else(|e| errors.push(e), |n| config.input.turbo_rate = n)
+                }
+                "region" => {
+                    parse_region(value).map_or_else(|e| errors.push(e), |r| config.region = r)
+                }
+                _ => errors.push(unknown()),
+            }
+        }
+        if let Err(e) = config.validate() {
+            errors.push(e);
+        }
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+fn port2_name(device: Port2Device) -> &'static str {
+    match device {
+        Port2Device::Controller => "controller",
+        Port2Device::Zapper => "zapper",
+        Port2Device::Arkanoid => "arkanoid",
+        Port2Device::PowerPad => "powerpad",
+    }
+}
+
+fn parse_port2(value: &str) -> Result<Port2Device, String> {
+    match value.to_lowercase().as_str() {
+        "controller" => Ok(Port2Device::Controller),
+        "zapper" => Ok(Port2Device::Zapper),
+        "arkanoid" => Ok(Port2Device::Arkanoid),
+        "powerpad" => Ok(Port2Device::PowerPad),
+        _ => Err(format!(
+            "input.port2: \"{}\" is not controller, zapper, arkanoid or powerpad",
+            value
+        )),
+    }
+}
+
+fn multitap_name(multitap: MultitapSetting) -> &'static str {
+    match multitap {
+        MultitapSetting::None => "none",
+        MultitapSetting::FourScore => "fourscore",
+        MultitapSetting::Hori => "hori",
+    }
+}
+
+fn parse_multitap(value: &str) -> Result<MultitapSetting, String> {
+    match value.to_lowercase().as_str() {
+        "none" => Ok(MultitapSetting::None),
+        "fourscore" => Ok(MultitapSetting::FourScore),
+        "hori" => Ok(MultitapSetting::Hori),
+        _ => Err(format!(
+            "input.multitap: \"{}\" is not none, fourscore or hori",
+            value
+        )),
+    }
+}
+
+fn expansion_name(expansion: ExpansionSetting) -> &'static str {
+    match expansion {
+        ExpansionSetting::None => "none",
+        ExpansionSetting::Arkanoid => "arkanoid",
+        ExpansionSetting::FamilyTrainer => "familytrainer",
+        ExpansionSetting::Keyboard => "keyboard",
+    }
+}
+
+fn parse_expansion(value: &str) -> Result<ExpansionSetting, String> {
+    match value.to_lowercase().as_str() {
+        "none" => Ok(ExpansionSetting::None),
+        "arkanoid" => Ok(ExpansionSetting::Arkanoid),
+        "familytrainer" => Ok(ExpansionSetting::FamilyTrainer),
+        "keyboard" => Ok(ExpansionSetting::Keyboard),
+        _ => Err(format!(
+            "input.expansion: \"{}\" is not none, arkanoid, familytrainer or keyboard",
+            value
+        )),
+    }
+}
+
+fn region_name(region: RegionSetting) -> &'static str {
+    match region {
+        RegionSetting::Auto => "auto",
+        RegionSetting::Ntsc => "ntsc",
+        RegionSetting::Pal => "pal",
+    }
+}
+
+fn parse_region(value: &str) -> Result<RegionSetting, String> {
+    match value.to_lowercase().as_str() {
+        "auto" => Ok(RegionSetting::Auto),
+        "ntsc" => Ok(RegionSetting::Ntsc),
+        "pal" => Ok(RegionSetting::Pal),
+        _ => Err(format!("region: \"{}\" is not auto, ntsc or pal", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::{Config, ExpansionSetting, MultitapSetting, Port2Device};
+    use super::SettingsForm;
+
+    #[test]
+    fn test_settings_form() {
+        let config = Config::default();
+        let mut form = SettingsForm::new(&config);
+        assert_eq!(form.to_config(), Ok(config));
+
+        let index = |form: &SettingsForm, name| form.fields.iter().position(|(n, _)| *n == name);
+        assert_eq!(form.fields[index(&form, "region").unwrap()].1, "auto");
+        form.set(index(&form, "video.scale").unwrap(), String::from("3"));
+        form.set(index(&form, "region").unwrap(), String::from("PAL"));
+        form.set(index(&form, "input.port2").unwrap(), String::from("Zapper"));
+        let config = form.to_config().unwrap();
+        assert_eq!(config.video.scale, 3);
+        assert_eq!(config.input.port2, Port2Device::Zapper);
+        form.set(
+            index(&form, "input.port2").unwrap(),
+            String::from("controller"),
+        );
+        form.set(
+            index(&form, "input.multitap").unwrap(),
+            String::from("FourScore"),
+        );
+        form.set(
+            index(&form, "input.expansion").unwrap(),
+            String::from("FamilyTrainer"),
+        );
+        form.set(
+            index(&form, "gamepad.buttons.a").unwrap(),
+            String::from("rightshoulder, b"),
+        );
+        form.set(
+            index(&form, "gamepad.deadzone").unwrap(),
+            String::from("0.5"),
+        );
+        let config = form.to_config().unwrap();
+        assert_eq!(config.gamepad.buttons.a, "rightshoulder, b");
+        assert_eq!(config.gamepad.deadzone, 0.5);
+        assert_eq!(config.input.multitap, MultitapSetting::FourScore);
+        assert_eq!(config.input.expansion, ExpansionSetting::FamilyTrainer);
+
+        form.set(
+            index(&form, "speed.fast_forward").unwrap(),
+            String::from("3"),
+        );
+        form.set(
+            index(&form, "speed.slow_motion").unwrap(),
+            String::from("0.25"),
+        );
+        let config = form.to_config().unwrap();
+        assert_eq!(config.speed.fast_forward, 3.0);
+        assert_eq!(config.speed.slow_motion, 0.25);
+        form.set(
+            index(&form, "speed.fast_forward").unwrap(),
+            String::from("0.5"),
+        );
+        assert_eq!(
+            form.to_config(),
+            Err(String::from(
+                "speed.fast_forward: must be 0 for uncapped or between 1 and 16"
+            ))
+        );
+        form.set(
+            index(&form, "speed.fast_forward").unwrap(),
+            String::from("0"),
+        );
+
+        form.set(
+            index(&form, "input.turbo_rate").unwrap(),
+            String::from("soon"),
+        );
+        form.set(
+            index(&form, "keys.player2.a").unwrap(),
+            String::from("Hyper"),
+        );
+        assert!(form.is_key(index(&form, "keys.hotkeys.rewind").unwrap()));
+        assert!(!form.is_key(index(&form, "region").unwrap()));
+        assert_eq!(
+            form.to_config(),
+            Err(String::from(
+                "input.turbo_rate: \"soon\" is not a number\nkeys.player2.a: unknown key \"Hyper\""
+            ))
+        );
+
+        let mut form = SettingsForm::new(&Config::default());
+        form.set(index(&form, "region").unwrap(), String::from("secam"));
+        form.set(
+            index(&form, "input.expansion").unwrap(),
+            String::from("mouse"),
+        );
+        assert_eq!(
+            form.to_config(),
+            Err(String::from(
+                "input.expansion: \"mouse\" is not none, arkanoid, familytrainer or keyboard\nregion: \"secam\" is not auto, ntsc or pal"
+            ))
+        );
+
+        let mut form = SettingsForm::new(&Config::default());
+        form.fields
+            .push((String::from("keys.player9.a"), String::from("A")));
+        form.fields
+            .push((String::from("video.sharpness"), String::from("1")));
+        assert_eq!(
+            form.to_config(),
+            Err(String::from(
+                "keys.player9.a: unknown setting\nvideo.sharpness: unknown setting"
+            ))
+        );
+    }
+}
@@ -0,0 +1,17 @@
+// Emulator core. The iced frontend in main.rs is one consumer of this API.
+
+mod nes;
+
+pub use nes::arkanoid::{ARKANOID_MAX, ARKANOID_MIN, Arkanoid, ArkanoidState};
+pub use nes::data_recorder::{DataRecorder, TAPE_RATE, TapeMode};
+pub use nes::family_keyboard::{FamilyKeyboard, KEYBOARD_KEYS, KEYBOARD_LAYOUT, KeyboardState};
+pub use nes::input::{Beam, Controller, ControllerState, ExpansionDevice, InputDevice, PORT_COUNT};
+pub use nes::input_macro::InputMacro;
+pub use nes::multitap::{HoriAdapter, Multitap};
+pub use nes::power_pad::{POWER_PAD_BUTTONS, PowerPad, PowerPadState};
+pub use nes::zapper::{Zapper, ZapperState};
+pub use nes::{
+    FRAME_HEIGHT, FRAME_WIDTH, InteruptSource, Mirroring, Nes, Palette, Region, Registers, RomInfo,
+    SYSTEM_PALETTE, Snapshot, parse_pal,
+};
+pub use nes::{debugger, disasm, gdb, runner};
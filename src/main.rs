@@ -1,21 +1,56 @@
-mod nes;
-use std::time::{self, Duration, Instant};
+mod frontend;
+
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
 
 use iced::{self, keyboard}; //, subscription};
 
 use image::{DynamicImage, EncodableLayout};
 
-use crate::nes::Nes;
+use rusty_nes::runner::{Command, Event, Runner, Speed, Stats};
+use rusty_nes::{
+    ARKANOID_MAX, ARKANOID_MIN, Arkanoid, ArkanoidState, Controller, ControllerState,
+    ExpansionDevice, FamilyKeyboard, HoriAdapter, InputDevice, InputMacro, KeyboardState, Multitap,
+    Nes, PORT_COUNT, PowerPad, PowerPadState, Snapshot, TapeMode, Zapper, ZapperState,
+};
+
+use iced::{Element, Subscription, Task, widget};
 
-use iced::{Element, Subscription, widget};
+use frontend::bindings::{self, Action, Bindings, Hotkey};
+use frontend::config::{Config, ExpansionSetting, MultitapSetting, Port2Device};
+use frontend::gamepad::{self, Gamepads, NoGamepads};
+use frontend::keys;
+use frontend::library::{self, RomEntry};
+use frontend::recent::RecentFiles;
+use frontend::settings::SettingsForm;
 
 pub fn main() -> iced::Result {
     // let mut nes = Nes::new();
     // nes.load_rom(String::from("donkey_kong.nes"));
     // nes.ppu.borrow().render_chr();
+    let rom = std::env::args_os().nth(1).map(PathBuf::from);
+    let config_file = Config::default_file();
+    let (config, config_error) = match config_file.as_deref().map(Config::load) {
+        Some(Err(e)) => (Config::default(), Some(e)),
+        Some(Ok(config)) => (config, None),
+        None => (Config::default(), None),
+    };
+    // Room for the controls and debug views under the screen
+    let scale = config.video.scale as f32;
+    let window_size = iced::Size::new(256.0 * scale + 40.0, 240.0 * scale + 400.0);
     iced::application("RustyNES", update, view)
         .subscription(subscription)
-        .run()
+        .window_size(window_size)
+        .run_with(move || {
+            let mut app = IcedApp::new(config, config_file);
+            if config_error.is_some() {
+                app.status = config_error;
+            }
+            if let Some(rom) = rom {
+                app.load_rom(rom);
+            }
+            (app, Task::none())
+        })
     // IcedApp::run(Settings::default());
     // let native_options = eframe::NativeOptions::default();
     // eframe::run_native("My egui App",
@@ -26,12 +61,50 @@ pub fn main() -> iced::Result {
     // );
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Screen {
+    Game,
+    Library,
+    Settings,
+}
+
 struct IcedApp {
-    nes: Nes,
+    // Emulation runs on its own thread
+    runner: Runner,
+
+    screen: Screen,
+    config: Config,
+    config_file: Option<PathBuf>,
+    // Only while the settings screen is open
+    settings: Option<SettingsForm>,
+    // Settings field waiting for a key press
+    binding: Option<usize>,
+    library: Vec<RomEntry>,
+    recent: RecentFiles,
+    // Last error, shown until the next ROM loads
+    status: Option<String>,
+    rom: Option<PathBuf>,
 
     // state
-    frame_rate: f64,
-    controller_state: IcedControllerState,
+    bindings: Bindings,
+    pads: [ControllerState; 4],
+    // Buttons held on the turbo keys
+    turbo: [ControllerState; 4],
+    gamepads: Gamepads,
+    // What each player's gamepad is holding, on top of the keys
+    gamepad_pads: [ControllerState; 4],
+    gamepad_turbo: [ControllerState; 4],
+    recording_macro: bool,
+    zapper: ZapperState,
+    arkanoid: ArkanoidState,
+    power_pad: PowerPadState,
+    family_keys: KeyboardState,
+    // Keys go to the Family BASIC keyboard instead of the bindings
+    keyboard_capture: bool,
+    fast_forward: bool,
+    slow_motion: bool,
+    paused: bool,
+    stats: Stats,
 
     // cached images
     chr_image: Option<image::RgbaImage>,
@@ -45,45 +118,458 @@ pub enum AppMessage {
     Tick(Instant),
     KeyPress(iced::keyboard::Key),
     KeyReleased(iced::keyboard::Key),
+    TogglePause,
+    FrameAdvance,
+    Reset,
+    PowerCycle,
+    ShowScreen(Screen),
+    RescanLibrary,
+    OpenFile,
+    FilePicked(Option<PathBuf>),
+    LoadRom(PathBuf),
+    SettingChanged(usize, String),
+    // Over the game screen, for the Zapper and Arkanoid paddle
+    MouseMoved(Option<iced::Point>),
+    MouseButton(bool),
+    BindKey(usize),
+    SaveSettings,
+    // Data recorder on the Family BASIC keyboard
+    Tape(TapeMode),
+    LoadTape,
+    TapePicked(Option<PathBuf>),
+    SaveTape,
+    TapeSaveTo(Option<PathBuf>),
     // Event(iced::Event)
 }
 
-impl Default for IcedApp {
-    fn default() -> Self {
-        IcedApp::new(())
+impl IcedApp {
+    fn speed(&self) -> Speed {
+        if self.fast_forward {
+            self.config.speed.fast_forward_speed()
+        } else if self.slow_motion {
+            self.config.speed.slow_motion_speed()
+        } else {
+            Speed::default()
+        }
+    }
+
+    fn on_hotkey(&mut self, hotkey: Hotkey, pressed: bool) -> Task<AppMessage> {
+        let speed = self.speed();
+        match hotkey {
+            Hotkey::FastForward => self.fast_forward = pressed,
+            Hotkey::Rewind => self.runner.send(Command::Rewind(pressed)),
+            // The rest act once per press
+            _ if !pressed => (),
+            Hotkey::Pause => return update(self, AppMessage::TogglePause),
+            Hotkey::FrameAdvance => return update(self, AppMessage::FrameAdvance),
+            Hotkey::Reset => return update(self, AppMessage::Reset),
+            Hotkey::PowerCycle => return update(self, AppMessage::PowerCycle),
+            Hotkey::Open => return update(self, AppMessage::OpenFile),
+            Hotkey::SlowMotion => self.slow_motion = !self.slow_motion,
+            Hotkey::SaveState => self.status = self.save_state().err(),
+            Hotkey::LoadState => self.status = self.load_state().err(),
+            Hotkey::Screenshot => self.status = self.screenshot().err(),
+            Hotkey::RecordMacro => self.status = self.record_macro().err(),
+            Hotkey::PlayMacro => self.status = self.play_macro().err(),
+            Hotkey::KeyboardCapture => {
+                self.keyboard_capture = !self.keyboard_capture
+                    && self.config.input.expansion == ExpansionSetting::Keyboard;
+                self.family_keys = KeyboardState::default();
+                self.send_family_keys();
+            }
+        }
+        if self.speed() != speed {
+            self.runner.send(Command::SetSpeed(self.speed()));
+        }
+        Task::none()
+    }
+
+    // Cursor position on the game screen widget, None when outside
+    fn on_mouse_move(&mut self, position: Option<iced::Point>) {
+        let scale = self.config.video.scale as f32;
+        self.zapper.aim = position
+            .map(|p| ((p.x / scale) as u32, (p.y / scale) as u32))
+            .filter(|&(x, y)| x < rusty_nes::FRAME_WIDTH && y < rusty_nes::FRAME_HEIGHT);
+        // The paddle's knob follows the cursor across the screen
+        if let Some((x, _)) = self.zapper.aim {
+            let range = (ARKANOID_MAX - ARKANOID_MIN) as u32;
+            self.arkanoid.position =
+                ARKANOID_MIN + (x * range / (rusty_nes::FRAME_WIDTH - 1)) as u8;
+        }
+        self.send_pointer();
+    }
+
+    fn on_mouse_button(&mut self, pressed: bool) {
+        self.zapper.trigger = pressed;
+        self.arkanoid.fire = pressed;
+        self.send_pointer();
+    }
+
+    // To whichever mouse driven device is plugged in
+    fn send_pointer(&mut self) {
+        let (zapper, arkanoid) = (self.zapper, self.arkanoid);
+        self.runner.send(Command::Run(Box::new(move |nes| {
+            if let Some(device) = nes.input_device_mut::<Zapper>(1) {
+                device.set_state(zapper)
+            }
+            if let Some(device) = nes.input_device_mut::<Arkanoid>(1) {
+                device.set_state(arkanoid)
+            }
+            if let Some(device) = nes.expansion_device_mut::<Arkanoid>() {
+                device.set_state(arkanoid)
+            }
+        })));
+    }
+
+    fn on_power_pad(&mut self, button: usize, pressed: bool) {
+        self.power_pad.buttons[button] = pressed;
+        let power_pad = self.power_pad;
+        self.runner.send(Command::Run(Box::new(move |nes| {
+            if let Some(device) = nes.input_device_mut::<PowerPad>(1) {
+                device.set_state(power_pad)
+            }
+            if let Some(device) = nes.expansion_device_mut::<PowerPad>() {
+                device.set_state(power_pad)
+            }
+        })));
+    }
+
+    // Keys without a Family BASIC twin are dropped
+    fn on_family_key(&mut self, key: &keyboard::Key, pressed: bool) {
+        if let Some(name) = bindings::family_key(key) {
+            self.family_keys.set(name, pressed);
+            self.send_family_keys();
+        }
+    }
+
+    fn send_family_keys(&mut self) {
+        let keys = self.family_keys;
+        self.runner.send(Command::Run(Box::new(move |nes| {
+            if let Some(keyboard) = nes.expansion_device_mut::<FamilyKeyboard>() {
+                keyboard.set_state(keys)
+            }
+        })));
+    }
+
+    fn set_tape_mode(&mut self, mode: TapeMode) {
+        self.runner.send(Command::Run(Box::new(move |nes| {
+            if let Some(keyboard) = nes.expansion_device_mut::<FamilyKeyboard>() {
+                match mode {
+                    TapeMode::Stopped => keyboard.tape.stop(),
+                    TapeMode::Playing => keyboard.tape.play(),
+                    TapeMode::Recording => keyboard.tape.record(),
+                }
+            }
+        })));
+    }
+
+    // WAV files by extension, anything else is a raw bitstream
+    fn load_tape(&mut self, path: &Path) -> Result<(), String> {
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let wav = is_wav(path);
+        self.runner
+            .with_nes(move |nes| {
+                let keyboard = nes
+                    .expansion_device_mut::<FamilyKeyboard>()
+                    .ok_or("No Family BASIC keyboard attached")?;
+                if wav {
+                    keyboard.tape.load_wav(&bytes)
+                } else {
+                    keyboard.tape.load_raw(&bytes);
+                    Ok(())
+                }
+            })
+            .unwrap_or_else(|| Err(String::from("Emulation thread stopped")))
+    }
+
+    fn save_tape(&mut self, path: &Path) -> Result<(), String> {
+        let wav = is_wav(path);
+        let bytes = self
+            .runner
+            .with_nes(move |nes| {
+                nes.expansion_device_mut::<FamilyKeyboard>()
+                    .map(|keyboard| {
+                        keyboard.tape.stop();
+                        if wav {
+                            keyboard.tape.to_wav()
+                        } else {
+                            keyboard.tape.to_raw()
+                        }
+                    })
+            })
+            .ok_or("Emulation thread stopped")?
+            .ok_or("No Family BASIC keyboard attached")?;
+        write_file(path, &bytes)
+    }
+
+    fn on_button(&mut self, player: usize, button: &str, pressed: bool) {
+        match button.strip_prefix("turbo_") {
+            Some(button) => bindings::set_button(&mut self.turbo[player], button, pressed),
+            None => bindings::set_button(&mut self.pads[player], button, pressed),
+        }
+        self.send_player(player);
+    }
+
+    fn poll_gamepads(&mut self) {
+        for (player, pad, turbo) in self.gamepads.poll() {
+            self.gamepad_pads[player] = pad;
+            self.gamepad_turbo[player] = turbo;
+            self.send_player(player);
+        }
+    }
+
+    // Keys and gamepad together
+    fn send_player(&mut self, player: usize) {
+        self.runner.send(Command::Input(
+            player,
+            self.pads[player] | self.gamepad_pads[player],
+        ));
+        self.runner.send(Command::Turbo(
+            player,
+            self.turbo[player] | self.gamepad_turbo[player],
+        ));
+    }
+
+    // Named after the ROM, so each game has one slot
+    fn save_file(&self, extension: &str) -> Result<PathBuf, String> {
+        let rom = self.rom.as_deref().ok_or("No ROM loaded")?;
+        Ok(self
+            .config
+            .paths
+            .save_dir
+            .join(rom_title(rom))
+            .with_extension(extension))
+    }
+
+    // Starts recording player 1, or stops and saves what was recorded
+    fn record_macro(&mut self) -> Result<(), String> {
+        let file = self.save_file("macro")?;
+        self.recording_macro = !self.recording_macro;
+        if self.recording_macro {
+            self.runner.send(Command::RecordMacro(0));
+            return Ok(());
+        }
+        // Keeps the macro already saved for this game
+        match self.runner.stop_recording()? {
+            Some(input) if !input.frames.is_empty() => {
+                write_file(&file, input.to_text().as_bytes())
+            }
+            _ => Err(String::from("Nothing was recorded")),
+        }
+    }
+
+    fn play_macro(&mut self) -> Result<(), String> {
+        let file = self.save_file("macro")?;
+        let text = std::fs::read_to_string(&file)
+            .map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+        let input =
+            InputMacro::from_text(&text).map_err(|e| format!("{}: {}", file.display(), e))?;
+        self.runner.send(Command::PlayMacro(0, input));
+        Ok(())
+    }
+
+    fn save_state(&mut self) -> Result<(), String> {
+        let file = self.save_file("state")?;
+        let snapshot = self
+            .runner
+            .with_nes(|nes| nes.save_state())
+            .ok_or("Emulation thread stopped")?;
+        write_file(&file, snapshot.as_bytes())
+    }
+
+    fn load_state(&mut self) -> Result<(), String> {
+        let file = self.save_file("state")?;
+        let bytes = std::fs::read(&file)
+            .map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+        self.runner
+            .send(Command::LoadState(Snapshot::from_bytes(bytes)?));
+        Ok(())
+    }
+
+    fn screenshot(&mut self) -> Result<(), String> {
+        let title = self.rom.as_deref().map_or_else(String::new, rom_title);
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let dir = &self.config.paths.screenshot_dir;
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        let file = dir.join(format!("{}-{}.png", title, time));
+        self.frame
+            .save(&file)
+            .map_err(|e| format!("Failed to write {}: {}", file.display(), e))
     }
-}
 
-impl IcedApp {
     // type Executor = executor::Default;
     // type Flags = ();
     // type Message = AppMessage;
     // type Theme = Theme;
 
-    fn new(_flags: ()) -> IcedApp {
+    fn new(config: Config, config_file: Option<PathBuf>) -> IcedApp {
         let mut nes = Nes::default();
         //nes.load_rom(String::from("donkey_kong.nes"));
         //  nes.load_rom(String::from("super_mario_brothers.nes"));
-        _ = nes.load_rom(String::from("super_mario_brothers.nes"));
+        if let Ok(path) = std::env::var("RUSTY_NES_TRACE") {
+            match std::fs::File::create(&path) {
+                Ok(file) => nes.set_tracer(Some(Box::new(std::io::BufWriter::new(file)))),
+                Err(e) => println!("Can't open trace file {}: {}", path, e),
+            }
+        }
         // nes.load_rom(String::from("nes-test-roms/full_palette/full_palette.nes"));
         // nes.load_rom(String::from("nes-test-roms/ppu_vbl_nmi/rom_singles/01-vbl_basics.nes"));
-        IcedApp {
-            nes,
+        let (backend, gamepad_error) = match config
+            .gamepad
+            .load_mappings()
+            .and_then(|mappings| gamepad::host_backend(&mappings))
+        {
+            Ok(backend) => (backend, None),
+            Err(e) => (
+                Box::new(NoGamepads) as Box<dyn gamepad::GamepadBackend>,
+                Some(e),
+            ),
+        };
+        let gamepads = Gamepads::new(backend, &config.gamepad);
+        let mut app = IcedApp {
+            runner: Runner::spawn(nes),
+            screen: Screen::Library,
+            config,
+            config_file,
+            settings: None,
+            binding: None,
+            library: Vec::new(),
+            recent: RecentFiles::load(RecentFiles::default_file()),
+            status: gamepad_error,
+            rom: None,
             chr_image: None,
             nt_image: None,
-            frame_rate: 60.0,
             frame: image::RgbaImage::new(256, 240),
-            controller_state: IcedControllerState::default(),
+            bindings: Bindings::default(),
+            pads: [ControllerState::default(); 4],
+            turbo: [ControllerState::default(); 4],
+            gamepads,
+            gamepad_pads: [ControllerState::default(); 4],
+            gamepad_turbo: [ControllerState::default(); 4],
+            recording_macro: false,
+            zapper: ZapperState::default(),
+            arkanoid: ArkanoidState::default(),
+            power_pad: PowerPadState::default(),
+            family_keys: KeyboardState::default(),
+            keyboard_capture: false,
+            fast_forward: false,
+            slow_motion: false,
+            paused: false,
+            stats: Stats::default(),
+        };
+        app.apply_config();
+        app
+    }
+
+    // Pushes the options out to the places that use them
+    fn apply_config(&mut self) {
+        self.bindings = Bindings::new(&self.config.keys);
+        self.gamepads.set_config(&self.config.gamepad);
+        self.library = library::scan(&self.config.paths.rom_dirs);
+        self.runner
+            .send(Command::SetRegion(self.config.region.region()));
+        self.runner
+            .send(Command::SetTurboRate(self.config.input.turbo_rate as f64));
+        // Slow motion stays on across a settings change, at the new speed
+        self.runner.send(Command::SetSpeed(self.speed()));
+        let input = self.config.input.clone();
+        // Devices already plugged in stay, so saving the settings doesn't
+        // reset them
+        let attached = self.runner.with_nes(move |nes| {
+            match input.expansion {
+                // Validation keeps other expansion devices away from the Hori
+                ExpansionSetting::None if input.multitap == MultitapSetting::Hori => {
+                    keep_expansion::<HoriAdapter>(nes)
+                }
+                ExpansionSetting::None => _ = nes.detach_expansion(),
+                ExpansionSetting::Arkanoid => keep_expansion::<Arkanoid>(nes),
+                ExpansionSetting::FamilyTrainer => keep_expansion::<PowerPad>(nes),
+                // Keeps the tape too
+                ExpansionSetting::Keyboard => keep_expansion::<FamilyKeyboard>(nes),
+            }
+            if input.multitap == MultitapSetting::FourScore {
+                for port in 0..PORT_COUNT {
+                    if nes.input_device_mut::<Multitap>(port).is_none() {
+                        nes.attach_input(port, Box::new(Multitap::new(port)))?;
+                    }
+                }
+                return Ok(());
+            }
+            keep_input::<Controller>(nes, 0)?;
+            match input.port2 {
+                Port2Device::Controller => keep_input::<Controller>(nes, 1),
+                Port2Device::Zapper => keep_input::<Zapper>(nes, 1),
+                Port2Device::Arkanoid => keep_input::<Arkanoid>(nes, 1),
+                Port2Device::PowerPad => keep_input::<PowerPad>(nes, 1),
+            }
+        });
+        if let Some(Err(e)) = attached {
+            self.status = Some(e);
+        }
+        match self.config.palette() {
+            Ok(palette) => self
+                .runner
+                .send(Command::Run(Box::new(move |nes| nes.set_palette(&palette)))),
+            Err(e) => self.status = Some(e),
+        }
+    }
+
+    fn save_settings(&mut self) {
+        let Some(form) = &self.settings else {
+            return;
+        };
+        let config = match form.to_config() {
+            Ok(config) => config,
+            Err(e) => {
+                self.status = Some(e);
+                return;
+            }
+        };
+        if let Some(file) = &self.config_file
+            && let Err(e) = config.save(file)
+        {
+            self.status = Some(e);
+            return;
+        }
+        self.config = config;
+        self.settings = None;
+        self.binding = None;
+        self.status = None;
+        self.screen = Screen::Game;
+        self.keyboard_capture = false;
+        self.apply_config();
+    }
+
+    fn load_rom(&mut self, path: PathBuf) {
+        let result = std::fs::read(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+            .and_then(|bytes| {
+                self.runner
+                    .with_nes(move |nes| nes.load_rom_bytes(&bytes))
+                    .unwrap_or_else(|| Err(String::from("Emulation thread stopped")))
+            });
+        match result {
+            Ok(()) => {
+                self.recent.add(&path);
+                self.rom = Some(path);
+                self.status = None;
+                self.screen = Screen::Game;
+            }
+            Err(e) => self.status = Some(e),
         }
     }
 }
 
-fn subscription(state: &IcedApp) -> Subscription<AppMessage> {
+fn subscription(_state: &IcedApp) -> Subscription<AppMessage> {
     if true {
         //self.is_playing {
         Subscription::batch([
-            iced::time::every(Duration::from_millis(1000 / state.frame_rate as u64))
-                .map(AppMessage::Tick),
+            // Redraws at the display rate, showing the newest emulated frame
+            iced::window::frames().map(AppMessage::Tick),
             keyboard::on_key_press(|key, _modifiers| Some(AppMessage::KeyPress(key))),
             keyboard::on_key_release(|key, _modifiers| Some(AppMessage::KeyReleased(key))),
             // subscription::events().map(AppMessage::Event)
@@ -94,33 +580,151 @@ fn subscription(state: &IcedApp) -> Subscription<AppMessage> {
     }
 }
 
-fn update(state: &mut IcedApp, message: AppMessage) {
+fn update(state: &mut IcedApp, message: AppMessage) -> Task<AppMessage> {
     match message {
         AppMessage::RefreshChrPressed => {
-            let chr_image = state.nes.ppu.borrow().render_chr();
-            state.chr_image = Some(DynamicImage::ImageLuma8(chr_image).into_rgba8());
-            let nt_image = state.nes.ppu.borrow().render_nt();
-            state.nt_image = Some(DynamicImage::ImageRgb8(nt_image).into_rgba8());
-            state.nes.ppu.borrow().print_nametable();
+            let images = state.runner.with_nes(|nes| {
+                nes.print_nametable();
+                (nes.chr_image(), nes.nametable_image())
+            });
+            if let Some((chr_image, nt_image)) = images {
+                state.chr_image = Some(DynamicImage::ImageLuma8(chr_image).into_rgba8());
+                state.nt_image = Some(DynamicImage::ImageRgb8(nt_image).into_rgba8());
+            }
         }
         AppMessage::Tick(_instant) => {
-            println!("Frame update");
-            let t = time::SystemTime::now();
-            state
-                .nes
-                .inputs
-                .borrow_mut()
-                .set_controller1_state(state.controller_state.state);
-            state.frame = DynamicImage::ImageRgb8(state.nes.run_frame()).into_rgba8();
-            let d = t.elapsed();
-            println!("Took {}s", d.unwrap().as_millis());
+            if let Some(frame) = state.runner.latest_frame() {
+                state.frame = DynamicImage::ImageRgb8(frame).into_rgba8();
+            }
+            state.stats = state.runner.stats();
+            for event in state.runner.take_events() {
+                match event {
+                    Event::Error(e) => state.status = Some(e),
+                }
+            }
+            state.poll_gamepads();
+        }
+        AppMessage::KeyPress(key) => {
+            if let Some(index) = state.binding.take() {
+                if key != keyboard::Key::Named(keyboard::key::Named::Escape)
+                    && let (Some(form), Some(name)) = (&mut state.settings, keys::key_name(&key))
+                {
+                    form.set(index, name);
+                }
+                return Task::none();
+            }
+            // Text fields on the settings screen take the keys
+            if state.screen == Screen::Settings {
+                return Task::none();
+            }
+            let action = state.bindings.action(&key);
+            if state.keyboard_capture && action != Some(Action::Hotkey(Hotkey::KeyboardCapture)) {
+                state.on_family_key(&key, true);
+                return Task::none();
+            }
+            match action {
+                Some(Action::Pad(player, button)) => state.on_button(player, button, true),
+                Some(Action::PowerPad(button)) => state.on_power_pad(button, true),
+                Some(Action::Hotkey(hotkey)) => return state.on_hotkey(hotkey, true),
+                None => (),
+            }
+        }
+        AppMessage::KeyReleased(key) => {
+            if state.keyboard_capture {
+                state.on_family_key(&key, false);
+                return Task::none();
+            }
+            match state.bindings.action(&key) {
+                Some(Action::Pad(player, button)) => state.on_button(player, button, false),
+                Some(Action::PowerPad(button)) => state.on_power_pad(button, false),
+                Some(Action::Hotkey(hotkey)) => return state.on_hotkey(hotkey, false),
+                None => (),
+            }
+        }
+        AppMessage::TogglePause => {
+            state.paused = !state.paused;
+            state.runner.send(if state.paused {
+                Command::Pause
+            } else {
+                Command::Resume
+            });
+        }
+        AppMessage::FrameAdvance => {
+            if !state.paused {
+                state.paused = true;
+                state.runner.send(Command::Pause);
+            }
+            state.runner.send(Command::StepFrame);
+        }
+        AppMessage::Reset => state.runner.send(Command::Reset),
+        AppMessage::PowerCycle => state.runner.send(Command::PowerCycle),
+        AppMessage::ShowScreen(screen) => {
+            state.binding = None;
+            state.settings = (screen == Screen::Settings).then(|| SettingsForm::new(&state.config));
+            state.screen = screen;
+        }
+        AppMessage::RescanLibrary => state.library = library::scan(&state.config.paths.rom_dirs),
+        AppMessage::OpenFile => {
+            let dialog = rfd::AsyncFileDialog::new()
+                .add_filter("NES ROM", &["nes", "NES"])
+                .pick_file();
+            return Task::perform(dialog, |file| {
+                AppMessage::FilePicked(file.map(|f| f.path().to_owned()))
+            });
+        }
+        AppMessage::FilePicked(path) => {
+            if let Some(path) = path {
+                state.load_rom(path);
+            }
+        }
+        AppMessage::LoadRom(path) => state.load_rom(path),
+        AppMessage::SettingChanged(index, value) => {
+            if let Some(form) = &mut state.settings {
+                form.set(index, value);
+            }
+        }
+        AppMessage::MouseMoved(position) => state.on_mouse_move(position),
+        AppMessage::MouseButton(pressed) => state.on_mouse_button(pressed),
+        AppMessage::BindKey(index) => state.binding = Some(index),
+        AppMessage::SaveSettings => state.save_settings(),
+        AppMessage::Tape(mode) => state.set_tape_mode(mode),
+        AppMessage::LoadTape => {
+            let dialog = rfd::AsyncFileDialog::new()
+                .add_filter("Tape", &["wav", "tape"])
+                .pick_file();
+            return Task::perform(dialog, |file| {
+                AppMessage::TapePicked(file.map(|f| f.path().to_owned()))
+            });
+        }
+        AppMessage::TapePicked(path) => {
+            if let Some(path) = path {
+                state.status = state.load_tape(&path).err();
+            }
+        }
+        AppMessage::SaveTape => {
+            let dialog = rfd::AsyncFileDialog::new()
+                .add_filter("WAV", &["wav"])
+                .add_filter("Raw tape", &["tape"])
+                .save_file();
+            return Task::perform(dialog, |file| {
+                AppMessage::TapeSaveTo(file.map(|f| f.path().to_owned()))
+            });
+        }
+        AppMessage::TapeSaveTo(path) => {
+            if let Some(path) = path {
+                state.status = state.save_tape(&path).err();
+            }
         }
-        AppMessage::KeyPress(key) => state.controller_state.on_event(key, true),
-        AppMessage::KeyReleased(key) => state.controller_state.on_event(key, false),
     }
+    Task::none()
 }
 
-fn view(state: &IcedApp) -> Element<AppMessage> {
+fn view(state: &IcedApp) -> Element<'_, AppMessage> {
+    match state.screen {
+        Screen::Library => return library_view(state),
+        Screen::Settings => return settings_view(state),
+        Screen::Game => (),
+    }
     // "Hello, world!".into();
     // let chr_image = self.nes.ppu.borrow().render_chr();
     // let chr_image = DynamicImage::ImageLuma8(chr_image).into_rgba8().as_bytes().to_owned();
@@ -137,20 +741,98 @@ fn view(state: &IcedApp) -> Element<AppMessage> {
         widget::image::Handle::from_rgba(200, 200, data)
     };
     let frame = state.frame.clone();
+    let scale = state.config.video.scale as f32;
     let frame = widget::image::Handle::from_rgba(
         frame.width(),
         frame.height(),
         frame.as_bytes().to_owned(),
     );
+    let screen = widget::image(frame)
+        .width(rusty_nes::FRAME_WIDTH as f32 * scale)
+        .height(rusty_nes::FRAME_HEIGHT as f32 * scale)
+        .filter_method(widget::image::FilterMethod::Nearest);
+    // The mouse is the Zapper or the Arkanoid paddle
+    let input = &state.config.input;
+    let zapper = input.port2 == Port2Device::Zapper;
+    let paddle =
+        input.port2 == Port2Device::Arkanoid || input.expansion == ExpansionSetting::Arkanoid;
+    let screen: Element<'_, AppMessage> = if zapper || paddle {
+        widget::mouse_area(screen)
+            .on_move(|p| AppMessage::MouseMoved(Some(p)))
+            .on_exit(AppMessage::MouseMoved(None))
+            .on_press(AppMessage::MouseButton(true))
+            .on_release(AppMessage::MouseButton(false))
+            .interaction(if zapper {
+                iced::mouse::Interaction::Crosshair
+            } else {
+                iced::mouse::Interaction::Idle
+            })
+            .into()
+    } else {
+        screen.into()
+    };
     // widget::image::Handle::from_pixels(144,171,Some(chr_image);
+    let hotkeys = &state.config.keys.hotkeys;
+    let controls = widget::row![
+        widget::button(widget::text(hotkey_label(
+            if state.paused { "Resume" } else { "Pause" },
+            &hotkeys.pause
+        )))
+        .on_press(AppMessage::TogglePause),
+        widget::button(widget::text(hotkey_label(
+            "Frame advance",
+            &hotkeys.frame_advance
+        )))
+        .on_press(AppMessage::FrameAdvance),
+        widget::button(widget::text(hotkey_label("Reset", &hotkeys.reset)))
+            .on_press(AppMessage::Reset),
+        widget::button(widget::text(hotkey_label("Power", &hotkeys.power_cycle)))
+            .on_press(AppMessage::PowerCycle),
+        widget::button(widget::text(hotkey_label("Open", &hotkeys.open)))
+            .on_press(AppMessage::OpenFile),
+        widget::button("Library").on_press(AppMessage::ShowScreen(Screen::Library)),
+        widget::button("Settings").on_press(AppMessage::ShowScreen(Screen::Settings)),
+    ]
+    .spacing(5);
+    let tape: Element<'_, AppMessage> = if input.expansion == ExpansionSetting::Keyboard {
+        widget::row![
+            widget::text(hotkey_label(
+                if state.keyboard_capture {
+                    "Keyboard captured"
+                } else {
+                    "Capture keyboard"
+                },
+                &hotkeys.keyboard_capture
+            )),
+            widget::button("Load tape").on_press(AppMessage::LoadTape),
+            widget::button("Play").on_press(AppMessage::Tape(TapeMode::Playing)),
+            widget::button("Record").on_press(AppMessage::Tape(TapeMode::Recording)),
+            widget::button("Stop").on_press(AppMessage::Tape(TapeMode::Stopped)),
+            widget::button("Save tape").on_press(AppMessage::SaveTape),
+        ]
+        .spacing(5)
+        .align_y(iced::Alignment::Center)
+        .into()
+    } else {
+        widget::row![].into()
+    };
     let content = iced::widget::column![
+        controls,
+        tape,
+        widget::text(state.status.clone().unwrap_or_default()),
         widget::text(String::from("NES Screen"))
             .size(30)
             .width(iced::Length::Fill),
-        widget::image::viewer(frame)
-            .max_scale(1.0)
-            .min_scale(2.0)
-            .width(iced::Length::Fill),
+        widget::stack![
+            screen,
+            widget::text(format!(
+                "{:.1} fps {:.0}%{}",
+                state.stats.fps,
+                state.stats.speed * 100.0,
+                if state.recording_macro { " REC" } else { "" }
+            ))
+            .size(14),
+        ],
         widget::text(String::from("CHR Data"))
             .size(30)
             .width(iced::Length::Fill),
@@ -172,81 +854,167 @@ fn view(state: &IcedApp) -> Element<AppMessage> {
         .into()
 }
 
-#[derive(Default)]
-struct IcedControllerState {
-    state: nes::input::ControllerState,
-}
-
-impl IcedControllerState {
-    fn on_event(&mut self, key: iced::keyboard::Key, active: bool) {
-        println!("Got key {:?} {active}", key);
-        use keyboard::Key;
-        use keyboard::key::Named;
-
-        match key {
-            Key::Named(name) => match name {
-                Named::ArrowUp => self.state.up = active,
-                Named::ArrowDown => self.state.down = active,
-                Named::ArrowLeft => self.state.left = active,
-                Named::ArrowRight => self.state.right = active,
-                Named::Enter => self.state.start = active,
-                Named::Shift => self.state.select = active,
-                _ => (),
-            },
-            Key::Character(c) => match c.as_str() {
-                "x" => self.state.a = active,
-                "z" => self.state.b = active,
-                _ => (),
-            },
-            _ => {}
-        }
-    }
-}
-
-// fn main() {
-//     println!("Welcome to Tom's NES Emulator");
-//     let mut nes = Nes::new();
-//     // nes.load_rom(String::from("nes-test-roms/instr_test-v5/rom_singles/01-basics.nes"));
-//     // cpu.load_rom(String::from("nes-test-roms/instr_test-v5/rom_singles/02-implied.nes"));
-//     // cpu.load_rom(String::from("nes-test-roms/instr_test-v5/rom_singles/03-immediate.nes"));
-//     // cpu.load_rom(String::from("nes-test-roms/instr_test-v5/rom_singles/14-rti.nes"));
-//     // cpu.load_rom(String::from("nes-test-roms/instr_test-v5/rom_singles/15-brk.nes"));
-//     // cpu.load_rom(String::from("nes-test-roms/instr_test-v5/rom_singles/16-special.nes"));
-//     nes.load_rom(String::from("nes-test-roms/instr_test-v5/official_only.nes"));
-//     // nes.load_rom(String::from("donkey_kong.nes"));
-//     for _ in 1..20000000 {
-//         let (_count, done) = nes.cpu.borrow_mut().run_instruction();
-//         if done {
-//             println!("Loop detected");
-//             break;
-//         }
-//         // if i % 100 == 0 {
-//         // println!();
-//         // println!("******************************");
-//         // println!("***     PROGRESS UPDATE    ***");
-//         // println!("Test status: {}, {:x}, {:x},{:x}", cpu.memory.read_byte(0x6000), cpu.memory.read_byte(0x6001), cpu.memory.read_byte(0x6002), cpu.memory.read_byte(0x6003));
-//         // println!("Test output:");
-//         // for i in 0..100 {
-//         //     let c = cpu.memory.read_byte(0x6004+i) as char;
-//         //     // if c != '\0' {
-//         //         print!("{}", c);
-//         //     // } else {
-//         //         // break;
-//         //     // }
-//         // }
-//         // println!("******************************");
-//         // }
-//     }
-//     // Test result
-//     println!(
-//         "Test result: {}, {:x}, {:x},{:x}",
-//         nes.mem.borrow().read_byte(0x6000),
-//         nes.mem.borrow().read_byte(0x6001),
-//         nes.mem.borrow().read_byte(0x6002),
-//         nes.mem.borrow().read_byte(0x6003)
-//     );
-//     println!("Test output:");
-//     for i in 0..100 {
-//         print!("{}", nes.mem.borrow().read_byte(0x6004 + i) as char);
-//     }
-// }
+fn library_view(state: &IcedApp) -> Element<'_, AppMessage> {
+    use iced::Length::{Fill, FillPortion};
+
+    let controls = widget::row![
+        widget::button(widget::text(hotkey_label(
+            "Open",
+            &state.config.keys.hotkeys.open
+        )))
+        .on_press(AppMessage::OpenFile),
+        widget::button("Rescan").on_press(AppMessage::RescanLibrary),
+        widget::button("Back to game").on_press(AppMessage::ShowScreen(Screen::Game)),
+        widget::button("Settings").on_press(AppMessage::ShowScreen(Screen::Settings)),
+    ]
+    .spacing(5);
+
+    let mut recent = widget::column![widget::text("Recent").size(20)].spacing(2);
+    for path in state.recent.paths() {
+        recent = recent.push(
+            widget::button(widget::text(path.display().to_string()))
+                .on_press(AppMessage::LoadRom(path.clone()))
+                .style(widget::button::text),
+        );
+    }
+
+    let mut roms = widget::column![widget::row![
+        widget::text("Title").width(FillPortion(4)),
+        widget::text("Mapper").width(FillPortion(1)),
+        widget::text("Region").width(FillPortion(1)),
+        widget::text("Supported").width(FillPortion(1)),
+        widget::text("").width(FillPortion(1)),
+    ]]
+    .spacing(2);
+    for entry in &state.library {
+        let (mapper, region) = match &entry.info {
+            Ok(info) => (info.mapper.to_string(), format!("{:?}", info.region)),
+            Err(e) => (e.clone(), String::new()),
+        };
+        let supported = entry.is_supported();
+        roms = roms.push(
+            widget::row![
+                widget::text(entry.title.clone()).width(FillPortion(4)),
+                widget::text(mapper).width(FillPortion(1)),
+                widget::text(region).width(FillPortion(1)),
+                widget::text(if supported { "Yes" } else { "No" }).width(FillPortion(1)),
+                widget::button("Play")
+                    .on_press_maybe(supported.then(|| AppMessage::LoadRom(entry.path.clone())))
+                    .width(FillPortion(1)),
+            ]
+            .align_y(iced::Alignment::Center),
+        );
+    }
+
+    let content = widget::column![
+        controls,
+        widget::text(state.status.clone().unwrap_or_default()),
+        recent,
+        widget::text(format!("Library ({} ROMs)", state.library.len())).size(20),
+        widget::scrollable(roms).height(Fill),
+    ]
+    .spacing(10)
+    .padding(10);
+    iced::widget::container(content)
+        .width(Fill)
+        .height(Fill)
+        .into()
+}
+
+fn settings_view(state: &IcedApp) -> Element<'_, AppMessage> {
+    use iced::Length::{Fill, FillPortion};
+
+    let Some(form) = &state.settings else {
+        return widget::text("").into();
+    };
+    let mut fields = widget::column![].spacing(4);
+    for (index, (name, value)) in form.fields.iter().enumerate() {
+        let input: Element<'_, AppMessage> = if form.is_key(index) {
+            let label = if state.binding == Some(index) {
+                "Press a key, Escape to cancel"
+            } else if value.is_empty() {
+                "Unbound"
+            } else {
+                value
+            };
+            widget::row![
+                widget::button(widget::text(label))
+                    .on_press(AppMessage::BindKey(index))
+                    .width(Fill),
+                widget::button("Clear").on_press(AppMessage::SettingChanged(index, String::new())),
+            ]
+            .spacing(5)
+            .into()
+        } else {
+            widget::text_input("", value)
+                .on_input(move |value| AppMessage::SettingChanged(index, value))
+                .into()
+        };
+        fields = fields.push(
+            widget::row![
+                widget::text(name.as_str()).width(FillPortion(1)),
+                widget::container(input).width(FillPortion(3)),
+            ]
+            .align_y(iced::Alignment::Center),
+        );
+    }
+    let content = widget::column![
+        widget::row![
+            widget::button("Save").on_press(AppMessage::SaveSettings),
+            widget::button("Cancel").on_press(AppMessage::ShowScreen(Screen::Game)),
+        ]
+        .spacing(5),
+        widget::text(state.status.clone().unwrap_or_default()),
+        widget::text("Window scale applies on restart. Directories are separated like PATH.")
+            .size(12),
+        widget::scrollable(fields).height(Fill),
+    ]
+    .spacing(10)
+    .padding(10);
+    iced::widget::container(content)
+        .width(Fill)
+        .height(Fill)
+        .into()
+}
+
+// "Pause (P)", or just "Pause" when unbound
+fn hotkey_label(text: &str, key: &str) -> String {
+    match key.chars().count() {
+        0 => text.to_string(),
+        1 => format!("{} ({})", text, key.to_uppercase()),
+        _ => format!("{} ({})", text, key),
+    }
+}
+
+fn rom_title(rom: &Path) -> String {
+    rom.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// Plugs a new T into the port unless one is there already
+fn keep_input<T: InputDevice + Default>(nes: &mut Nes, port: usize) -> Result<(), String> {
+    if nes.input_device_mut::<T>(port).is_some() {
+        return Ok(());
+    }
+    nes.attach_input(port, Box::<T>::default())
+}
+
+fn keep_expansion<T: ExpansionDevice + Default>(nes: &mut Nes) {
+    if nes.expansion_device_mut::<T>().is_none() {
+        nes.attach_expansion(Box::<T>::default())
+    }
+}
+
+fn write_file(file: &Path, bytes: &[u8]) -> Result<(), String> {
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    std::fs::write(file, bytes).map_err(|e| format!("Failed to write {}: {}", file.display(), e))
+}
+
+fn is_wav(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"))
+}
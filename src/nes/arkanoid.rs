@@ -0,0 +1,114 @@
+// Arkanoid "Vaus" paddle. A strobe latches the knob's potentiometer into an
+// 8-bit shift register that's read out MSB first and inverted. The NES
+// version goes in port 2 and answers on D4 with the button on D3; the
+// Famicom version plugs into the expansion port and uses D1 of $4017 for
+// the knob and D1 of $4016 for the button.
+
+use super::input::{ExpansionDevice, InputDevice};
+
+// Knob range that covers the playfield in Arkanoid
+pub const ARKANOID_MIN: u8 = 0x62;
+pub const ARKANOID_MAX: u8 = 0xF2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArkanoidState {
+    pub position: u8,
+    pub fire: bool,
+}
+
+impl Default for ArkanoidState {
+    fn default() -> Self {
+        ArkanoidState {
+            position: ARKANOID_MIN,
+            fire: false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Arkanoid {
+    state: ArkanoidState,
+    shift_register: u8,
+    strobe: bool,
+}
+
+impl Arkanoid {
+    pub fn set_state(&mut self, state: ArkanoidState) {
+        self.state = state
+    }
+
+    fn strobe(&mut self, val: u8) {
+        self.strobe = val & 0x01 != 0;
+        if self.strobe {
+            self.shift_register = !self.state.position;
+        }
+    }
+
+    // Next knob bit in bit 0
+    fn shift(&mut self) -> u8 {
+        if self.strobe {
+            self.shift_register = !self.state.position;
+        }
+        let bit = self.shift_register >> 7;
+        self.shift_register <<= 1;
+        bit
+    }
+}
+
+impl InputDevice for Arkanoid {
+    fn write(&mut self, val: u8) {
+        self.strobe(val)
+    }
+
+    fn read(&mut self) -> u8 {
+        let fire = if self.state.fire { 0x08 } else { 0 };
+        fire | self.shift() << 4
+    }
+}
+
+impl ExpansionDevice for Arkanoid {
+    fn write(&mut self, val: u8) {
+        self.strobe(val)
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        match port {
+            0 if self.state.fire => 0x02,
+            0 => 0,
+            _ => self.shift() << 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::input::{ExpansionDevice, InputDevice};
+    use super::{Arkanoid, ArkanoidState};
+
+    #[test]
+    fn test_arkanoid() {
+        let mut paddle = Arkanoid::default();
+        paddle.set_state(ArkanoidState {
+            position: 0xA5,
+            fire: true,
+        });
+        InputDevice::write(&mut paddle, 1);
+        InputDevice::write(&mut paddle, 0);
+        let knob: u8 = (0..8).fold(0, |val, _| {
+            let read = InputDevice::read(&mut paddle);
+            assert_eq!(read & 0x08, 0x08);
+            val << 1 | (read >> 4 & 1)
+        });
+        assert_eq!(knob, !0xA5);
+
+        paddle.set_state(ArkanoidState {
+            position: 0x80,
+            fire: false,
+        });
+        ExpansionDevice::write(&mut paddle, 1);
+        ExpansionDevice::write(&mut paddle, 0);
+        assert_eq!(ExpansionDevice::read(&mut paddle, 0), 0);
+        assert_eq!(ExpansionDevice::read(&mut paddle, 1), 0);
+        assert_eq!(ExpansionDevice::read(&mut paddle, 1), 0x02);
+    }
+}
@@ -0,0 +1,51 @@
+// What the CPU sees of the rest of the system. MemoryMap is the real one;
+// the cpu tests stand in FlatRam.
+
+pub trait Bus {
+    fn read_byte(&mut self, address: u16) -> u8;
+
+    // Returns extra cycles the CPU is stalled for (OAM DMA)
+    fn write_byte(&mut self, address: u16, val: u8) -> u32;
+
+    fn nmi_requested(&self) -> bool;
+
+    // Little endian
+    fn read_word(&mut self, address: u16) -> u16 {
+        (self.read_byte(address) as u16) | ((self.read_byte(address.wrapping_add(1)) as u16) << 8)
+    }
+}
+
+// 64K of plain RAM with no mirroring or devices, for cpu tests
+#[cfg(test)]
+pub struct FlatRam {
+    pub ram: Vec<u8>,
+    pub nmi: bool,
+}
+
+#[cfg(test)]
+impl FlatRam {
+    // Loads program at start and points the reset vector at it
+    pub fn with_program(start: u16, program: &[u8]) -> Self {
+        let mut ram = vec![0; 0x10000];
+        ram[start as usize..start as usize + program.len()].copy_from_slice(program);
+        ram[0xFFFC] = start as u8;
+        ram[0xFFFD] = (start >> 8) as u8;
+        Self { ram, nmi: false }
+    }
+}
+
+#[cfg(test)]
+impl Bus for FlatRam {
+    fn read_byte(&mut self, address: u16) -> u8 {
+        self.ram[address as usize]
+    }
+
+    fn write_byte(&mut self, address: u16, val: u8) -> u32 {
+        self.ram[address as usize] = val;
+        0
+    }
+
+    fn nmi_requested(&self) -> bool {
+        self.nmi
+    }
+}
@@ -1,10 +1,9 @@
-use super::memory_utils::read_word_from_buffer;
-use std::fs::read;
+use super::state::{StateReader, StateWriter};
 
-pub trait Cartridge {
-    // PRG
-    fn read_byte(&self, address: u16) -> u8;
-    fn read_word(&self, address: u16) -> u16;
+pub trait Cartridge: Send {
+    // PRG, $4020-$FFFF. Reads are None where nothing answers, leaving the
+    // CPU's open bus
+    fn read_byte(&self, address: u16) -> Option<u8>;
     fn write_byte(&mut self, address: u16, val: u8);
 
     // CHR
@@ -13,9 +12,13 @@ pub trait Cartridge {
 
     // Info
     fn get_nt_mirroring(&self) -> Mirroring;
+
+    // Mapper registers and RAM, for save states
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String>;
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Mirroring {
     OneScreen,
     UpperBank,
@@ -23,6 +26,35 @@ pub enum Mirroring {
     Horizontal,
 }
 
+// Placeholder for an empty slot, nothing answers
+pub struct NoCartridge;
+
+static NO_CHR: [u8; 0x2000] = [0; 0x2000];
+
+impl Cartridge for NoCartridge {
+    fn read_byte(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn write_byte(&mut self, _address: u16, _val: u8) {}
+
+    fn get_chr(&self) -> &[u8; 0x2000] {
+        &NO_CHR
+    }
+
+    fn write_byte_chr(&mut self, _address: u16, _val: u8) {}
+
+    fn get_nt_mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
+
 struct CartridgeMapper0 {
     prg_rom: [u8; 0x8000],
     chr_rom: [u8; 0x2000],
@@ -53,9 +85,10 @@ impl CartridgeMapper0 {
         ret.chr_rom[0..0x2000].clone_from_slice(chr_data);
         ret
     }
-    fn map_address(&self, address: u16) -> (&[u8], usize) {
+    // Nothing is mapped below $6000
+    fn map_address(&self, address: u16) -> Option<(&[u8], usize)> {
         match address {
-            0x6000..=0x7FFF => (&self.ram, address as usize - 0x6000),
+            0x6000..=0x7FFF => Some((&self.ram, address as usize - 0x6000)),
             0x8000..=0xFFFF => {
                 let offset = address as usize - 0x8000;
                 let offset = if self.mirrored {
@@ -63,45 +96,26 @@ impl CartridgeMapper0 {
                 } else {
                     offset
                 };
-                (&self.prg_rom, offset)
+                Some((&self.prg_rom, offset))
             }
-            _ => panic!("Invalid cartrige address {}", address),
-        }
-    }
-    fn map_address_mut(&mut self, address: u16) -> (&mut [u8], usize) {
-        match address {
-            0x6000..=0x7FFF => (&mut self.ram, address as usize - 0x6000),
-            0x8000..=0xFFFF => {
-                let offset = address as usize - 0x8000;
-                let offset = if self.mirrored {
-                    offset % 0x4000
-                } else {
-                    offset
-                };
-                (&mut self.prg_rom, offset)
-            }
-            _ => panic!("Invalid cartrige address {}", address),
+            _ => None,
         }
     }
 }
 
 impl Cartridge for CartridgeMapper0 {
-    fn read_byte(&self, address: u16) -> u8 {
+    fn read_byte(&self, address: u16) -> Option<u8> {
         // println!("reading byte {:x}",address);
-        let (buf, offset) = self.map_address(address);
-        buf[offset]
-    }
-
-    fn read_word(&self, address: u16) -> u16 {
-        // println!("reading word {:x}",address);
-        let (buf, offset) = self.map_address(address);
-        read_word_from_buffer(buf, offset)
+        let (buf, offset) = self.map_address(address)?;
+        Some(buf[offset])
     }
 
+    // Only the RAM is writable
     fn write_byte(&mut self, address: u16, val: u8) {
         // println!("writing byte {:x}",address);
-        let (buf, offset) = self.map_address_mut(address);
-        buf[offset] = val;
+        if let 0x6000..=0x7FFF = address {
+            self.ram[address as usize - 0x6000] = val;
+        }
     }
 
     fn get_chr(&self) -> &[u8; 0x2000] {
@@ -112,13 +126,20 @@ impl Cartridge for CartridgeMapper0 {
         self.nt_mirroring
     }
 
-    fn write_byte_chr(&mut self, _address: u16, _val: u8) {
-        panic!("Can't write to this mapper's CHR!");
+    // CHR ROM ignores writes
+    fn write_byte_chr(&mut self, _address: u16, _val: u8) {}
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.bytes_into(&mut self.ram)
     }
 }
 
 mod mmc1 {
-    use super::super::memory_utils;
+    use super::super::state::{StateReader, StateWriter};
     use super::Mirroring;
 
     // enum Mirroring {
@@ -192,9 +213,7 @@ mod mmc1 {
                 CHRSize::Size4k => 1,
             };
 
-            let ret = m + (s << 2) + (c << 4);
-            println!("{},{},{} -> {:x}", m, s, c, ret);
-            ret
+            m + (s << 2) + (c << 4)
         }
     }
 
@@ -214,8 +233,14 @@ mod mmc1 {
         }
     }
 
+    impl From<&PRGReg> for u8 {
+        fn from(p: &PRGReg) -> u8 {
+            p.bank | if p.wram_enable { 0x10 } else { 0 }
+        }
+    }
+
     enum MMC1Chr {
-        Ram([u8; 0x2000]),
+        Ram(Box<[u8; 0x2000]>),
         Rom(Vec<u8>),
     }
     pub struct CartridgeMapper1 {
@@ -247,7 +272,7 @@ mod mmc1 {
                 chr: if !chr.is_empty() {
                     MMC1Chr::Rom(chr)
                 } else {
-                    MMC1Chr::Ram([0; 0x2000])
+                    MMC1Chr::Ram(Box::new([0; 0x2000]))
                 },
                 shift_register: 0x10,
                 control_register: ConfigReg {
@@ -264,8 +289,8 @@ mod mmc1 {
             }
         }
 
-        fn map_address(&self, address: u16) -> (&[u8], usize) {
-            match address {
+        fn map_address(&self, address: u16) -> Option<(&[u8], usize)> {
+            Some(match address {
                 0x6000..=0x7FFF => (&self.prg_ram, address as usize - 0x6000),
                 0x8000..=0xFFFF => {
                     match &self.control_register.prg_swapping {
@@ -299,22 +324,16 @@ mod mmc1 {
                         }
                     }
                 }
-                _ => panic!("Invalid address to map"),
-            }
+                _ => return None,
+            })
         }
     }
 
     impl super::Cartridge for CartridgeMapper1 {
-        fn read_byte(&self, address: u16) -> u8 {
+        fn read_byte(&self, address: u16) -> Option<u8> {
             // println!("reading byte {:x}",address);
-            let (buf, offset) = self.map_address(address);
-            buf[offset]
-        }
-
-        fn read_word(&self, address: u16) -> u16 {
-            // println!("reading word {:x}",address);
-            let (buf, offset) = self.map_address(address);
-            memory_utils::read_word_from_buffer(buf, offset)
+            let (buf, offset) = self.map_address(address)?;
+            Some(buf[offset])
         }
 
         fn write_byte(&mut self, address: u16, val: u8) {
@@ -355,7 +374,7 @@ mod mmc1 {
                         }
                     }
                 }
-                _ => panic!("Invalid cartridge address {:x}", address),
+                _ => {} // Nothing below $6000
             };
             // todo: consecutive write cycles
         }
@@ -382,74 +401,148 @@ mod mmc1 {
                 }
             }
         }
+
+        fn save_state(&self, w: &mut StateWriter) {
+            w.bytes(&self.prg_ram);
+            if let MMC1Chr::Ram(ram) = &self.chr {
+                w.bytes(&ram[..]);
+            }
+            w.u8(self.shift_register);
+            w.u8((&self.control_register).into());
+            w.u8(self.chr_bank0_register);
+            w.u8(self.chr_bank1_register);
+            w.u8((&self.pgr_bank_register).into());
+        }
+
+        fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+            r.bytes_into(&mut self.prg_ram)?;
+            if let MMC1Chr::Ram(ram) = &mut self.chr {
+                r.bytes_into(&mut ram[..])?;
+            }
+            self.shift_register = r.u8()?;
+            self.control_register = ConfigReg::new(r.u8()?);
+            self.chr_bank0_register = r.u8()?;
+            self.chr_bank1_register = r.u8()?;
+            self.pgr_bank_register = PRGReg::new(r.u8()?);
+            Ok(())
+        }
     }
 }
 
-// todo move this
-pub fn load_rom(path: String) -> Result<Box<dyn Cartridge>, String> {
-    let Ok(bytes) = read(path) else {
-        panic!("failed to read"); // todo
-    };
-
-    let header = &bytes[..16];
-    if header[0..4] == [b'N', b'E', b'S', 0x1A] {
-        println!("Detected NES cartridge!. Size: {}", bytes.len());
-    } else {
-        panic!("Invalid cartridge!");
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    // Works on both
+    Dual,
+}
+
+// What the iNES header says about a ROM
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RomInfo {
+    pub mapper: u16,
+    pub mirroring: Mirroring,
+    pub four_screen: bool,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub region: Region,
+    pub nes2: bool,
+}
+
+impl RomInfo {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 16 || bytes[0..4] != [b'N', b'E', b'S', 0x1A] {
+            return Err(String::from("Not an iNES file"));
+        }
+        let header = &bytes[..16];
+        let flags6 = header[6];
+        let flags7 = header[7];
+        let nes2 = flags7 & 0x0C == 0x08;
+
+        let mut mapper = (((flags6 & 0xF0) >> 4) | (flags7 & 0xF0)) as u16;
+        let mut prg_rom_size = (header[4] as usize) * 16384;
+        let mut chr_rom_size = (header[5] as usize) * 8192;
+        let region = if nes2 {
+            mapper |= ((header[8] & 0x0F) as u16) << 8;
+            // TODO: exponent-multiplier sizes
+            prg_rom_size += ((header[9] & 0x0F) as usize) << 22;
+            chr_rom_size += ((header[9] & 0xF0) as usize) << 17;
+            match header[12] & 0x03 {
+                0 => Region::Ntsc,
+                1 => Region::Pal,
+                _ => Region::Dual,
+            }
+        } else if header[9] & 0x01 != 0 {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        };
+
+        Ok(Self {
+            mapper,
+            mirroring: match flags6 & 0x01 {
+                0 => Mirroring::Horizontal,
+                _ => Mirroring::Vertical,
+            },
+            four_screen: flags6 & 0x08 != 0,
+            prg_rom_size,
+            chr_rom_size,
+            has_battery: flags6 & 0x02 != 0,
+            has_trainer: flags6 & 0x04 != 0,
+            region,
+            nes2,
+        })
     }
-    println!("{:?}", header);
-
-    let prg_rom_size = (header[4] as usize) * 16384;
-    let chr_rom_size = (header[5] as usize) * 8192;
-    println!("PRG size: {}, chr size: {}", prg_rom_size, chr_rom_size);
-
-    let flags6 = header[6];
-    let flags7 = header[7];
-    let flags8 = header[8];
-    let flags9 = header[9];
-
-    println!(
-        "Flags: 6: {:x}, 7: {:x}, 8: {:x}, 9: {:x}",
-        flags6, flags7, flags8, flags9
-    );
-
-    let cartridge_type = ((flags6 & 0xF0) >> 4) | (flags7 & 0xF0);
-    let nt_mirroring = match flags6 & 0x01 {
-        0 => Mirroring::Horizontal,
-        1 => Mirroring::Vertical,
-        _ => panic!(),
-    };
-    // TODO: interpret flags, v2
-    // TODO: trainer
-    // TODO: battery ram save
 
-    let prg_addr = 16;
-    let prg_data = &bytes[prg_addr..(prg_addr + prg_rom_size)];
-    let chr_addr = prg_addr + prg_rom_size;
-    let chr_data = &bytes[chr_addr..(chr_addr + chr_rom_size)];
+    // Whether we have a mapper for it
+    pub fn is_supported(&self) -> bool {
+        matches!(self.mapper, 0 | 1)
+    }
+}
+
+pub fn parse_rom(bytes: &[u8]) -> Result<Box<dyn Cartridge>, String> {
+    let info = RomInfo::parse(bytes)?;
+    // TODO: battery ram save
+    // TODO: playchoice
 
-    // todo: playchoice
+    let prg_addr = 16 + if info.has_trainer { 512 } else { 0 };
+    let chr_addr = prg_addr + info.prg_rom_size;
+    if bytes.len() < chr_addr + info.chr_rom_size {
+        return Err(String::from("ROM is smaller than its header says"));
+    }
+    let prg_data = &bytes[prg_addr..chr_addr];
+    let chr_data = &bytes[chr_addr..(chr_addr + info.chr_rom_size)];
 
-    // Write PRG
-    match cartridge_type {
+    match info.mapper {
         0 => Ok(Box::new(CartridgeMapper0::new(
             prg_data,
             chr_data,
-            nt_mirroring,
+            info.mirroring,
         ))),
         1 => Ok(Box::new(mmc1::CartridgeMapper1::new(
             prg_data.to_vec(),
             chr_data.to_vec(),
-            nt_mirroring,
+            info.mirroring,
         ))),
-        _ => panic!("Unimplemented cartridge type {}", cartridge_type),
+        mapper => Err(format!("Unimplemented cartridge type {}", mapper)),
     }
-    // self.write_bytes(0x8000, prg_data);
-    // if prg_rom_size == 0x4000 {
-    //     self.write_bytes(0xC000, prg_data);
-    // }
+}
 
-    // println!("{:x?}", &prg_data);
+// Builds an iNES image of a 16k NROM cartridge, for tests
+#[cfg(test)]
+pub fn nrom_image(code: &[(u16, &[u8])], reset: u16) -> Vec<u8> {
+    let mut prg = vec![0xEAu8; 0x4000];
+    for (addr, bytes) in code {
+        let offset = (*addr as usize - 0x8000) % 0x4000;
+        prg[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+    prg[0x3FFC] = (reset & 0xFF) as u8;
+    prg[0x3FFD] = (reset >> 8) as u8;
 
-    // panic!();
+    let mut image = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    image.extend(prg);
+    image.extend([0u8; 0x2000]);
+    image
 }
@@ -1,9 +1,7 @@
-use std::{cell::RefCell, rc::Rc};
-
 use self::cpu_helpers::push_stack;
 
-#[allow(unused)]
-use super::memory::MemoryMap;
+use super::bus::Bus;
+use super::state::{SaveState, StateReader, StateWriter};
 
 use bitflags::bitflags;
 
@@ -29,20 +27,29 @@ struct CpuRegisters {
     s: u8,     // Stack pointer
 }
 
-mod cpu_helpers {
+// Copy of the registers for tracing/debugging
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub pc: u16,
+    pub p: u8,
+    pub s: u8,
+}
 
-    use std::cell::RefMut;
+mod cpu_helpers {
 
-    use super::super::memory::MemoryMap;
+    use super::super::bus::Bus;
 
     use super::CpuRegisters;
 
-    pub(super) fn push_stack(reg: &mut CpuRegisters, mem: &mut RefMut<MemoryMap>, val: u8) {
+    pub(super) fn push_stack(reg: &mut CpuRegisters, mem: &mut dyn Bus, val: u8) {
         mem.write_byte(reg.s as u16 + 0x100, val);
         // println!("   PUSHED {:x} to stack at {:x}", val, reg.s as u16 + 0x100);
         reg.s = reg.s.wrapping_sub(1);
     }
-    pub(super) fn pop_stack(reg: &mut CpuRegisters, mem: &RefMut<MemoryMap>) -> u8 {
+    pub(super) fn pop_stack(reg: &mut CpuRegisters, mem: &mut dyn Bus) -> u8 {
         reg.s = reg.s.wrapping_add(1);
         // println!("   POPPED {:x} from stack at {:x}", mem.read_byte(reg.s as u16 + 0x100), reg.s as u16 + 0x100);
         mem.read_byte(reg.s as u16 + 0x100)
@@ -55,14 +62,12 @@ mod cpu_helpers {
 
 #[allow(clippy::let_and_return)]
 mod control_instructions {
-    use std::cell::RefMut;
-
-    use super::super::{cpu::cpu_helpers::pop_stack, memory::MemoryMap};
+    use super::super::{bus::Bus, cpu::cpu_helpers::pop_stack};
 
     use super::cpu_helpers::page_cross;
     use super::{CpuRegisters, Operand, Status, cpu_helpers::push_stack};
 
-    pub(super) fn run_bit(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_bit(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let Operand::Address(addr) = operand else {
             panic!("BIT only takes addresses");
         };
@@ -166,11 +171,11 @@ mod control_instructions {
         cycles
     }
 
-    pub(super) fn run_rti(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_rti(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         assert_eq!(operand, Operand::None);
 
         // Pop flags fom register
-        let flags = Status::from_bits(pop_stack(reg, &mem)).unwrap();
+        let flags = Status::from_bits(pop_stack(reg, mem)).unwrap();
         reg.p
             .set(Status::NEGATIVE, flags.contains(Status::NEGATIVE));
         reg.p
@@ -185,8 +190,8 @@ mod control_instructions {
         // println!("Status: {:x}", reg.p.bits());
 
         // Pop the return address
-        let pc = pop_stack(reg, &mem) as u16;
-        let pc = pc | ((pop_stack(reg, &mem) as u16) << 8);
+        let pc = pop_stack(reg, mem) as u16;
+        let pc = pc | ((pop_stack(reg, mem) as u16) << 8);
         reg.pc = pc;
 
         5
@@ -247,7 +252,7 @@ mod control_instructions {
         // No flags
         0
     }
-    pub(super) fn run_cpx(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_cpx(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -262,7 +267,7 @@ mod control_instructions {
 
         cycles
     }
-    pub(super) fn run_cpy(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_cpy(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -327,18 +332,14 @@ mod control_instructions {
 
         0
     }
-    pub(super) fn run_jsr(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_jsr(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let Operand::Address(addr) = operand else {
             panic!("JSR only takes addresses");
         };
         // Push PC to stack
         let return_addr = reg.pc - 1; // Should point to the last read byte
-        push_stack(reg, &mut mem, ((return_addr & 0xFF00) >> 8) as u8);
-        push_stack(reg, &mut mem, (return_addr & 0x00FF) as u8);
+        push_stack(reg, mem, ((return_addr & 0xFF00) >> 8) as u8);
+        push_stack(reg, mem, (return_addr & 0x00FF) as u8);
 
         // Jump to addr
         reg.pc = addr;
@@ -348,7 +349,7 @@ mod control_instructions {
 
         3
     }
-    pub(super) fn run_ldy(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_ldy(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -361,51 +362,43 @@ mod control_instructions {
         reg.p.set(Status::NEGATIVE, val & 0x80 != 0);
         cycles
     }
-    pub(super) fn run_pha(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_pha(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         assert_eq!(operand, Operand::None);
-        push_stack(reg, &mut mem, reg.a);
+        push_stack(reg, mem, reg.a);
         // No flags
         2
     }
-    pub(super) fn run_php(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_php(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         assert_eq!(operand, Operand::None);
         push_stack(
             reg,
-            &mut mem,
+            mem,
             reg.p.bits | Status::BREAK.bits | Status::IGNORED.bits,
         );
         // No flags
         2
     }
-    pub(super) fn run_pla(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_pla(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         assert_eq!(operand, Operand::None);
-        reg.a = pop_stack(reg, &mem);
+        reg.a = pop_stack(reg, mem);
         // flags:
         reg.p.set(Status::ZERO, reg.a == 0);
         reg.p.set(Status::NEGATIVE, reg.a & 0x80 != 0);
 
         3
     }
-    pub(super) fn run_plp(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_plp(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         assert_eq!(operand, Operand::None);
-        let val = pop_stack(reg, &mem);
+        let val = pop_stack(reg, mem);
         reg.p.bits = val;
         // No flags:
 
         3
     }
-    pub(super) fn run_rts(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_rts(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         assert_eq!(operand, Operand::None);
-        let bl = pop_stack(reg, &mem) as u16; //mem.read_byte(reg.s.into()) as u16;
-        let bh = pop_stack(reg, &mem) as u16; // mem.read_byte(reg.s.into()) as u16;
+        let bl = pop_stack(reg, mem) as u16; //mem.read_byte(reg.s.into()) as u16;
+        let bh = pop_stack(reg, mem) as u16; // mem.read_byte(reg.s.into()) as u16;
 
         let return_addr = (bh << 8) | bl;
         // print!("  {:x} & {:x} -> {:x}", bh, bl, return_addr);
@@ -432,11 +425,7 @@ mod control_instructions {
         // No flags
         0
     }
-    pub(super) fn run_sty(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_sty(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let Operand::Address(addr) = operand else {
             panic!("STY only takes addresses");
         };
@@ -465,13 +454,11 @@ mod control_instructions {
 
 #[allow(clippy::let_and_return)]
 mod alu_instructions {
-    use std::cell::RefMut;
-
-    use super::super::memory::MemoryMap;
+    use super::super::bus::Bus;
 
     use super::{CpuRegisters, Operand, Status};
 
-    pub(super) fn run_cmp(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_cmp(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -486,11 +473,7 @@ mod alu_instructions {
 
         cycles
     }
-    pub(super) fn run_sta(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_sta(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let Operand::Address(addr) = operand else {
             panic!("STA only takes addresses");
         };
@@ -498,7 +481,7 @@ mod alu_instructions {
         1 + mem.write_byte(addr, reg.a)
     }
 
-    pub(super) fn run_lda(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_lda(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -513,7 +496,7 @@ mod alu_instructions {
 
         cycles
     }
-    pub(super) fn run_ora(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_ora(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -528,7 +511,7 @@ mod alu_instructions {
         // print!("   ORA result {:x}", reg.a);
         cycles
     }
-    pub(super) fn run_eor(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_eor(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -543,7 +526,7 @@ mod alu_instructions {
         // print!("   EOR result {:x}", reg.a);
         cycles
     }
-    pub(super) fn run_and(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_and(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -560,7 +543,7 @@ mod alu_instructions {
         // print!("   AND result {:x}", reg.a);
         cycles
     }
-    pub(super) fn run_adc(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_adc(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -585,7 +568,7 @@ mod alu_instructions {
 
         cycles
     }
-    pub(super) fn run_sbc(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_sbc(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -613,15 +596,9 @@ mod alu_instructions {
 }
 
 mod rmw_instructions {
-    use std::cell::RefMut;
-
-    use super::super::memory::MemoryMap;
+    use super::super::bus::Bus;
     use super::{CpuRegisters, Operand, Status};
-    pub(super) fn run_asl(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_asl(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 3),
             Operand::Value(_) => panic!("ASL Operates on A or memory"),
@@ -644,11 +621,7 @@ mod rmw_instructions {
 
         cycles
     }
-    pub(super) fn run_rol(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_rol(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 3),
             Operand::Value(_) => panic!("ROL Operates on A or memory"),
@@ -671,11 +644,7 @@ mod rmw_instructions {
 
         cycles
     }
-    pub(super) fn run_ror(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_ror(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 3),
             Operand::Value(_) => panic!("ROR Operates on A or memory"),
@@ -703,11 +672,7 @@ mod rmw_instructions {
 
         cycles
     }
-    pub(super) fn run_dec(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_dec(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let addr = match operand {
             Operand::Address(addr) => addr,
             _ => panic!("Dec Operates on memory"),
@@ -738,11 +703,7 @@ mod rmw_instructions {
 
         0
     }
-    pub(super) fn run_inc(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_inc(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let Operand::Address(addr) = operand else {
             panic!("INC only takes addresses");
         };
@@ -758,7 +719,7 @@ mod rmw_instructions {
 
         3 + hidden_cycles
     }
-    pub(super) fn run_ldx(reg: &mut CpuRegisters, mem: RefMut<MemoryMap>, operand: Operand) -> u32 {
+    pub(super) fn run_ldx(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (val, cycles) = match operand {
             Operand::Address(addr) => (mem.read_byte(addr), 1),
             Operand::Value(val) => (val, 0),
@@ -772,11 +733,7 @@ mod rmw_instructions {
 
         cycles
     }
-    pub(super) fn run_lsr(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_lsr(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let (z, c, cycles) = match operand {
             Operand::Address(addr) => {
                 let val = mem.read_byte(addr);
@@ -800,11 +757,7 @@ mod rmw_instructions {
 
         cycles
     }
-    pub(super) fn run_stx(
-        reg: &mut CpuRegisters,
-        mut mem: RefMut<MemoryMap>,
-        operand: Operand,
-    ) -> u32 {
+    pub(super) fn run_stx(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
         let Operand::Address(addr) = operand else {
             panic!("STX only takes addresses");
         };
@@ -847,6 +800,201 @@ mod rmw_instructions {
     }
 }
 
+mod unofficial_instructions {
+    use super::super::bus::Bus;
+    use super::alu_instructions::{run_adc, run_cmp, run_sbc};
+    use super::{CpuRegisters, Operand, Status};
+
+    fn set_nz(reg: &mut CpuRegisters, val: u8) {
+        reg.p.set(Status::ZERO, val == 0);
+        reg.p.set(Status::NEGATIVE, val & 0x80 != 0);
+    }
+
+    fn read_operand(mem: &mut dyn Bus, operand: Operand, name: &str) -> (u8, u32) {
+        match operand {
+            Operand::Address(addr) => (mem.read_byte(addr), 1),
+            Operand::Value(val) => (val, 0),
+            Operand::None => panic!("{} requires an operand", name),
+        }
+    }
+
+    // Read the value and write the result, returning the result and cycles
+    fn modify(
+        mem: &mut dyn Bus,
+        operand: Operand,
+        name: &str,
+        f: impl FnOnce(u8) -> u8,
+    ) -> (u8, u32) {
+        let Operand::Address(addr) = operand else {
+            panic!("{} only takes addresses", name);
+        };
+        let val = mem.read_byte(addr);
+        let result = f(val);
+        let hidden_cycles = mem.write_byte(addr, result);
+        (result, 3 + hidden_cycles)
+    }
+
+    pub(super) fn run_slo(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let mut carry = false;
+        let (result, cycles) = modify(mem, operand, "SLO", |val| {
+            carry = val & 0x80 != 0;
+            val << 1
+        });
+        reg.p.set(Status::CARRY, carry);
+        reg.a |= result;
+        set_nz(reg, reg.a);
+
+        cycles
+    }
+    pub(super) fn run_rla(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let carry_in = reg.p.contains(Status::CARRY) as u8;
+        let mut carry = false;
+        let (result, cycles) = modify(mem, operand, "RLA", |val| {
+            carry = val & 0x80 != 0;
+            val << 1 | carry_in
+        });
+        reg.p.set(Status::CARRY, carry);
+        reg.a &= result;
+        set_nz(reg, reg.a);
+
+        cycles
+    }
+    pub(super) fn run_sre(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let mut carry = false;
+        let (result, cycles) = modify(mem, operand, "SRE", |val| {
+            carry = val & 0x01 != 0;
+            val >> 1
+        });
+        reg.p.set(Status::CARRY, carry);
+        reg.a ^= result;
+        set_nz(reg, reg.a);
+
+        cycles
+    }
+    pub(super) fn run_rra(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let carry_in = if reg.p.contains(Status::CARRY) {
+            0x80
+        } else {
+            0
+        };
+        let mut carry = false;
+        let (result, cycles) = modify(mem, operand, "RRA", |val| {
+            carry = val & 0x01 != 0;
+            val >> 1 | carry_in
+        });
+        // The carry out of the rotate feeds the add
+        reg.p.set(Status::CARRY, carry);
+        cycles + run_adc(reg, mem, Operand::Value(result))
+    }
+    pub(super) fn run_dcp(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let (result, cycles) = modify(mem, operand, "DCP", |val| val.wrapping_sub(1));
+        cycles + run_cmp(reg, mem, Operand::Value(result))
+    }
+    pub(super) fn run_isb(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let (result, cycles) = modify(mem, operand, "ISB", |val| val.wrapping_add(1));
+        cycles + run_sbc(reg, mem, Operand::Value(result))
+    }
+    pub(super) fn run_lax(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let (val, cycles) = read_operand(mem, operand, "LAX");
+        reg.a = val;
+        reg.x = val;
+        set_nz(reg, val);
+
+        cycles
+    }
+    pub(super) fn run_sax(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let Operand::Address(addr) = operand else {
+            panic!("SAX only takes addresses");
+        };
+        1 + mem.write_byte(addr, reg.a & reg.x)
+    }
+    pub(super) fn run_anc(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let (val, cycles) = read_operand(mem, operand, "ANC");
+        reg.a &= val;
+        set_nz(reg, reg.a);
+        reg.p.set(Status::CARRY, reg.a & 0x80 != 0);
+
+        cycles
+    }
+    pub(super) fn run_alr(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let (val, cycles) = read_operand(mem, operand, "ALR");
+        let and = reg.a & val;
+        reg.a = and >> 1;
+        reg.p.set(Status::CARRY, and & 0x01 != 0);
+        set_nz(reg, reg.a);
+
+        cycles
+    }
+    pub(super) fn run_arr(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let (val, cycles) = read_operand(mem, operand, "ARR");
+        let carry_in = if reg.p.contains(Status::CARRY) {
+            0x80
+        } else {
+            0
+        };
+        reg.a = (reg.a & val) >> 1 | carry_in;
+        set_nz(reg, reg.a);
+        // C and V come out of the adder instead of the shift
+        reg.p.set(Status::CARRY, reg.a & 0x40 != 0);
+        reg.p
+            .set(Status::OVERFLOW, ((reg.a >> 6) ^ (reg.a >> 5)) & 0x01 != 0);
+
+        cycles
+    }
+    pub(super) fn run_sbx(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let (val, cycles) = read_operand(mem, operand, "SBX");
+        let and = reg.a & reg.x;
+        reg.x = and.wrapping_sub(val);
+        reg.p.set(Status::CARRY, and >= val);
+        set_nz(reg, reg.x);
+
+        cycles
+    }
+    // ANE and LXA mix in an unstable "magic" value that depends on the chip,
+    // these are the values the test roms expect
+    pub(super) fn run_ane(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let (val, cycles) = read_operand(mem, operand, "ANE");
+        reg.a = (reg.a | 0xEE) & reg.x & val;
+        set_nz(reg, reg.a);
+
+        cycles
+    }
+    pub(super) fn run_lxa(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let (val, cycles) = read_operand(mem, operand, "LXA");
+        reg.a = (reg.a | 0xFF) & val;
+        reg.x = reg.a;
+        set_nz(reg, reg.a);
+
+        cycles
+    }
+    pub(super) fn run_las(reg: &mut CpuRegisters, mem: &mut dyn Bus, operand: Operand) -> u32 {
+        let (val, cycles) = read_operand(mem, operand, "LAS");
+        let result = val & reg.s;
+        reg.a = result;
+        reg.x = result;
+        reg.s = result;
+        set_nz(reg, result);
+
+        cycles
+    }
+    // SHA/SHX/SHY/TAS store the register ANDed with the high byte of the base
+    // address + 1. When indexing crosses a page that value also replaces the
+    // high byte of the address.
+    pub(super) fn run_sh(mem: &mut dyn Bus, operand: Operand, index: u8, val: u8) -> u32 {
+        let Operand::Address(addr) = operand else {
+            panic!("SHx only takes addresses");
+        };
+        let base = addr.wrapping_sub(index as u16);
+        let result = val & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if base & 0xFF00 != addr & 0xFF00 {
+            (result as u16) << 8 | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        1 + mem.write_byte(addr, result)
+    }
+}
+
 struct LoopDetection {
     last_pc: u16,
     repeats: usize,
@@ -854,9 +1002,10 @@ struct LoopDetection {
 
 pub struct Cpu {
     registers: CpuRegisters,
-    // cycle_count: u64,
-    memory: Rc<RefCell<MemoryMap>>,
+    cycle_count: u64,
     loop_detection: LoopDetection,
+    // For the debugger to break on
+    last_interrupt: Option<InteruptSource>,
 
     // TODO: move?
     last_nmi_level: bool,
@@ -868,14 +1017,15 @@ enum PageCrossCycle {
 }
 
 #[allow(unused)]
-enum InteruptSource {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InteruptSource {
     Irq,
     Brk,
     Nmi,
 }
 
 impl Cpu {
-    pub fn new(mem: Rc<RefCell<MemoryMap>>) -> Self {
+    pub fn new() -> Self {
         Self {
             registers: CpuRegisters {
                 a: 0,
@@ -887,34 +1037,35 @@ impl Cpu {
                 },
                 s: 0xff,
             },
-            // cycle_count: 0,
-            memory: mem,
+            cycle_count: 0,
             loop_detection: LoopDetection {
                 last_pc: 0,
                 repeats: 0,
             },
+            last_interrupt: None,
             last_nmi_level: false,
         }
     }
 
-    fn read_byte_pc(&mut self) -> u8 {
+    fn read_byte_pc(&mut self, bus: &mut dyn Bus) -> u8 {
         // println!("PC at {:x}", self.registers.pc);
-        let ret = self.memory.borrow().read_byte(self.registers.pc);
+        let ret = bus.read_byte(self.registers.pc);
         self.registers.pc += 1;
         ret
     }
 
-    fn read_word_pc(&mut self) -> u16 {
-        let ret = self.memory.borrow().read_word(self.registers.pc);
+    fn read_word_pc(&mut self, bus: &mut dyn Bus) -> u16 {
+        let ret = bus.read_word(self.registers.pc);
         self.registers.pc += 2;
         ret
     }
 
-    fn read_byte(&mut self, addr: u16) -> u8 {
-        self.memory.borrow().read_byte(addr)
+    fn read_byte(&mut self, addr: u16, bus: &mut dyn Bus) -> u8 {
+        bus.read_byte(addr)
     }
 
-    fn enter_interrupt(&mut self, source: InteruptSource) -> u32 {
+    fn enter_interrupt(&mut self, source: InteruptSource, bus: &mut dyn Bus) -> u32 {
+        self.last_interrupt = Some(source);
         // Push PC + 2 to stack
         let return_addr = match source {
             InteruptSource::Brk => self.registers.pc + 2 - 1, // Should point to the next instruction
@@ -922,14 +1073,10 @@ impl Cpu {
         };
         push_stack(
             &mut self.registers,
-            &mut self.memory.borrow_mut(),
+            bus,
             ((return_addr & 0xFF00) >> 8) as u8,
         );
-        push_stack(
-            &mut self.registers,
-            &mut self.memory.borrow_mut(),
-            (return_addr & 0x00FF) as u8,
-        );
+        push_stack(&mut self.registers, bus, (return_addr & 0x00FF) as u8);
 
         // println!("Status: {:x}", reg.p.bits());
         let p = self.registers.p
@@ -938,13 +1085,13 @@ impl Cpu {
                 InteruptSource::Brk => Status::BREAK,
                 _ => Status::empty(),
             };
-        push_stack(&mut self.registers, &mut self.memory.borrow_mut(), p.bits);
+        push_stack(&mut self.registers, bus, p.bits);
 
         // Initiate interrupt
 
         let irq_addr = match source {
-            InteruptSource::Nmi => self.memory.borrow().read_word(0xfffa),
-            _ => self.memory.borrow().read_word(0xfffe),
+            InteruptSource::Nmi => bus.read_word(0xfffa),
+            _ => bus.read_word(0xfffe),
         };
 
         self.registers.p.set(Status::IT_DISABLE, true);
@@ -953,16 +1100,18 @@ impl Cpu {
         6
     }
 
-    pub fn run_instruction(&mut self) -> (u64, bool) {
-        let nmi_level = self.memory.borrow().nmi_requested();
+    pub fn run_instruction(&mut self, bus: &mut dyn Bus) -> (u64, bool) {
+        let nmi_level = bus.nmi_requested();
         let last_nmi_level = self.last_nmi_level;
         self.last_nmi_level = nmi_level;
         if nmi_level && nmi_level != last_nmi_level {
             //println!("NMI!");
-            return (1 + self.enter_interrupt(InteruptSource::Nmi) as u64, false);
+            let cycles = 1 + self.enter_interrupt(InteruptSource::Nmi, bus) as u64;
+            self.cycle_count += cycles;
+            return (cycles, false);
         }
 
-        let instruction = self.read_byte_pc();
+        let instruction = self.read_byte_pc(bus);
         // print!(
         //     "Instr {:x} at pc {:x} -> ",
         //     instruction, self.registers.pc-1
@@ -970,11 +1119,13 @@ impl Cpu {
         let instruction = InstructionType::from(instruction);
         // println!("{:?}",instruction);
         let cycles = match instruction {
-            InstructionType::Control(inst, mode) => self.run_control_instruction(inst, mode),
-            InstructionType::Alu(inst, mode) => self.run_alu_instruction(inst, mode),
-            InstructionType::Rmw(inst, mode) => self.run_rmw_instruction(inst, mode),
-            InstructionType::Nop(mode) => self.run_nop_instruction(mode),
-            // InstructionType::Unofficial => todo!(),
+            InstructionType::Control(inst, mode) => self.run_control_instruction(inst, mode, bus),
+            InstructionType::Alu(inst, mode) => self.run_alu_instruction(inst, mode, bus),
+            InstructionType::Rmw(inst, mode) => self.run_rmw_instruction(inst, mode, bus),
+            InstructionType::Nop(mode) => self.run_nop_instruction(mode, bus),
+            InstructionType::Unofficial(inst, mode) => {
+                self.run_unofficial_instruction(inst, mode, bus)
+            }
         };
         if self.registers.pc == self.loop_detection.last_pc {
             self.loop_detection.repeats += 1;
@@ -988,16 +1139,65 @@ impl Cpu {
         if cycles < 2 {
             cycles = 2;
         }
+        self.cycle_count += cycles as u64;
         (cycles as u64, loop_detected)
     }
 
-    fn parse_operand(&mut self, mode: AddressMode) -> (Operand, u8, PageCrossCycle) {
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.registers.a,
+            x: self.registers.x,
+            y: self.registers.y,
+            pc: self.registers.pc,
+            p: self.registers.p.bits,
+            s: self.registers.s,
+        }
+    }
+
+    pub fn set_registers(&mut self, regs: &Registers) {
+        self.registers.a = regs.a;
+        self.registers.x = regs.x;
+        self.registers.y = regs.y;
+        self.registers.pc = regs.pc;
+        self.registers.p = Status::from_bits_truncate(regs.p);
+        self.registers.s = regs.s;
+    }
+
+    #[allow(unused)]
+    pub fn set_pc(&mut self, pc: u16) {
+        self.registers.pc = pc;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycle_count
+    }
+
+    // Interrupt entered since the last call, if any
+    pub fn take_interrupt(&mut self) -> Option<InteruptSource> {
+        self.last_interrupt.take()
+    }
+
+    // Whether the next run_instruction enters NMI instead of running an
+    // instruction
+    pub fn nmi_pending(&self, bus: &dyn Bus) -> bool {
+        bus.nmi_requested() && !self.last_nmi_level
+    }
+
+    fn parse_operand(
+        &mut self,
+        mode: AddressMode,
+        bus: &mut dyn Bus,
+    ) -> (Operand, u8, PageCrossCycle) {
         match mode {
             AddressMode::Implied => (Operand::None, 0, PageCrossCycle::NA),
             AddressMode::Acc => (Operand::None, 0, PageCrossCycle::NA),
-            AddressMode::Abs => (Operand::Address(self.read_word_pc()), 2, PageCrossCycle::NA),
+            AddressMode::Abs => (
+                Operand::Address(self.read_word_pc(bus)),
+                2,
+                PageCrossCycle::NA,
+            ),
             AddressMode::AbsX => {
-                let base = self.read_word_pc();
+                let base = self.read_word_pc(bus);
                 let addr = base + self.registers.x as u16;
                 let page_cross = if (base & 0xFF00) != (addr & 0xFF00) {
                     PageCrossCycle::PageCross
@@ -1007,7 +1207,7 @@ impl Cpu {
                 (Operand::Address(addr), 2, page_cross)
             }
             AddressMode::AbsY => {
-                let base = self.read_word_pc();
+                let base = self.read_word_pc(bus);
                 let addr = base + self.registers.y as u16;
                 let page_cross = if (base & 0xFF00) != (addr & 0xFF00) {
                     PageCrossCycle::PageCross
@@ -1016,29 +1216,33 @@ impl Cpu {
                 };
                 (Operand::Address(addr), 2, page_cross)
             }
-            AddressMode::Imm => (Operand::Value(self.read_byte_pc()), 1, PageCrossCycle::NA),
+            AddressMode::Imm => (
+                Operand::Value(self.read_byte_pc(bus)),
+                1,
+                PageCrossCycle::NA,
+            ),
             AddressMode::Ind => {
-                let addr_location = self.read_word_pc();
+                let addr_location = self.read_word_pc(bus);
                 // Indir wraps on page boundaries!
-                let lo_byte = self.read_byte(addr_location);
+                let lo_byte = self.read_byte(addr_location, bus);
                 let hi_byte_loc = (addr_location & 0xFF00)
                     | (((addr_location & 0xFF) as u8).wrapping_add(1) as u16);
-                let hi_byte = self.read_byte(hi_byte_loc);
+                let hi_byte = self.read_byte(hi_byte_loc, bus);
 
                 let addr = ((hi_byte as u16) << 8) | (lo_byte as u16);
                 (Operand::Address(addr), 4, PageCrossCycle::NA)
             }
             AddressMode::IndX => {
-                let ll_addr = self.read_byte_pc();
+                let ll_addr = self.read_byte_pc(bus);
                 let addr_location = ll_addr.wrapping_add(self.registers.x);
-                let addr = (self.read_byte(addr_location as u16) as u16)
-                    | ((self.read_byte(addr_location.wrapping_add(1) as u16) as u16) << 8);
+                let addr = (self.read_byte(addr_location as u16, bus) as u16)
+                    | ((self.read_byte(addr_location.wrapping_add(1) as u16, bus) as u16) << 8);
                 (Operand::Address(addr), 4, PageCrossCycle::NA)
             }
             AddressMode::IndY => {
-                let ll_addr = self.read_byte_pc();
-                let addr = self.read_byte(ll_addr as u16) as u16
-                    | ((self.read_byte(ll_addr.wrapping_add(1) as u16) as u16) << 8);
+                let ll_addr = self.read_byte_pc(bus);
+                let addr = self.read_byte(ll_addr as u16, bus) as u16
+                    | ((self.read_byte(ll_addr.wrapping_add(1) as u16, bus) as u16) << 8);
                 let res = addr.wrapping_add(self.registers.y as u16);
                 let page_cross = if (addr & 0xFF00) != (res & 0xFF00) {
                     PageCrossCycle::PageCross
@@ -1049,92 +1253,73 @@ impl Cpu {
             } // TODO: carry
             AddressMode::Rel => {
                 // let orig_pc = self.registers.pc - 1;
-                // let opu8 = self.read_byte_pc();
+                // let opu8 = self.read_byte_pc(bus);
                 // let opi8 = opu8 as i8;
                 // let res = orig_pc.wrapping_add((opi8) as u16);
                 // println!("{:x} + {:x}({}) = {:x}",orig_pc, opu8, opi8, res);
-                let opi8 = self.read_byte_pc() as i8;
+                let opi8 = self.read_byte_pc(bus) as i8;
                 let res = self.registers.pc.wrapping_add((opi8) as u16); // My guess: acts on incremented pc
                 (Operand::Address(res), 1, PageCrossCycle::NA)
             }
             AddressMode::Zpg => (
-                Operand::Address(self.read_byte_pc() as u16),
+                Operand::Address(self.read_byte_pc(bus) as u16),
                 1,
                 PageCrossCycle::NA,
             ), // addr 00BB
             AddressMode::ZpgX => (
-                Operand::Address(self.read_byte_pc().wrapping_add(self.registers.x) as u16),
+                Operand::Address(self.read_byte_pc(bus).wrapping_add(self.registers.x) as u16),
                 2,
                 PageCrossCycle::NA,
             ),
             AddressMode::ZpgY => (
-                Operand::Address(self.read_byte_pc().wrapping_add(self.registers.y) as u16),
+                Operand::Address(self.read_byte_pc(bus).wrapping_add(self.registers.y) as u16),
                 2,
                 PageCrossCycle::NA,
             ),
         }
     }
 
-    fn run_control_instruction(&mut self, inst: ControlInstruction, mode: AddressMode) -> u32 {
+    fn run_control_instruction(
+        &mut self,
+        inst: ControlInstruction,
+        mode: AddressMode,
+        bus: &mut dyn Bus,
+    ) -> u32 {
         use control_instructions::*;
-        let (operand, op_cycles, pg_cross) = self.parse_operand(mode);
+        let (operand, op_cycles, pg_cross) = self.parse_operand(mode, bus);
         let inst_cycles: u32 = match inst {
             ControlInstruction::Bcc => run_bcc(&mut self.registers, operand),
             ControlInstruction::Bcs => run_bcs(&mut self.registers, operand),
             ControlInstruction::Beq => run_beq(&mut self.registers, operand),
-            ControlInstruction::Bit => {
-                run_bit(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
+            ControlInstruction::Bit => run_bit(&mut self.registers, bus, operand),
             ControlInstruction::Bmi => run_bmi(&mut self.registers, operand),
             ControlInstruction::Bne => run_bne(&mut self.registers, operand),
             ControlInstruction::Bpl => run_bpl(&mut self.registers, operand),
-            ControlInstruction::Brk => self.enter_interrupt(InteruptSource::Brk),
+            ControlInstruction::Brk => self.enter_interrupt(InteruptSource::Brk, bus),
             ControlInstruction::Bvc => run_bvc(&mut self.registers, operand),
             ControlInstruction::Bvs => run_bvs(&mut self.registers, operand),
             ControlInstruction::Clc => run_clc(&mut self.registers, operand),
             ControlInstruction::Cld => run_cld(&mut self.registers, operand),
             ControlInstruction::Cli => run_cli(&mut self.registers, operand),
             ControlInstruction::Clv => run_clv(&mut self.registers, operand),
-            ControlInstruction::Cpx => {
-                run_cpx(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
-            ControlInstruction::Cpy => {
-                run_cpy(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
+            ControlInstruction::Cpx => run_cpx(&mut self.registers, bus, operand),
+            ControlInstruction::Cpy => run_cpy(&mut self.registers, bus, operand),
             ControlInstruction::Dey => run_dey(&mut self.registers, operand),
             ControlInstruction::Inx => run_inx(&mut self.registers, operand),
             ControlInstruction::Iny => run_iny(&mut self.registers, operand),
             ControlInstruction::Jmp => run_jmp(&mut self.registers, operand),
-            ControlInstruction::Jsr => {
-                run_jsr(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
-            ControlInstruction::Ldy => {
-                run_ldy(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
-            ControlInstruction::Pha => {
-                run_pha(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
-            ControlInstruction::Php => {
-                run_php(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
-            ControlInstruction::Pla => {
-                run_pla(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
-            ControlInstruction::Plp => {
-                run_plp(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
-            ControlInstruction::Rti => {
-                run_rti(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
-            ControlInstruction::Rts => {
-                run_rts(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
+            ControlInstruction::Jsr => run_jsr(&mut self.registers, bus, operand),
+            ControlInstruction::Ldy => run_ldy(&mut self.registers, bus, operand),
+            ControlInstruction::Pha => run_pha(&mut self.registers, bus, operand),
+            ControlInstruction::Php => run_php(&mut self.registers, bus, operand),
+            ControlInstruction::Pla => run_pla(&mut self.registers, bus, operand),
+            ControlInstruction::Plp => run_plp(&mut self.registers, bus, operand),
+            ControlInstruction::Rti => run_rti(&mut self.registers, bus, operand),
+            ControlInstruction::Rts => run_rts(&mut self.registers, bus, operand),
             ControlInstruction::Sec => run_sec(&mut self.registers, operand),
             ControlInstruction::Sed => run_sed(&mut self.registers, operand),
             ControlInstruction::Sei => run_sei(&mut self.registers, operand),
-            ControlInstruction::Sty => {
-                run_sty(&mut self.registers, self.memory.borrow_mut(), operand)
-            }
+            ControlInstruction::Sty => run_sty(&mut self.registers, bus, operand),
             ControlInstruction::Tay => run_tay(&mut self.registers, operand),
             ControlInstruction::Tya => run_tya(&mut self.registers, operand),
         };
@@ -1147,22 +1332,27 @@ impl Cpu {
             }
     }
 
-    fn run_alu_instruction(&mut self, inst: AluInstruction, mode: AddressMode) -> u32 {
+    fn run_alu_instruction(
+        &mut self,
+        inst: AluInstruction,
+        mode: AddressMode,
+        bus: &mut dyn Bus,
+    ) -> u32 {
         use alu_instructions::*;
-        let (operand, op_cycles, mut pg_cross) = self.parse_operand(mode);
+        let (operand, op_cycles, mut pg_cross) = self.parse_operand(mode, bus);
         let inst_cycles = match inst {
-            AluInstruction::Adc => run_adc(&mut self.registers, self.memory.borrow_mut(), operand),
-            AluInstruction::And => run_and(&mut self.registers, self.memory.borrow_mut(), operand),
-            AluInstruction::Cmp => run_cmp(&mut self.registers, self.memory.borrow_mut(), operand),
-            AluInstruction::Eor => run_eor(&mut self.registers, self.memory.borrow_mut(), operand),
-            AluInstruction::Lda => run_lda(&mut self.registers, self.memory.borrow_mut(), operand),
-            AluInstruction::Ora => run_ora(&mut self.registers, self.memory.borrow_mut(), operand),
-            AluInstruction::Sbc => run_sbc(&mut self.registers, self.memory.borrow_mut(), operand),
+            AluInstruction::Adc => run_adc(&mut self.registers, bus, operand),
+            AluInstruction::And => run_and(&mut self.registers, bus, operand),
+            AluInstruction::Cmp => run_cmp(&mut self.registers, bus, operand),
+            AluInstruction::Eor => run_eor(&mut self.registers, bus, operand),
+            AluInstruction::Lda => run_lda(&mut self.registers, bus, operand),
+            AluInstruction::Ora => run_ora(&mut self.registers, bus, operand),
+            AluInstruction::Sbc => run_sbc(&mut self.registers, bus, operand),
             AluInstruction::Sta => {
                 if matches!(pg_cross, PageCrossCycle::NoPageCross) {
                     pg_cross = PageCrossCycle::PageCross;
                 }
-                run_sta(&mut self.registers, self.memory.borrow_mut(), operand)
+                run_sta(&mut self.registers, bus, operand)
             }
         };
         op_cycles as u32
@@ -1174,49 +1364,54 @@ impl Cpu {
             }
     }
 
-    fn run_rmw_instruction(&mut self, inst: RmwInstruction, mode: AddressMode) -> u32 {
+    fn run_rmw_instruction(
+        &mut self,
+        inst: RmwInstruction,
+        mode: AddressMode,
+        bus: &mut dyn Bus,
+    ) -> u32 {
         use rmw_instructions::*;
-        let (operand, op_cycles, mut pg_cross) = self.parse_operand(mode);
+        let (operand, op_cycles, mut pg_cross) = self.parse_operand(mode, bus);
         let inst_cycles = match inst {
             RmwInstruction::Asl => {
                 if matches!(pg_cross, PageCrossCycle::NoPageCross) {
                     pg_cross = PageCrossCycle::PageCross;
                 }
-                run_asl(&mut self.registers, self.memory.borrow_mut(), operand)
+                run_asl(&mut self.registers, bus, operand)
             }
             RmwInstruction::Dec => {
                 if matches!(pg_cross, PageCrossCycle::NoPageCross) {
                     pg_cross = PageCrossCycle::PageCross;
                 }
-                run_dec(&mut self.registers, self.memory.borrow_mut(), operand)
+                run_dec(&mut self.registers, bus, operand)
             }
             RmwInstruction::Dex => run_dex(&mut self.registers, operand),
             RmwInstruction::Inc => {
                 if matches!(pg_cross, PageCrossCycle::NoPageCross) {
                     pg_cross = PageCrossCycle::PageCross;
                 }
-                run_inc(&mut self.registers, self.memory.borrow_mut(), operand)
+                run_inc(&mut self.registers, bus, operand)
             }
-            RmwInstruction::Ldx => run_ldx(&mut self.registers, self.memory.borrow_mut(), operand),
+            RmwInstruction::Ldx => run_ldx(&mut self.registers, bus, operand),
             RmwInstruction::Lsr => {
                 if matches!(pg_cross, PageCrossCycle::NoPageCross) {
                     pg_cross = PageCrossCycle::PageCross;
                 }
-                run_lsr(&mut self.registers, self.memory.borrow_mut(), operand)
+                run_lsr(&mut self.registers, bus, operand)
             }
             RmwInstruction::Rol => {
                 if matches!(pg_cross, PageCrossCycle::NoPageCross) {
                     pg_cross = PageCrossCycle::PageCross;
                 }
-                run_rol(&mut self.registers, self.memory.borrow_mut(), operand)
+                run_rol(&mut self.registers, bus, operand)
             }
             RmwInstruction::Ror => {
                 if matches!(pg_cross, PageCrossCycle::NoPageCross) {
                     pg_cross = PageCrossCycle::PageCross;
                 }
-                run_ror(&mut self.registers, self.memory.borrow_mut(), operand)
+                run_ror(&mut self.registers, bus, operand)
             }
-            RmwInstruction::Stx => run_stx(&mut self.registers, self.memory.borrow_mut(), operand),
+            RmwInstruction::Stx => run_stx(&mut self.registers, bus, operand),
             RmwInstruction::Tax => run_tax(&mut self.registers, operand),
             RmwInstruction::Tsx => run_tsx(&mut self.registers, operand),
             RmwInstruction::Txa => run_txa(&mut self.registers, operand),
@@ -1230,26 +1425,128 @@ impl Cpu {
                 0
             }
     }
-    fn run_nop_instruction(&mut self, mode: AddressMode) -> u32 {
-        let (_, cycles, _) = self.parse_operand(mode);
+    fn run_unofficial_instruction(
+        &mut self,
+        inst: UnofficialInstruction,
+        mode: AddressMode,
+        bus: &mut dyn Bus,
+    ) -> u32 {
+        use unofficial_instructions::*;
+        if matches!(inst, UnofficialInstruction::Jam) {
+            // The cpu locks up until reset, keep fetching the same opcode
+            self.registers.pc = self.registers.pc.wrapping_sub(1);
+            return 0;
+        }
+        let (operand, op_cycles, mut pg_cross) = self.parse_operand(mode, bus);
+        // Writes and read-modify-writes always take the page cross cycle
+        let always_page_cross = !matches!(
+            inst,
+            UnofficialInstruction::Lax
+                | UnofficialInstruction::Las
+                | UnofficialInstruction::Sax
+                | UnofficialInstruction::Anc
+                | UnofficialInstruction::Alr
+                | UnofficialInstruction::Arr
+                | UnofficialInstruction::Ane
+                | UnofficialInstruction::Lxa
+                | UnofficialInstruction::Sbx
+                | UnofficialInstruction::Sbc
+        );
+        if always_page_cross && matches!(pg_cross, PageCrossCycle::NoPageCross) {
+            pg_cross = PageCrossCycle::PageCross;
+        }
+        let reg = &mut self.registers;
+        let inst_cycles = match inst {
+            UnofficialInstruction::Slo => run_slo(reg, bus, operand),
+            UnofficialInstruction::Rla => run_rla(reg, bus, operand),
+            UnofficialInstruction::Sre => run_sre(reg, bus, operand),
+            UnofficialInstruction::Rra => run_rra(reg, bus, operand),
+            UnofficialInstruction::Dcp => run_dcp(reg, bus, operand),
+            UnofficialInstruction::Isb => run_isb(reg, bus, operand),
+            UnofficialInstruction::Lax => run_lax(reg, bus, operand),
+            UnofficialInstruction::Sax => run_sax(reg, bus, operand),
+            UnofficialInstruction::Sbc => alu_instructions::run_sbc(reg, bus, operand),
+            UnofficialInstruction::Anc => run_anc(reg, bus, operand),
+            UnofficialInstruction::Alr => run_alr(reg, bus, operand),
+            UnofficialInstruction::Arr => run_arr(reg, bus, operand),
+            UnofficialInstruction::Ane => run_ane(reg, bus, operand),
+            UnofficialInstruction::Lxa => run_lxa(reg, bus, operand),
+            UnofficialInstruction::Sbx => run_sbx(reg, bus, operand),
+            UnofficialInstruction::Las => run_las(reg, bus, operand),
+            UnofficialInstruction::Sha => run_sh(bus, operand, reg.y, reg.a & reg.x),
+            UnofficialInstruction::Shx => run_sh(bus, operand, reg.y, reg.x),
+            UnofficialInstruction::Shy => run_sh(bus, operand, reg.x, reg.y),
+            UnofficialInstruction::Tas => {
+                reg.s = reg.a & reg.x;
+                run_sh(bus, operand, reg.y, reg.s)
+            }
+            UnofficialInstruction::Jam => unreachable!(),
+        };
+        op_cycles as u32
+            + inst_cycles
+            + if matches!(pg_cross, PageCrossCycle::PageCross) {
+                1
+            } else {
+                0
+            }
+    }
+    fn run_nop_instruction(&mut self, mode: AddressMode, bus: &mut dyn Bus) -> u32 {
+        let (_, cycles, _) = self.parse_operand(mode, bus);
         cycles as u32 + 1
     }
 
+    // The reset line runs the interrupt sequence with the stack writes
+    // suppressed, so S still drops by 3
+    pub fn reset(&mut self, bus: &mut dyn Bus) {
+        self.registers.s = self.registers.s.wrapping_sub(3);
+        self.registers.p.insert(Status::IT_DISABLE);
+        self.registers.pc = bus.read_word(0xFFFC);
+        self.cycle_count += 7;
+    }
+
     // todo move this
-    pub fn initialize(&mut self) {
-        self.registers.pc = self.memory.borrow().read_word(0xFFFC); // Reset
+    pub fn initialize(&mut self, bus: &mut dyn Bus) {
+        self.registers.pc = bus.read_word(0xFFFC); // Reset
+        self.registers.s = 0xFD;
+        self.registers.p.insert(Status::IT_DISABLE);
+        self.cycle_count = 7; // The reset sequence takes 7 cycles
         // TODO: need better way to fake ppu!
-        // self.memory.borrow_mut().write_byte(0x2002, 0x80);// Fake malfunctioning PPUSTATUS register
-        println!(
-            "Pc now at {:x}-> {}",
-            self.registers.pc,
-            self.memory.borrow().read_byte(self.registers.pc)
-        );
+        // bus.write_byte(0x2002, 0x80);// Fake malfunctioning PPUSTATUS register
+    }
+}
+
+impl SaveState for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        let regs = self.registers();
+        w.u8(regs.a);
+        w.u8(regs.x);
+        w.u8(regs.y);
+        w.u16(regs.pc);
+        w.u8(regs.p);
+        w.u8(regs.s);
+        w.u64(self.cycle_count);
+        w.bool(self.last_nmi_level);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let regs = Registers {
+            a: r.u8()?,
+            x: r.u8()?,
+            y: r.u8()?,
+            pc: r.u16()?,
+            p: r.u8()?,
+            s: r.u8()?,
+        };
+        self.set_registers(&regs);
+        self.cycle_count = r.u64()?;
+        self.last_nmi_level = r.bool()?;
+        self.last_interrupt = None;
+        Ok(())
     }
 }
 
 #[derive(Debug)]
-enum ControlInstruction {
+pub(super) enum ControlInstruction {
     Bcc,
     Bcs,
     Beq,
@@ -1287,7 +1584,7 @@ enum ControlInstruction {
 }
 
 #[derive(Debug)]
-enum AluInstruction {
+pub(super) enum AluInstruction {
     Ora,
     And,
     Eor,
@@ -1299,7 +1596,7 @@ enum AluInstruction {
 }
 
 #[derive(Debug)]
-enum RmwInstruction {
+pub(super) enum RmwInstruction {
     Asl,
     Dec,
     Dex,
@@ -1316,7 +1613,32 @@ enum RmwInstruction {
 }
 
 #[derive(Debug)]
-enum AddressMode {
+pub(super) enum UnofficialInstruction {
+    Slo,
+    Rla,
+    Sre,
+    Rra,
+    Sax,
+    Lax,
+    Dcp,
+    Isb,
+    Sbc,
+    Anc,
+    Alr,
+    Arr,
+    Ane,
+    Lxa,
+    Sbx,
+    Sha,
+    Tas,
+    Shy,
+    Shx,
+    Las,
+    Jam,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressMode {
     Implied,
     Acc,
     Abs,
@@ -1340,11 +1662,12 @@ enum Operand {
 }
 
 #[derive(Debug)]
-enum InstructionType {
+pub(super) enum InstructionType {
     Control(ControlInstruction, AddressMode),
     Alu(AluInstruction, AddressMode),
     Rmw(RmwInstruction, AddressMode),
-    Nop(AddressMode), // Unofficial,
+    Nop(AddressMode),
+    Unofficial(UnofficialInstruction, AddressMode),
 }
 
 impl From<u8> for InstructionType {
@@ -1523,16 +1846,123 @@ impl From<u8> for InstructionType {
             0xFD => InstructionType::Alu(AluInstruction::Sbc, AddressMode::AbsX),
             0xFE => InstructionType::Rmw(RmwInstruction::Inc, AddressMode::AbsX),
 
-            _ => todo!("Instruction {} not implemented!", instruction),
+            // Unofficial
+            0x02 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0x03 => InstructionType::Unofficial(UnofficialInstruction::Slo, AddressMode::IndX),
+            0x04 => InstructionType::Nop(AddressMode::Zpg),
+            0x07 => InstructionType::Unofficial(UnofficialInstruction::Slo, AddressMode::Zpg),
+            0x0B => InstructionType::Unofficial(UnofficialInstruction::Anc, AddressMode::Imm),
+            0x0C => InstructionType::Nop(AddressMode::Abs),
+            0x0F => InstructionType::Unofficial(UnofficialInstruction::Slo, AddressMode::Abs),
+            0x12 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0x13 => InstructionType::Unofficial(UnofficialInstruction::Slo, AddressMode::IndY),
+            0x14 => InstructionType::Nop(AddressMode::ZpgX),
+            0x17 => InstructionType::Unofficial(UnofficialInstruction::Slo, AddressMode::ZpgX),
+            0x1B => InstructionType::Unofficial(UnofficialInstruction::Slo, AddressMode::AbsY),
+            0x1C => InstructionType::Nop(AddressMode::AbsX),
+            0x1F => InstructionType::Unofficial(UnofficialInstruction::Slo, AddressMode::AbsX),
+            0x22 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0x23 => InstructionType::Unofficial(UnofficialInstruction::Rla, AddressMode::IndX),
+            0x27 => InstructionType::Unofficial(UnofficialInstruction::Rla, AddressMode::Zpg),
+            0x2B => InstructionType::Unofficial(UnofficialInstruction::Anc, AddressMode::Imm),
+            0x2F => InstructionType::Unofficial(UnofficialInstruction::Rla, AddressMode::Abs),
+            0x32 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0x33 => InstructionType::Unofficial(UnofficialInstruction::Rla, AddressMode::IndY),
+            0x34 => InstructionType::Nop(AddressMode::ZpgX),
+            0x37 => InstructionType::Unofficial(UnofficialInstruction::Rla, AddressMode::ZpgX),
+            0x3B => InstructionType::Unofficial(UnofficialInstruction::Rla, AddressMode::AbsY),
+            0x3C => InstructionType::Nop(AddressMode::AbsX),
+            0x3F => InstructionType::Unofficial(UnofficialInstruction::Rla, AddressMode::AbsX),
+            0x42 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0x43 => InstructionType::Unofficial(UnofficialInstruction::Sre, AddressMode::IndX),
+            0x44 => InstructionType::Nop(AddressMode::Zpg),
+            0x47 => InstructionType::Unofficial(UnofficialInstruction::Sre, AddressMode::Zpg),
+            0x4B => InstructionType::Unofficial(UnofficialInstruction::Alr, AddressMode::Imm),
+            0x4F => InstructionType::Unofficial(UnofficialInstruction::Sre, AddressMode::Abs),
+            0x52 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0x53 => InstructionType::Unofficial(UnofficialInstruction::Sre, AddressMode::IndY),
+            0x54 => InstructionType::Nop(AddressMode::ZpgX),
+            0x57 => InstructionType::Unofficial(UnofficialInstruction::Sre, AddressMode::ZpgX),
+            0x5B => InstructionType::Unofficial(UnofficialInstruction::Sre, AddressMode::AbsY),
+            0x5C => InstructionType::Nop(AddressMode::AbsX),
+            0x5F => InstructionType::Unofficial(UnofficialInstruction::Sre, AddressMode::AbsX),
+            0x62 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0x63 => InstructionType::Unofficial(UnofficialInstruction::Rra, AddressMode::IndX),
+            0x64 => InstructionType::Nop(AddressMode::Zpg),
+            0x67 => InstructionType::Unofficial(UnofficialInstruction::Rra, AddressMode::Zpg),
+            0x6B => InstructionType::Unofficial(UnofficialInstruction::Arr, AddressMode::Imm),
+            0x6F => InstructionType::Unofficial(UnofficialInstruction::Rra, AddressMode::Abs),
+            0x72 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0x73 => InstructionType::Unofficial(UnofficialInstruction::Rra, AddressMode::IndY),
+            0x74 => InstructionType::Nop(AddressMode::ZpgX),
+            0x77 => InstructionType::Unofficial(UnofficialInstruction::Rra, AddressMode::ZpgX),
+            0x7B => InstructionType::Unofficial(UnofficialInstruction::Rra, AddressMode::AbsY),
+            0x7C => InstructionType::Nop(AddressMode::AbsX),
+            0x7F => InstructionType::Unofficial(UnofficialInstruction::Rra, AddressMode::AbsX),
+            0x80 => InstructionType::Nop(AddressMode::Imm),
+            0x82 => InstructionType::Nop(AddressMode::Imm),
+            0x83 => InstructionType::Unofficial(UnofficialInstruction::Sax, AddressMode::IndX),
+            0x87 => InstructionType::Unofficial(UnofficialInstruction::Sax, AddressMode::Zpg),
+            0x89 => InstructionType::Nop(AddressMode::Imm),
+            0x8B => InstructionType::Unofficial(UnofficialInstruction::Ane, AddressMode::Imm),
+            0x8F => InstructionType::Unofficial(UnofficialInstruction::Sax, AddressMode::Abs),
+            0x92 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0x93 => InstructionType::Unofficial(UnofficialInstruction::Sha, AddressMode::IndY),
+            0x97 => InstructionType::Unofficial(UnofficialInstruction::Sax, AddressMode::ZpgY),
+            0x9B => InstructionType::Unofficial(UnofficialInstruction::Tas, AddressMode::AbsY),
+            0x9C => InstructionType::Unofficial(UnofficialInstruction::Shy, AddressMode::AbsX),
+            0x9E => InstructionType::Unofficial(UnofficialInstruction::Shx, AddressMode::AbsY),
+            0x9F => InstructionType::Unofficial(UnofficialInstruction::Sha, AddressMode::AbsY),
+            0xA3 => InstructionType::Unofficial(UnofficialInstruction::Lax, AddressMode::IndX),
+            0xA7 => InstructionType::Unofficial(UnofficialInstruction::Lax, AddressMode::Zpg),
+            0xAB => InstructionType::Unofficial(UnofficialInstruction::Lxa, AddressMode::Imm),
+            0xAF => InstructionType::Unofficial(UnofficialInstruction::Lax, AddressMode::Abs),
+            0xB2 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0xB3 => InstructionType::Unofficial(UnofficialInstruction::Lax, AddressMode::IndY),
+            0xB7 => InstructionType::Unofficial(UnofficialInstruction::Lax, AddressMode::ZpgY),
+            0xBB => InstructionType::Unofficial(UnofficialInstruction::Las, AddressMode::AbsY),
+            0xBF => InstructionType::Unofficial(UnofficialInstruction::Lax, AddressMode::AbsY),
+            0xC2 => InstructionType::Nop(AddressMode::Imm),
+            0xC3 => InstructionType::Unofficial(UnofficialInstruction::Dcp, AddressMode::IndX),
+            0xC7 => InstructionType::Unofficial(UnofficialInstruction::Dcp, AddressMode::Zpg),
+            0xCB => InstructionType::Unofficial(UnofficialInstruction::Sbx, AddressMode::Imm),
+            0xCF => InstructionType::Unofficial(UnofficialInstruction::Dcp, AddressMode::Abs),
+            0xD2 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0xD3 => InstructionType::Unofficial(UnofficialInstruction::Dcp, AddressMode::IndY),
+            0xD4 => InstructionType::Nop(AddressMode::ZpgX),
+            0xD7 => InstructionType::Unofficial(UnofficialInstruction::Dcp, AddressMode::ZpgX),
+            0xDB => InstructionType::Unofficial(UnofficialInstruction::Dcp, AddressMode::AbsY),
+            0xDC => InstructionType::Nop(AddressMode::AbsX),
+            0xDF => InstructionType::Unofficial(UnofficialInstruction::Dcp, AddressMode::AbsX),
+            0xE2 => InstructionType::Nop(AddressMode::Imm),
+            0xE3 => InstructionType::Unofficial(UnofficialInstruction::Isb, AddressMode::IndX),
+            0xE7 => InstructionType::Unofficial(UnofficialInstruction::Isb, AddressMode::Zpg),
+            0xEB => InstructionType::Unofficial(UnofficialInstruction::Sbc, AddressMode::Imm),
+            0xEF => InstructionType::Unofficial(UnofficialInstruction::Isb, AddressMode::Abs),
+            0xF2 => InstructionType::Unofficial(UnofficialInstruction::Jam, AddressMode::Implied),
+            0xF3 => InstructionType::Unofficial(UnofficialInstruction::Isb, AddressMode::IndY),
+            0xF4 => InstructionType::Nop(AddressMode::ZpgX),
+            0xF7 => InstructionType::Unofficial(UnofficialInstruction::Isb, AddressMode::ZpgX),
+            0xFB => InstructionType::Unofficial(UnofficialInstruction::Isb, AddressMode::AbsY),
+            0xFC => InstructionType::Nop(AddressMode::AbsX),
+            0xFF => InstructionType::Unofficial(UnofficialInstruction::Isb, AddressMode::AbsX),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::bus::FlatRam;
     use super::super::cpu::Operand;
 
-    use super::{CpuRegisters, Status, control_instructions::run_sei};
+    use super::{Cpu, CpuRegisters, Status, control_instructions::run_sei};
+
+    fn run_program(program: &[u8]) -> (Cpu, FlatRam) {
+        let mut ram = FlatRam::with_program(0x8000, program);
+        let mut cpu = Cpu::new();
+        cpu.initialize(&mut ram);
+        (cpu, ram)
+    }
 
     #[test]
     fn test_sei() {
@@ -1550,4 +1980,76 @@ mod tests {
         assert!(reg.p.contains(Status::CARRY));
         assert!(!reg.p.contains(Status::ZERO));
     }
+
+    #[test]
+    fn test_unofficial_opcodes() {
+        let (mut cpu, mut ram) = run_program(&[
+            0xA9, 0xF0, // LDA #$F0
+            0xA2, 0x3C, // LDX #$3C
+            0x87, 0x10, // SAX $10
+            0xA7, 0x10, // LAX $10
+            0xC7, 0x11, // DCP $11
+            0xE7, 0x12, // ISB $12
+            0x07, 0x13, // SLO $13
+            0x1F, 0xF0, 0x02, // SLO $02F0,X
+            0xCB, 0x10, // SBX #$10
+            0x02, // JAM
+        ]);
+        ram.ram[0x11] = 0x31;
+        ram.ram[0x12] = 0x0F;
+        ram.ram[0x13] = 0x81;
+        ram.ram[0x320] = 0x40;
+
+        let mut step = |ram: &mut FlatRam| cpu.run_instruction(ram).0;
+        step(&mut ram);
+        step(&mut ram);
+        assert_eq!(step(&mut ram), 3);
+        assert_eq!(ram.ram[0x10], 0x30);
+        assert_eq!(step(&mut ram), 3);
+        assert_eq!(step(&mut ram), 5);
+        assert_eq!(ram.ram[0x11], 0x30);
+        assert_eq!(step(&mut ram), 5);
+        assert_eq!(ram.ram[0x12], 0x10);
+        assert_eq!(step(&mut ram), 5);
+        assert_eq!(ram.ram[0x13], 0x02);
+        // Read-modify-write always takes the page cross cycle
+        assert_eq!(step(&mut ram), 7);
+        assert_eq!(ram.ram[0x320], 0x80);
+        let regs = cpu.registers();
+        assert_eq!((regs.a, regs.x), (0xA2, 0x30));
+        assert_eq!(regs.p & Status::CARRY.bits, 0);
+        assert_eq!(regs.p & Status::NEGATIVE.bits, Status::NEGATIVE.bits);
+
+        cpu.run_instruction(&mut ram);
+        let regs = cpu.registers();
+        assert_eq!(regs.x, 0x10);
+        assert_eq!(regs.p & Status::CARRY.bits, Status::CARRY.bits);
+
+        // JAM locks up on its own opcode
+        let pc = regs.pc;
+        for _ in 0..4 {
+            cpu.run_instruction(&mut ram);
+        }
+        assert_eq!(cpu.registers().pc, pc);
+    }
+
+    #[test]
+    fn test_nmi_edge() {
+        let (mut cpu, mut ram) = run_program(&[
+            0xE8, // INX
+            0x4C, 0x00, 0x80, // JMP $8000
+        ]);
+        ram.ram[0xFFFA] = 0x00;
+        ram.ram[0xFFFB] = 0x90;
+        ram.ram[0x9000] = 0xE8; // INX
+
+        ram.nmi = true;
+        assert!(cpu.nmi_pending(&ram));
+        cpu.run_instruction(&mut ram);
+        assert_eq!(cpu.registers().pc, 0x9000);
+        // The line staying high doesn't retrigger
+        cpu.run_instruction(&mut ram);
+        assert_eq!(cpu.registers().pc, 0x9001);
+        assert!(!cpu.nmi_pending(&ram));
+    }
 }
@@ -0,0 +1,231 @@
+// Family BASIC data recorder. The tape is kept as a 1-bit signal sampled at
+// TAPE_RATE: recording samples bit 2 of $4016 writes and playback feeds D1 of
+// $4016 reads. Tapes are stored either as 8-bit mono WAV or as a raw
+// bitstream, TAPE_RATE samples a second packed LSB first.
+
+pub const TAPE_RATE: u32 = 32000;
+// NTSC PPU dots per second
+const DOT_RATE: u64 = 5_369_318;
+
+const WAV_HIGH: u8 = 0xF0;
+const WAV_LOW: u8 = 0x10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TapeMode {
+    #[default]
+    Stopped,
+    Playing,
+    Recording,
+}
+
+#[derive(Default)]
+pub struct DataRecorder {
+    samples: Vec<bool>,
+    mode: TapeMode,
+    // Dot count when play or record was pressed, taken on the next access
+    start: Option<u64>,
+    now: u64,
+    // Level being recorded
+    level: bool,
+}
+
+impl DataRecorder {
+    pub fn mode(&self) -> TapeMode {
+        self.mode
+    }
+
+    // Length of the tape in seconds
+    pub fn duration(&self) -> f64 {
+        self.samples.len() as f64 / TAPE_RATE as f64
+    }
+
+    pub fn play(&mut self) {
+        self.mode = TapeMode::Playing;
+        self.start = None;
+    }
+
+    // Starts a fresh tape
+    pub fn record(&mut self) {
+        self.samples.clear();
+        self.mode = TapeMode::Recording;
+        self.start = None;
+    }
+
+    pub fn stop(&mut self) {
+        if self.mode == TapeMode::Recording {
+            self.fill();
+        }
+        self.mode = TapeMode::Stopped;
+    }
+
+    pub(super) fn clock(&mut self, dots: u64) {
+        self.now = dots;
+        // The clock goes back on power cycles and state loads
+        if self.mode != TapeMode::Stopped && self.start.is_none_or(|start| dots < start) {
+            self.start = Some(dots);
+        }
+        if self.mode == TapeMode::Recording {
+            self.fill();
+        }
+    }
+
+    pub(super) fn write(&mut self, level: bool) {
+        self.level = level;
+    }
+
+    pub(super) fn read(&self) -> bool {
+        match self.mode {
+            TapeMode::Playing => self.samples.get(self.position()).copied().unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    // Sample under the head
+    fn position(&self) -> usize {
+        let elapsed = self.now - self.start.unwrap_or(self.now);
+        (elapsed * TAPE_RATE as u64 / DOT_RATE) as usize
+    }
+
+    // Holds the current level up to the head
+    fn fill(&mut self) {
+        let position = self.position();
+        if self.samples.len() < position {
+            self.samples.resize(position, self.level);
+        }
+    }
+
+    pub fn to_raw(&self) -> Vec<u8> {
+        self.samples
+            .chunks(8)
+            .map(|bits| {
+                bits.iter()
+                    .enumerate()
+                    .fold(0, |byte, (i, &bit)| byte | (bit as u8) << i)
+            })
+            .collect()
+    }
+
+    pub fn load_raw(&mut self, bytes: &[u8]) {
+        self.samples = bytes
+            .iter()
+            .flat_map(|byte| (0..8).map(move |i| byte >> i & 1 != 0))
+            .collect();
+        self.mode = TapeMode::Stopped;
+    }
+
+    pub fn to_wav(&self) -> Vec<u8> {
+        let data_len = self.samples.len() as u32;
+        let mut wav = Vec::with_capacity(44 + self.samples.len());
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // Mono
+        wav.extend_from_slice(&TAPE_RATE.to_le_bytes());
+        wav.extend_from_slice(&TAPE_RATE.to_le_bytes()); // Bytes per second
+        wav.extend_from_slice(&1u16.to_le_bytes()); // Bytes per frame
+        wav.extend_from_slice(&8u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        wav.extend(
+            self.samples
+                .iter()
+                .map(|&bit| if bit { WAV_HIGH } else { WAV_LOW }),
+        );
+        wav
+    }
+
+    // 8 or 16-bit PCM at any rate. Only the first channel is used, anything
+    // above the midpoint is a 1
+    pub fn load_wav(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(String::from("Not a WAV file"));
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let mut format = None;
+        let mut data = None;
+        let mut offset = 12;
+        while offset + 8 <= bytes.len() {
+            let len = u32_at(offset + 4) as usize;
+            let body = offset + 8;
+            let end = (body + len).min(bytes.len());
+            match &bytes[offset..offset + 4] {
+                b"fmt " if len >= 16 && end - body >= 16 => {
+                    format = Some((
+                        u16_at(body),
+                        u16_at(body + 2),
+                        u32_at(body + 4),
+                        u16_at(body + 14),
+                    ))
+                }
+                b"data" => data = Some(&bytes[body..end]),
+                _ => (),
+            }
+            // Chunks are padded to an even length
+            offset = body + len + (len & 1);
+        }
+        let (Some((tag, channels, rate, bits)), Some(data)) = (format, data) else {
+            return Err(String::from("WAV file is missing its format or data"));
+        };
+        if tag != 1 || channels == 0 || rate == 0 || (bits != 8 && bits != 16) {
+            return Err(format!(
+                "Only 8 and 16-bit PCM WAV files are supported, not format {} with {} bits",
+                tag, bits
+            ));
+        }
+        let frame_len = channels as usize * bits as usize / 8;
+        let frames = data.len() / frame_len;
+        let level = |frame: usize| {
+            let i = frame * frame_len;
+            match bits {
+                8 => data[i] > 0x80,
+                _ => i16::from_le_bytes([data[i], data[i + 1]]) > 0,
+            }
+        };
+        let len = frames as u64 * TAPE_RATE as u64 / rate as u64;
+        self.samples = (0..len)
+            .map(|i| level((i * rate as u64 / TAPE_RATE as u64) as usize))
+            .collect();
+        self.mode = TapeMode::Stopped;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DOT_RATE, DataRecorder, TAPE_RATE, TapeMode};
+
+    #[test]
+    fn test_data_recorder() {
+        let mut tape = DataRecorder::default();
+        tape.record();
+        // A square wave, 100 samples high then 100 low
+        let dots_per_sample = DOT_RATE / TAPE_RATE as u64 + 1;
+        for sample in 0..1000 {
+            tape.clock(1000 + sample * dots_per_sample);
+            tape.write(sample / 100 % 2 == 0);
+        }
+        tape.stop();
+        assert_eq!(tape.mode(), TapeMode::Stopped);
+        let recorded = tape.samples.clone();
+        assert!(recorded.len() >= 990);
+        assert!(recorded[5] && !recorded[110] && recorded[210]);
+
+        let mut copy = DataRecorder::default();
+        copy.load_wav(&tape.to_wav()).unwrap();
+        assert_eq!(copy.samples, recorded);
+        copy.load_raw(&tape.to_raw());
+        assert_eq!(&copy.samples[..recorded.len()], &recorded[..]);
+
+        copy.play();
+        copy.clock(5000);
+        assert!(copy.read());
+        copy.clock(5000 + 150 * dots_per_sample);
+        assert!(!copy.read());
+
+        assert!(copy.load_wav(b"RIFF\0\0\0\0WAVE").is_err());
+    }
+}
@@ -0,0 +1,414 @@
+// Debugger support: breakpoints, watchpoints and stepping on top of Nes.
+//
+// CPU watchpoints are checked by MemoryMap on every read_byte/write_byte, PPU
+// watchpoints on the VRAM address of $2007 accesses. A hit is latched and
+// picked up after the instruction that caused it finishes.
+
+use std::cell::Cell;
+use std::collections::BTreeSet;
+
+use super::Nes;
+use super::cpu::InteruptSource;
+
+// Safety net for stepping commands that may never finish
+const STEP_LIMIT: usize = 1_000_000;
+
+const OPCODE_JSR: u8 = 0x20;
+const OPCODE_RTI: u8 = 0x40;
+const OPCODE_RTS: u8 = 0x60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn matches(&self, access: Access) -> bool {
+        match self {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16, // Inclusive
+    pub kind: WatchKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchBus {
+    Cpu,
+    Ppu,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub bus: WatchBus,
+    pub address: u16,
+    pub access: Access,
+    pub value: u8,
+}
+
+#[derive(Default)]
+pub struct Watchpoints {
+    cpu: Vec<Watchpoint>,
+    ppu: Vec<Watchpoint>,
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watchpoints {
+    fn check(&self, bus: WatchBus, address: u16, access: Access, value: u8) {
+        let list = match bus {
+            WatchBus::Cpu => &self.cpu,
+            WatchBus::Ppu => &self.ppu,
+        };
+        let hit = list
+            .iter()
+            .any(|w| (w.start..=w.end).contains(&address) && w.kind.matches(access));
+        // Keep the first hit of an instruction
+        if hit && self.hit.get().is_none() {
+            self.hit.set(Some(WatchHit {
+                bus,
+                address,
+                access,
+                value,
+            }));
+        }
+    }
+
+    pub(super) fn check_cpu(&self, address: u16, access: Access, value: u8) {
+        if !self.cpu.is_empty() {
+            self.check(WatchBus::Cpu, address, access, value)
+        }
+    }
+
+    pub(super) fn check_ppu(&self, address: u16, access: Access, value: u8) {
+        if !self.ppu.is_empty() {
+            self.check(WatchBus::Ppu, address, access, value)
+        }
+    }
+
+    pub(super) fn watching_ppu(&self) -> bool {
+        !self.ppu.is_empty()
+    }
+
+    pub(super) fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    Interrupt(InteruptSource),
+    Scanline(usize),
+    FrameComplete,
+    // Ran STEP_LIMIT instructions (or the caller's limit) without stopping
+    Limit,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    break_on_nmi: bool,
+    break_on_irq: bool,
+    break_on_brk: bool,
+}
+
+impl Debugger {
+    fn breaks_on(&self, source: InteruptSource) -> bool {
+        match source {
+            InteruptSource::Nmi => self.break_on_nmi,
+            InteruptSource::Irq => self.break_on_irq,
+            InteruptSource::Brk => self.break_on_brk,
+        }
+    }
+}
+
+struct StepResult {
+    frame_complete: bool,
+    stop: Option<StopReason>,
+}
+
+impl Nes {
+    pub fn add_breakpoint(&mut self, pc: u16) {
+        self.debugger.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u16) {
+        self.debugger.breakpoints.remove(&pc);
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &u16> {
+        self.debugger.breakpoints.iter()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.bus.watchpoints_mut().cpu.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.bus.watchpoints_mut().cpu.retain(|w| *w != watchpoint);
+    }
+
+    // Watches VRAM addresses accessed through $2007
+    pub fn add_ppu_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.bus.watchpoints_mut().ppu.push(watchpoint);
+    }
+
+    pub fn remove_ppu_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.bus.watchpoints_mut().ppu.retain(|w| *w != watchpoint);
+    }
+
+    pub fn set_break_on_interrupt(&mut self, source: InteruptSource, enable: bool) {
+        match source {
+            InteruptSource::Nmi => self.debugger.break_on_nmi = enable,
+            InteruptSource::Irq => self.debugger.break_on_irq = enable,
+            InteruptSource::Brk => self.debugger.break_on_brk = enable,
+        }
+    }
+
+    fn debug_step(&mut self) -> StepResult {
+        let (_, frame_complete) = self.run_instruction();
+
+        let watch_hit = self.bus.watchpoints().take_hit();
+        let interrupt = self.cpu.take_interrupt();
+        let pc = self.cpu.registers().pc;
+
+        let stop = if let Some(hit) = watch_hit {
+            Some(StopReason::Watchpoint(hit))
+        } else if let Some(source) = interrupt.filter(|s| self.debugger.breaks_on(*s)) {
+            Some(StopReason::Interrupt(source))
+        } else if self.debugger.breakpoints.contains(&pc) {
+            Some(StopReason::Breakpoint(pc))
+        } else {
+            None
+        };
+        StepResult {
+            frame_complete,
+            stop,
+        }
+    }
+
+    fn run_until(
+        &mut self,
+        limit: usize,
+        mut done: impl FnMut(&Nes, &StepResult) -> bool,
+    ) -> StopReason {
+        for _ in 0..limit {
+            let result = self.debug_step();
+            if let Some(stop) = result.stop {
+                return stop;
+            }
+            if done(self, &result) {
+                return StopReason::Step;
+            }
+        }
+        StopReason::Limit
+    }
+
+    pub fn step_instruction(&mut self) -> StopReason {
+        self.debug_step().stop.unwrap_or(StopReason::Step)
+    }
+
+    // Runs a JSR to completion, otherwise the same as step_instruction
+    pub fn step_over(&mut self) -> StopReason {
+        let regs = self.cpu.registers();
+        if self.bus.peek_byte(regs.pc) != OPCODE_JSR {
+            return self.step_instruction();
+        }
+        let return_pc = regs.pc.wrapping_add(3);
+        self.run_until(STEP_LIMIT, |nes, _| {
+            let now = nes.cpu.registers();
+            now.pc == return_pc && now.s == regs.s
+        })
+    }
+
+    // Runs until the current subroutine (or interrupt handler) returns
+    pub fn step_out(&mut self) -> StopReason {
+        let start_s = self.cpu.registers().s;
+        let mut returning = false;
+        for _ in 0..STEP_LIMIT {
+            let pc = self.cpu.registers().pc;
+            let opcode = self.bus.peek_byte(pc);
+            let result = self.debug_step();
+            if let Some(stop) = result.stop {
+                return stop;
+            }
+            if matches!(opcode, OPCODE_RTS | OPCODE_RTI) {
+                returning = self.cpu.registers().s > start_s;
+            }
+            if returning {
+                return StopReason::Step;
+            }
+        }
+        StopReason::Limit
+    }
+
+    // Stops at the start of the next visit to the scanline
+    pub fn run_to_scanline(&mut self, scanline: usize) -> StopReason {
+        let mut left = self.bus.ppu_position().0 != scanline;
+        let stop = self.run_until(STEP_LIMIT, |nes, _| {
+            let on_line = nes.bus.ppu_position().0 == scanline;
+            let reached = left && on_line;
+            left |= !on_line;
+            reached
+        });
+        match stop {
+            StopReason::Step => StopReason::Scanline(scanline),
+            stop => stop,
+        }
+    }
+
+    // Runs until a breakpoint, watchpoint or interrupt break, or until
+    // `limit` instructions have run
+    pub fn resume(&mut self, limit: usize) -> StopReason {
+        self.run_until(limit, |_, _| false)
+    }
+
+    pub fn resume_frame(&mut self) -> StopReason {
+        match self.run_until(STEP_LIMIT, |_, result| result.frame_complete) {
+            StopReason::Step => StopReason::FrameComplete,
+            stop => stop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Nes, cartridge::nrom_image};
+    use super::{Access, StopReason, WatchBus, WatchHit, WatchKind, Watchpoint};
+
+    fn test_nes() -> Nes {
+        let image = nrom_image(
+            &[
+                (
+                    0xC000,
+                    &[
+                        0xA9, 0x01, // LDA #$01
+                        0x20, 0x10, 0xC0, // JSR $C010
+                        0x8D, 0x00, 0x03, // STA $0300
+                        0x4C, 0x08, 0xC0, // JMP $C008
+                    ],
+                ),
+                (
+                    0xC010,
+                    &[
+                        0xE8, // INX
+                        0xAD, 0x00, 0x03, // LDA $0300
+                        0x60, // RTS
+                    ],
+                ),
+            ],
+            0xC000,
+        );
+        let mut nes = Nes::new();
+        nes.load_rom_bytes(&image).unwrap();
+        nes
+    }
+
+    fn pc(nes: &Nes) -> u16 {
+        nes.cpu.registers().pc
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut nes = test_nes();
+        nes.add_breakpoint(0xC005);
+        assert_eq!(nes.resume(100), StopReason::Breakpoint(0xC005));
+        assert_eq!(nes.cpu.registers().x, 1);
+        nes.remove_breakpoint(0xC005);
+        assert_eq!(nes.resume(100), StopReason::Limit);
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut nes = test_nes();
+        nes.step_instruction();
+        assert_eq!(pc(&nes), 0xC002);
+        assert_eq!(nes.step_over(), StopReason::Step);
+        assert_eq!(pc(&nes), 0xC005);
+
+        let mut nes = test_nes();
+        nes.step_instruction();
+        nes.step_instruction();
+        assert_eq!(pc(&nes), 0xC010);
+        assert_eq!(nes.step_out(), StopReason::Step);
+        assert_eq!(pc(&nes), 0xC005);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut nes = test_nes();
+        nes.add_watchpoint(Watchpoint {
+            start: 0x0300,
+            end: 0x0300,
+            kind: WatchKind::Write,
+        });
+        assert_eq!(
+            nes.resume(100),
+            StopReason::Watchpoint(WatchHit {
+                bus: WatchBus::Cpu,
+                address: 0x0300,
+                access: Access::Write,
+                value: 0x00,
+            })
+        );
+        assert_eq!(pc(&nes), 0xC008);
+    }
+
+    #[test]
+    fn test_fetch_watchpoint() {
+        // Operand fetches are reads like any other
+        let mut nes = test_nes();
+        nes.add_watchpoint(Watchpoint {
+            start: 0xC004,
+            end: 0xC004,
+            kind: WatchKind::Read,
+        });
+        assert_eq!(
+            nes.resume(100),
+            StopReason::Watchpoint(WatchHit {
+                bus: WatchBus::Cpu,
+                address: 0xC004,
+                access: Access::Read,
+                value: 0xC0,
+            })
+        );
+        assert_eq!(pc(&nes), 0xC010);
+
+        // So are interrupt vector fetches
+        let mut nes = Nes::new();
+        nes.load_rom_bytes(&nrom_image(&[(0xC000, &[0x00])], 0xC000))
+            .unwrap();
+        nes.add_watchpoint(Watchpoint {
+            start: 0xFFFE,
+            end: 0xFFFF,
+            kind: WatchKind::Read,
+        });
+        assert_eq!(
+            nes.resume(100),
+            StopReason::Watchpoint(WatchHit {
+                bus: WatchBus::Cpu,
+                address: 0xFFFE,
+                access: Access::Read,
+                value: 0xEA,
+            })
+        );
+        assert_eq!(pc(&nes), 0xEAEA);
+    }
+}
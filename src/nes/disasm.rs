@@ -0,0 +1,244 @@
+// 6502 disassembler shared by the tracer and the debugging tools.
+// Decoding goes through the cpu's opcode table (InstructionType::from), so
+// unofficial opcodes are named the same way the cpu sees them.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::cpu::{AddressMode, InstructionType};
+
+const NES_REGISTERS: &[(u16, &str)] = &[
+    (0x2000, "PPUCTRL"),
+    (0x2001, "PPUMASK"),
+    (0x2002, "PPUSTATUS"),
+    (0x2003, "OAMADDR"),
+    (0x2004, "OAMDATA"),
+    (0x2005, "PPUSCROLL"),
+    (0x2006, "PPUADDR"),
+    (0x2007, "PPUDATA"),
+    (0x4000, "SQ1_VOL"),
+    (0x4001, "SQ1_SWEEP"),
+    (0x4002, "SQ1_LO"),
+    (0x4003, "SQ1_HI"),
+    (0x4004, "SQ2_VOL"),
+    (0x4005, "SQ2_SWEEP"),
+    (0x4006, "SQ2_LO"),
+    (0x4007, "SQ2_HI"),
+    (0x4008, "TRI_LINEAR"),
+    (0x400A, "TRI_LO"),
+    (0x400B, "TRI_HI"),
+    (0x400C, "NOISE_VOL"),
+    (0x400E, "NOISE_LO"),
+    (0x400F, "NOISE_HI"),
+    (0x4010, "DMC_FREQ"),
+    (0x4011, "DMC_RAW"),
+    (0x4012, "DMC_START"),
+    (0x4013, "DMC_LEN"),
+    (0x4014, "OAMDMA"),
+    (0x4015, "SND_CHN"),
+    (0x4016, "JOY1"),
+    (0x4017, "JOY2"),
+];
+
+// Names for addresses, used in place of the raw operand
+#[derive(Default, Clone)]
+pub struct Symbols {
+    labels: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Symbols for the PPU/APU/IO registers
+    pub fn nes_registers() -> Self {
+        let mut symbols = Self::new();
+        for (addr, name) in NES_REGISTERS {
+            symbols.add_label(*addr, *name);
+        }
+        symbols
+    }
+
+    pub fn add_label(&mut self, addr: u16, name: impl Into<String>) {
+        self.labels.insert(addr, name.into());
+    }
+
+    pub fn remove_label(&mut self, addr: u16) {
+        self.labels.remove(&addr);
+    }
+
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: String,
+    pub mode: AddressMode,
+    pub official: bool,
+    // Operand without symbols, e.g. "($80),Y"
+    pub operand: String,
+    // Address named by the operand (before indexing), branch target for branches
+    pub target: Option<u16>,
+    pub label: Option<String>,
+}
+
+impl Instruction {
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.bytes.len() as u16)
+    }
+}
+
+fn operand_len(mode: AddressMode) -> usize {
+    match mode {
+        AddressMode::Implied | AddressMode::Acc => 0,
+        AddressMode::Abs | AddressMode::AbsX | AddressMode::AbsY | AddressMode::Ind => 2,
+        _ => 1,
+    }
+}
+
+fn format_operand(mode: AddressMode, addr: &str, value: u8) -> String {
+    match mode {
+        AddressMode::Implied => String::new(),
+        AddressMode::Acc => String::from("A"),
+        AddressMode::Imm => format!("#${:02X}", value),
+        AddressMode::Zpg | AddressMode::Abs | AddressMode::Rel => addr.to_string(),
+        AddressMode::ZpgX | AddressMode::AbsX => format!("{},X", addr),
+        AddressMode::ZpgY | AddressMode::AbsY => format!("{},Y", addr),
+        AddressMode::Ind => format!("({})", addr),
+        AddressMode::IndX => format!("({},X)", addr),
+        AddressMode::IndY => format!("({}),Y", addr),
+    }
+}
+
+// Missing bytes (e.g. at the end of a buffer) read as 0
+pub fn disassemble(address: u16, bytes: &[u8], symbols: &Symbols) -> Instruction {
+    let byte = |i: usize| bytes.get(i).copied().unwrap_or(0);
+    let opcode = byte(0);
+
+    let (mnemonic, mode, official) = match InstructionType::from(opcode) {
+        InstructionType::Control(inst, mode) => (format!("{:?}", inst), mode, true),
+        InstructionType::Alu(inst, mode) => (format!("{:?}", inst), mode, true),
+        InstructionType::Rmw(inst, mode) => (format!("{:?}", inst), mode, true),
+        InstructionType::Nop(mode) => (String::from("Nop"), mode, opcode == 0xEA),
+        InstructionType::Unofficial(inst, mode) => (format!("{:?}", inst), mode, false),
+    };
+
+    let len = 1 + operand_len(mode);
+    let word = (byte(1) as u16) | ((byte(2) as u16) << 8);
+    let target = match mode {
+        AddressMode::Implied | AddressMode::Acc | AddressMode::Imm => None,
+        AddressMode::Zpg | AddressMode::ZpgX | AddressMode::ZpgY => Some(byte(1) as u16),
+        AddressMode::IndX | AddressMode::IndY => Some(byte(1) as u16),
+        AddressMode::Abs | AddressMode::AbsX | AddressMode::AbsY | AddressMode::Ind => Some(word),
+        AddressMode::Rel => Some(
+            address
+                .wrapping_add(2)
+                .wrapping_add(byte(1) as i8 as u16),
+        ),
+    };
+    let raw_addr = match (mode, target) {
+        (_, None) => String::new(),
+        (AddressMode::Zpg | AddressMode::ZpgX | AddressMode::ZpgY, Some(t)) => format!("${:02X}", t),
+        (AddressMode::IndX | AddressMode::IndY, Some(t)) => format!("${:02X}", t),
+        (_, Some(t)) => format!("${:04X}", t),
+    };
+
+    Instruction {
+        address,
+        bytes: (0..len).map(byte).collect(),
+        mnemonic: mnemonic.to_uppercase(),
+        mode,
+        official,
+        operand: format_operand(mode, &raw_addr, byte(1)),
+        target,
+        label: target.and_then(|t| symbols.label(t)).map(String::from),
+    }
+}
+
+// Disassemble `count` instructions starting at `start`
+pub fn disassemble_range(
+    mut read: impl FnMut(u16) -> u8,
+    start: u16,
+    count: usize,
+    symbols: &Symbols,
+) -> Vec<Instruction> {
+    let mut address = start;
+    let mut ret = Vec::with_capacity(count);
+    for _ in 0..count {
+        let bytes = [
+            read(address),
+            read(address.wrapping_add(1)),
+            read(address.wrapping_add(2)),
+        ];
+        let inst = disassemble(address, &bytes, symbols);
+        address = inst.next_address();
+        ret.push(inst);
+    }
+    ret
+}
+
+// Operand with the target address replaced by its label, if it has one
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operand = match &self.label {
+            Some(label) => format_operand(self.mode, label, 0),
+            None => self.operand.clone(),
+        };
+        if operand.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, operand)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Symbols, disassemble, disassemble_range};
+
+    #[test]
+    fn test_operands() {
+        let symbols = Symbols::new();
+        let inst = disassemble(0xC000, &[0x4C, 0xF5, 0xC5], &symbols);
+        assert_eq!(inst.to_string(), "JMP $C5F5");
+        assert_eq!(inst.bytes.len(), 3);
+
+        assert_eq!(disassemble(0, &[0xB1, 0x89], &symbols).to_string(), "LDA ($89),Y");
+        assert_eq!(disassemble(0, &[0x4A], &symbols).to_string(), "LSR A");
+        // Branches show the target
+        assert_eq!(disassemble(0xC100, &[0xD0, 0xFE], &symbols).to_string(), "BNE $C100");
+    }
+
+    #[test]
+    fn test_unofficial() {
+        let inst = disassemble(0, &[0xA7, 0x10], &Symbols::new());
+        assert_eq!(inst.to_string(), "LAX $10");
+        assert!(!inst.official);
+        assert!(!disassemble(0, &[0x04, 0x10], &Symbols::new()).official);
+        assert!(disassemble(0, &[0xEA], &Symbols::new()).official);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut symbols = Symbols::nes_registers();
+        symbols.add_label(0x0300, "buffer");
+        assert_eq!(
+            disassemble(0, &[0x8D, 0x00, 0x20], &symbols).to_string(),
+            "STA PPUCTRL"
+        );
+        assert_eq!(
+            disassemble(0, &[0xBD, 0x00, 0x03], &symbols).to_string(),
+            "LDA buffer,X"
+        );
+
+        let code = [0xA9, 0x00, 0x8D, 0x14, 0x40, 0x60];
+        let insts = disassemble_range(|a| code[a as usize % code.len()], 0, 3, &symbols);
+        let text: Vec<_> = insts.iter().map(|i| i.to_string()).collect();
+        assert_eq!(text, ["LDA #$00", "STA OAMDMA", "RTS"]);
+    }
+}
@@ -0,0 +1,148 @@
+// Family BASIC keyboard on the Famicom expansion port. Writes to $4016 scan
+// the matrix: bit 0 resets to the first row, bit 1 picks the column and
+// going from column 1 back to 0 moves down a row, bit 2 enables the
+// keyboard. $4017 D1 to D4 then read four keys of the selected row and
+// column, 0 when pressed. A tenth row has no keys.
+//
+// The data recorder hangs off the keyboard: bit 2 of $4016 writes also goes
+// to the tape and the tape comes back on D1 of $4016.
+
+use super::data_recorder::DataRecorder;
+use super::input::ExpansionDevice;
+
+const ROWS: usize = 9;
+pub const KEYBOARD_KEYS: usize = ROWS * 8;
+
+// By matrix position: row, then column, then D1 to D4
+pub const KEYBOARD_LAYOUT: [&str; KEYBOARD_KEYS] = [
+    "]", "[", "Return", "F8", "Stop", "Yen", "RShift", "Kana", //
+    ";", ":", "@", "F7", "^", "-", "/", "_", //
+    "K", "L", "O", "F6", "0", "P", ",", ".", //
+    "J", "U", "I", "F5", "8", "9", "N", "M", //
+    "H", "G", "Y", "F4", "6", "7", "V", "B", //
+    "D", "R", "T", "F3", "4", "5", "C", "F", //
+    "A", "S", "W", "F2", "3", "E", "Z", "X", //
+    "Ctr", "Q", "Esc", "F1", "2", "1", "Grph", "LShift", //
+    "Left", "Right", "Up", "ClrHome", "Ins", "Del", "Space", "Down", //
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyboardState {
+    // Indexed like KEYBOARD_LAYOUT
+    pub keys: [bool; KEYBOARD_KEYS],
+}
+
+impl Default for KeyboardState {
+    fn default() -> Self {
+        KeyboardState {
+            keys: [false; KEYBOARD_KEYS],
+        }
+    }
+}
+
+impl KeyboardState {
+    // Names as in KEYBOARD_LAYOUT, ignoring case. False for unknown keys
+    pub fn set(&mut self, name: &str, pressed: bool) -> bool {
+        match KEYBOARD_LAYOUT
+            .iter()
+            .position(|key| key.eq_ignore_ascii_case(name))
+        {
+            Some(index) => {
+                self.keys[index] = pressed;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct FamilyKeyboard {
+    state: KeyboardState,
+    row: usize,
+    column: usize,
+    enabled: bool,
+    pub tape: DataRecorder,
+}
+
+impl FamilyKeyboard {
+    pub fn set_state(&mut self, state: KeyboardState) {
+        self.state = state
+    }
+}
+
+impl ExpansionDevice for FamilyKeyboard {
+    fn write(&mut self, val: u8) {
+        let column = (val as usize >> 1) & 0x01;
+        if column == 0 && self.column == 1 {
+            self.row = (self.row + 1) % (ROWS + 1);
+        }
+        self.column = column;
+        if val & 0x01 != 0 {
+            self.row = 0;
+        }
+        self.enabled = val & 0x04 != 0;
+        self.tape.write(val & 0x04 != 0);
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        if port == 0 {
+            return (self.tape.read() as u8) << 1;
+        }
+        if !self.enabled {
+            return 0;
+        }
+        let mut val = 0x1E;
+        if self.row < ROWS {
+            let first = self.row * 8 + self.column * 4;
+            for (bit, &pressed) in self.state.keys[first..first + 4].iter().enumerate() {
+                if pressed {
+                    val &= !(0x02 << bit);
+                }
+            }
+        }
+        val
+    }
+
+    fn clock(&mut self, dots: u64) {
+        self.tape.clock(dots);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::input::ExpansionDevice;
+    use super::{FamilyKeyboard, KeyboardState};
+
+    // Reads the whole matrix the way Family BASIC does
+    fn scan(keyboard: &mut FamilyKeyboard) -> Vec<u8> {
+        let mut reads = Vec::new();
+        keyboard.write(0x05);
+        for _ in 0..9 {
+            keyboard.write(0x04);
+            reads.push(keyboard.read(1));
+            keyboard.write(0x06);
+            reads.push(keyboard.read(1));
+        }
+        reads
+    }
+
+    #[test]
+    fn test_family_keyboard() {
+        let mut keyboard = FamilyKeyboard::default();
+        let mut state = KeyboardState::default();
+        assert!(state.set("return", true));
+        assert!(state.set("Space", true));
+        assert!(!state.set("Hyper", true));
+        keyboard.set_state(state);
+
+        let reads = scan(&mut keyboard);
+        let mut expected = vec![0x1E; 18];
+        expected[0] = 0x1E & !0x08;
+        expected[17] = 0x1E & !0x08;
+        assert_eq!(reads, expected);
+
+        keyboard.write(0x00);
+        assert_eq!(keyboard.read(1), 0);
+    }
+}
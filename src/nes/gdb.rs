@@ -0,0 +1,513 @@
+// GDB remote serial protocol stub, so external debuggers can attach.
+//
+// Registers are sent in the order A, X, Y, P, S (one byte each) then PC
+// (two bytes, little endian). Memory reads go through peek_byte so they
+// don't disturb the PPU or controllers.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+use super::Nes;
+use super::cpu::Registers;
+use super::debugger::{Access, StopReason, WatchBus, WatchKind, Watchpoint};
+
+// Instructions to run between checks for an interrupt from the client
+const CONTINUE_CHUNK: usize = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const INTERRUPT_BYTE: u8 = 0x03;
+
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+// Waits for one debugger on localhost:port and serves it until it detaches
+pub fn serve_tcp(nes: &mut Nes, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    GdbStub::new(nes, stream).run()
+}
+
+#[cfg(unix)]
+pub fn serve_unix(nes: &mut Nes, path: impl AsRef<Path>) -> io::Result<()> {
+    let listener = UnixListener::bind(path)?;
+    let (stream, _) = listener.accept()?;
+    GdbStub::new(nes, stream).run()
+}
+
+pub struct GdbStub<'a, C: Connection> {
+    nes: &'a mut Nes,
+    conn: C,
+    // Bytes that arrived while checking for an interrupt, read before the
+    // connection
+    pending: VecDeque<u8>,
+}
+
+enum Flow {
+    Continue,
+    Detach,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_num(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+// "addr,len" as used by m, M and Z packets
+fn parse_addr_len(text: &str) -> Option<(u16, u32)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((parse_num(addr)? as u16, parse_num(len)?))
+}
+
+fn register_bytes(regs: &Registers) -> [u8; 7] {
+    let [pc_lo, pc_hi] = regs.pc.to_le_bytes();
+    [regs.a, regs.x, regs.y, regs.p, regs.s, pc_lo, pc_hi]
+}
+
+fn stop_reply(stop: StopReason) -> String {
+    match stop {
+        StopReason::Watchpoint(hit) if hit.bus == WatchBus::Cpu => {
+            let kind = match hit.access {
+                Access::Read => "rwatch",
+                Access::Write => "watch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.address)
+        }
+        _ => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+impl<'a, C: Connection> GdbStub<'a, C> {
+    pub fn new(nes: &'a mut Nes, conn: C) -> Self {
+        Self {
+            nes,
+            conn,
+            pending: VecDeque::new(),
+        }
+    }
+
+    // Serves packets until the client detaches, kills or disconnects
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet)? {
+                Flow::Continue => (),
+                Flow::Detach => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0u8];
+        match self.conn.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Returns None when the connection closes
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acks, and interrupts while already stopped
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(INTERRUPT_BYTE) => {
+                    self.send_packet(&format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut sum = [0u8; 2];
+            for b in sum.iter_mut() {
+                *b = self
+                    .read_byte()?
+                    .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            }
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                == Some(checksum(&data));
+            if valid {
+                self.conn.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.conn.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.conn.write_all(packet.as_bytes())?;
+        self.conn.flush()
+    }
+
+    fn handle(&mut self, packet: &str) -> io::Result<Flow> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => to_hex(&register_bytes(&self.cpu_registers())),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "c" => match self.resume()? {
+                Some(stop) => stop_reply(stop),
+                None => format!("S{:02x}", SIGINT),
+            },
+            "s" => stop_reply(self.nes.step_instruction()),
+            "Z" => self.breakpoint(args, true),
+            "z" => self.breakpoint(args, false),
+            "H" => String::from("OK"),
+            "q" if args.starts_with("Supported") => String::from("PacketSize=1000"),
+            "q" if args == "Attached" => String::from("1"),
+            "D" => {
+                self.send_packet("OK")?;
+                return Ok(Flow::Detach);
+            }
+            "k" => return Ok(Flow::Detach),
+            // Unsupported
+            _ => String::new(),
+        };
+        self.send_packet(&reply)?;
+        Ok(Flow::Continue)
+    }
+
+    fn cpu_registers(&self) -> Registers {
+        self.nes.cpu.registers()
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        match from_hex(args) {
+            Some(bytes) if bytes.len() == 7 => {
+                let regs = Registers {
+                    a: bytes[0],
+                    x: bytes[1],
+                    y: bytes[2],
+                    p: bytes[3],
+                    s: bytes[4],
+                    pc: u16::from_le_bytes([bytes[5], bytes[6]]),
+                };
+                self.nes.cpu.set_registers(&regs);
+                String::from("OK")
+            }
+            _ => String::from("E01"),
+        }
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let regs = self.cpu_registers();
+        match parse_num(args) {
+            Some(n @ 0..=4) => to_hex(&register_bytes(&regs)[n as usize..=n as usize]),
+            Some(5) => to_hex(&regs.pc.to_le_bytes()),
+            _ => String::from("E01"),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let Some((n, value)) = args.split_once('=') else {
+            return String::from("E01");
+        };
+        let (Some(n), Some(value)) = (parse_num(n), from_hex(value)) else {
+            return String::from("E01");
+        };
+        let mut regs = self.cpu_registers();
+        match (n, value.as_slice()) {
+            (0, [v]) => regs.a = *v,
+            (1, [v]) => regs.x = *v,
+            (2, [v]) => regs.y = *v,
+            (3, [v]) => regs.p = *v,
+            (4, [v]) => regs.s = *v,
+            (5, [lo, hi]) => regs.pc = u16::from_le_bytes([*lo, *hi]),
+            _ => return String::from("E01"),
+        }
+        self.nes.cpu.set_registers(&regs);
+        String::from("OK")
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_addr_len(args) else {
+            return String::from("E01");
+        };
+        let mem = &self.nes.bus;
+        let bytes: Vec<u8> = (0..len.min(0x10000))
+            .map(|i| mem.peek_byte(addr.wrapping_add(i as u16)))
+            .collect();
+        to_hex(&bytes)
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((target, data)) = args.split_once(':') else {
+            return String::from("E01");
+        };
+        let (Some((addr, len)), Some(bytes)) = (parse_addr_len(target), from_hex(data)) else {
+            return String::from("E01");
+        };
+        if bytes.len() != len as usize {
+            return String::from("E01");
+        }
+        let mem = &mut self.nes.bus;
+        for (i, b) in bytes.iter().enumerate() {
+            mem.write_byte(addr.wrapping_add(i as u16), *b);
+        }
+        // Our own writes shouldn't trigger watchpoints
+        mem.watchpoints().take_hit();
+        String::from("OK")
+    }
+
+    // Z/z type,addr,kind
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return String::from("E01");
+        };
+        let (Some(addr), Some(len)) = (parse_num(addr), parse_num(len)) else {
+            return String::from("E01");
+        };
+        let addr = addr as u16;
+        match (kind, insert) {
+            // Software and hardware breakpoints are the same thing here
+            ("0" | "1", true) => self.nes.add_breakpoint(addr),
+            ("0" | "1", false) => self.nes.remove_breakpoint(addr),
+            ("2" | "3" | "4", _) => {
+                // Watchpoints cover len bytes, at most the whole address space
+                if !(1..=0x10000).contains(&len) {
+                    return String::from("E01");
+                }
+                let watchpoint = Watchpoint {
+                    start: addr,
+                    end: addr.wrapping_add((len - 1) as u16),
+                    kind: match kind {
+                        "2" => WatchKind::Write,
+                        "3" => WatchKind::Read,
+                        _ => WatchKind::ReadWrite,
+                    },
+                };
+                if insert {
+                    self.nes.add_watchpoint(watchpoint);
+                } else {
+                    self.nes.remove_watchpoint(watchpoint);
+                }
+            }
+            _ => return String::new(),
+        }
+        String::from("OK")
+    }
+
+    // Runs until something stops the Nes, or returns None if the client
+    // sends an interrupt first
+    fn resume(&mut self) -> io::Result<Option<StopReason>> {
+        loop {
+            let stop = self.nes.resume(CONTINUE_CHUNK);
+            if stop != StopReason::Limit {
+                return Ok(Some(stop));
+            }
+            if self.interrupt_pending()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn interrupt_pending(&mut self) -> io::Result<bool> {
+        self.conn.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = match self.conn.read(&mut byte) {
+            Ok(1) if byte[0] == INTERRUPT_BYTE => Ok(true),
+            Ok(1) => {
+                self.pending.push_back(byte[0]);
+                Ok(false)
+            }
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Debugger disconnected while the target was running",
+            )),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.conn.set_nonblocking(false)?;
+        result
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use super::super::{Nes, cartridge::nrom_image};
+    use super::{GdbStub, checksum};
+
+    struct Client {
+        stream: UnixStream,
+    }
+
+    impl Client {
+        // Sends a packet and returns the reply
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            let mut ack = [0u8];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut byte = [0u8];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut sum = [0u8; 2];
+            self.stream.read_exact(&mut sum).unwrap();
+            // The stub may already be gone after D
+            let _ = self.stream.write_all(b"+");
+            String::from_utf8(data).unwrap()
+        }
+    }
+
+    fn looping_nes() -> Nes {
+        let image = nrom_image(
+            &[(
+                0xC000,
+                &[
+                    0xA9, 0x42, // LDA #$42
+                    0x8D, 0x00, 0x03, // STA $0300
+                    0xE8, // INX
+                    0x4C, 0x05, 0xC0, // JMP $C005
+                ],
+            )],
+            0xC000,
+        );
+        let mut nes = Nes::new();
+        nes.load_rom_bytes(&image).unwrap();
+        nes
+    }
+
+    #[test]
+    fn test_session() {
+        let mut nes = looping_nes();
+        let (server, stream) = UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = Client { stream };
+            assert_eq!(client.request("?"), "S05");
+            assert_eq!(client.request("p5"), "00c0");
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("g"), "42000024fd02c0");
+            assert_eq!(client.request("Z2,300,1"), "OK");
+            assert_eq!(client.request("c"), "T05watch:300;");
+            assert_eq!(client.request("m300,2"), "4200");
+            assert_eq!(client.request("z2,300,1"), "OK");
+            assert_eq!(client.request("Z2,300,0"), "E01");
+            assert_eq!(client.request("Z2,300,10001"), "E01");
+            assert_eq!(client.request("Z3,300,10000"), "OK");
+            assert_eq!(client.request("z3,300,10000"), "OK");
+
+            assert_eq!(client.request("M310,2:abcd"), "OK");
+            assert_eq!(client.request("m310,2"), "abcd");
+            assert_eq!(client.request("P1=10"), "OK");
+            assert_eq!(client.request("Z0,c005,1"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p1"), "11");
+
+            // Free running until interrupted
+            assert_eq!(client.request("z0,c005,1"), "OK");
+            client.stream.write_all(b"$c#63").unwrap();
+            let mut ack = [0u8];
+            client.stream.read_exact(&mut ack).unwrap();
+            client.stream.write_all(&[0x03]).unwrap();
+            assert_eq!(client.reply(), "S02");
+
+            // A packet sent while running is answered after the interrupt
+            client.stream.write_all(b"$c#63").unwrap();
+            client.stream.read_exact(&mut ack).unwrap();
+            client.stream.write_all(b"$?#3f").unwrap();
+            client.stream.write_all(&[0x03]).unwrap();
+            assert_eq!(client.reply(), "S02");
+            client.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            assert_eq!(client.reply(), "S05");
+
+            assert_eq!(client.request("D"), "OK");
+        });
+        GdbStub::new(&mut nes, server).run().unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_disconnect_while_running() {
+        let mut nes = looping_nes();
+        let (server, mut stream) = UnixStream::pair().unwrap();
+        stream.write_all(b"$c#63").unwrap();
+        let client = thread::spawn(move || {
+            let mut ack = [0u8];
+            stream.read_exact(&mut ack).unwrap();
+            // Hangs up without interrupting
+        });
+        let err = GdbStub::new(&mut nes, server).run().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        client.join().unwrap();
+    }
+}
@@ -1,13 +1,51 @@
-pub trait InputDevice {
+use std::any::Any;
+use std::ops::BitOr;
+
+use image::RgbImage;
+
+use super::multitap::{HoriAdapter, Multitap};
+
+// Something plugged into one of the controller ports. Each device has its
+// own way of taking state from the frontend; get at it through
+// InputBus::device_mut.
+pub trait InputDevice: Any + Send {
+    // Strobe, bit 0 of $4016
     fn write(&mut self, val: u8);
 
+    // Data bits as they appear in $4016 or $4017
     fn read(&mut self) -> u8;
 
-    // Todo: Not general!
-    fn set_state(&mut self, state: ControllerState);
+    // Called before each read, for devices that watch the screen
+    fn sense_light(&mut self, _beam: &Beam) {}
 }
 
-#[derive(Default, Clone, Copy)]
+// Something on the Famicom expansion port. It sees the three output bits
+// of $4016 writes and answers on both $4016 and $4017, alongside whatever is
+// in the ports.
+pub trait ExpansionDevice: Any + Send {
+    // Bits 0 to 2 of $4016
+    fn write(&mut self, val: u8);
+
+    // 0 for $4016, 1 for $4017
+    fn read(&mut self, port: usize) -> u8;
+
+    fn sense_light(&mut self, _beam: &Beam) {}
+
+    // Called with Beam::dots before each access, for devices that keep time
+    fn clock(&mut self, _dots: u64) {}
+}
+
+// What the PPU has drawn so far. Pixels at or after the beam still hold the
+// previous frame
+pub struct Beam<'a> {
+    pub frame: &'a RgbImage,
+    pub scanline: usize,
+    pub dot: usize,
+    // PPU dots since power on
+    pub dots: u64,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct ControllerState {
     pub up: bool,
     pub down: bool,
@@ -19,6 +57,40 @@ pub struct ControllerState {
     pub b: bool,
 }
 
+impl ControllerState {
+    // In the order the buttons are shifted out, A first
+    pub(super) fn bits(&self) -> u8 {
+        let mut val = 0;
+        val |= if self.a { 0x01 } else { 0 };
+        val |= if self.b { 0x02 } else { 0 };
+        val |= if self.select { 0x04 } else { 0 };
+        val |= if self.start { 0x08 } else { 0 };
+        val |= if self.up { 0x10 } else { 0 };
+        val |= if self.down { 0x20 } else { 0 };
+        val |= if self.left { 0x40 } else { 0 };
+        val |= if self.right { 0x80 } else { 0 };
+        val
+    }
+}
+
+// Pressed on either
+impl BitOr for ControllerState {
+    type Output = ControllerState;
+
+    fn bitor(self, other: ControllerState) -> ControllerState {
+        ControllerState {
+            up: self.up || other.up,
+            down: self.down || other.down,
+            right: self.right || other.right,
+            left: self.left || other.left,
+            start: self.start || other.start,
+            select: self.select || other.select,
+            a: self.a || other.a,
+            b: self.b || other.b,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Controller {
     button_state: ControllerState,
@@ -27,17 +99,12 @@ pub struct Controller {
 }
 
 impl Controller {
+    pub fn set_state(&mut self, state: ControllerState) {
+        self.button_state = state
+    }
+
     fn poll(&mut self) {
-        let mut val = 0;
-        val |= if self.button_state.a { 0x01 } else { 0 };
-        val |= if self.button_state.b { 0x02 } else { 0 };
-        val |= if self.button_state.select { 0x04 } else { 0 };
-        val |= if self.button_state.start { 0x08 } else { 0 };
-        val |= if self.button_state.up { 0x10 } else { 0 };
-        val |= if self.button_state.down { 0x20 } else { 0 };
-        val |= if self.button_state.left { 0x40 } else { 0 };
-        val |= if self.button_state.right { 0x80 } else { 0 };
-        self.shift_register = val;
+        self.shift_register = self.button_state.bits();
     }
 }
 
@@ -58,53 +125,220 @@ impl InputDevice for Controller {
         self.shift_register |= 0x80; // Official controllers read 1 after emptying
         ret
     }
-
-    fn set_state(&mut self, state: ControllerState) {
-        self.button_state = state
-    }
 }
 
+pub const PORT_COUNT: usize = 2;
+
 #[derive(Default)]
 pub struct InputBus {
-    controller1: Option<Box<dyn InputDevice>>,
-    controller2: Option<Box<dyn InputDevice>>,
+    ports: [Option<Box<dyn InputDevice>>; PORT_COUNT],
+    expansion: Option<Box<dyn ExpansionDevice>>,
 }
 
 impl InputBus {
+    // Standard controllers in both ports
     pub fn new() -> Self {
-        Self {
-            controller1: Some(Box::<Controller>::default()),
-            controller2: None,
+        let mut bus = Self::default();
+        for port in &mut bus.ports {
+            *port = Some(Box::<Controller>::default());
         }
+        bus
     }
 
-    pub fn write(&mut self, val: u8) {
-        if let Some(c) = &mut self.controller1 {
-            c.write(val & 0x01)
+    pub fn attach(&mut self, port: usize, device: Box<dyn InputDevice>) -> Result<(), String> {
+        let slot = self
+            .ports
+            .get_mut(port)
+            .ok_or_else(|| format!("No controller port {}", port + 1))?;
+        *slot = Some(device);
+        Ok(())
+    }
+
+    // None when the port is empty or doesn't exist
+    pub fn detach(&mut self, port: usize) -> Option<Box<dyn InputDevice>> {
+        self.ports.get_mut(port)?.take()
+    }
+
+    // None when the port is empty or holds some other kind of device
+    pub fn device_mut<T: InputDevice>(&mut self, port: usize) -> Option<&mut T> {
+        let device: &mut dyn Any = self.ports.get_mut(port)?.as_deref_mut()?;
+        device.downcast_mut()
+    }
+
+    pub fn attach_expansion(&mut self, device: Box<dyn ExpansionDevice>) {
+        self.expansion = Some(device);
+    }
+
+    pub fn detach_expansion(&mut self) -> Option<Box<dyn ExpansionDevice>> {
+        self.expansion.take()
+    }
+
+    pub fn expansion_mut<T: ExpansionDevice>(&mut self) -> Option<&mut T> {
+        let device: &mut dyn Any = self.expansion.as_deref_mut()?;
+        device.downcast_mut()
+    }
+
+    pub fn write(&mut self, val: u8, beam: &Beam) {
+        for device in self.ports.iter_mut().flatten() {
+            device.write(val & 0x01)
         }
-        if let Some(c) = &mut self.controller2 {
-            c.write(val & 0x01)
+        if let Some(device) = &mut self.expansion {
+            device.clock(beam.dots);
+            device.write(val & 0x07)
         }
     }
 
-    pub fn read_4016(&mut self) -> u8 {
-        match &mut self.controller1 {
-            Some(c) => c.read(),
-            None => 0,
+    pub fn read_4016(&mut self, beam: &Beam) -> u8 {
+        self.read(0, beam)
+    }
+
+    pub fn read_4017(&mut self, beam: &Beam) -> u8 {
+        self.read(1, beam)
+    }
+
+    fn read(&mut self, port: usize, beam: &Beam) -> u8 {
+        let mut val = 0;
+        if let Some(device) = &mut self.ports[port] {
+            device.sense_light(beam);
+            val |= device.read();
         }
+        if let Some(device) = &mut self.expansion {
+            device.clock(beam.dots);
+            device.sense_light(beam);
+            val |= device.read(port);
+        }
+        val
     }
 
-    pub fn read_4017(&mut self) -> u8 {
-        match &mut self.controller2 {
-            Some(c) => c.read(),
-            None => 0,
+    // Players 1 and 2 (0 and 1) go to the controller or Four Score in their
+    // port. Players 3 and 4 only exist with a Four Score, or a Hori adapter
+    // on the expansion port. Others are ignored
+    pub fn set_controller_state(&mut self, player: usize, state: ControllerState) {
+        let (port, controller) = (player % PORT_COUNT, player / PORT_COUNT);
+        if let Some(tap) = self.device_mut::<Multitap>(port) {
+            tap.set_state(controller, state)
+        } else if controller == 0 {
+            if let Some(c) = self.device_mut::<Controller>(port) {
+                c.set_state(state)
+            }
+        } else if controller == 1
+            && let Some(hori) = self.expansion_mut::<HoriAdapter>()
+        {
+            hori.set_state(port, state)
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::super::multitap::{HoriAdapter, Multitap};
+    use super::{Beam, Controller, ControllerState, InputBus, InputDevice};
+
+    struct Constant(u8);
 
-    // TODO: not general!
-    pub fn set_controller1_state(&mut self, state: ControllerState) {
-        if let Some(c) = &mut self.controller1 {
-            c.set_state(state)
+    impl InputDevice for Constant {
+        fn write(&mut self, _val: u8) {}
+
+        fn read(&mut self) -> u8 {
+            self.0
         }
     }
+
+    #[test]
+    fn test_ports() {
+        let frame = RgbImage::new(256, 240);
+        let beam = Beam {
+            frame: &frame,
+            scanline: 0,
+            dot: 0,
+            dots: 0,
+        };
+        let mut bus = InputBus::new();
+        let state = ControllerState {
+            a: true,
+            ..Default::default()
+        };
+        bus.set_controller_state(1, state);
+        bus.write(1, &beam);
+        bus.write(0, &beam);
+        assert_eq!(bus.read_4016(&beam), 0);
+        assert_eq!(bus.read_4017(&beam), 1);
+        assert_eq!(bus.read_4017(&beam), 0);
+
+        bus.attach(0, Box::new(Constant(0x18))).unwrap();
+        assert!(bus.device_mut::<Controller>(0).is_none());
+        assert!(bus.device_mut::<Constant>(0).is_some());
+        assert_eq!(bus.read_4016(&beam), 0x18);
+        assert!(bus.detach(1).is_some());
+        assert_eq!(bus.read_4017(&beam), 0);
+        assert!(bus.attach(2, Box::new(Constant(0))).is_err());
+        assert!(bus.detach(2).is_none());
+
+        // Player 4 is the second controller on port 2's multitap
+        bus.attach(1, Box::new(Multitap::new(1))).unwrap();
+        bus.set_controller_state(3, state);
+        bus.write(1, &beam);
+        bus.write(0, &beam);
+        let bits: Vec<u8> = (0..9).map(|_| bus.read_4017(&beam)).collect();
+        assert_eq!(bits, [0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        // There's no player 6
+        bus.set_controller_state(5, state);
+    }
+
+    #[test]
+    fn test_hori() {
+        let frame = RgbImage::new(256, 240);
+        let beam = Beam {
+            frame: &frame,
+            scanline: 0,
+            dot: 0,
+            dots: 0,
+        };
+        let mut bus = InputBus::new();
+        bus.attach_expansion(Box::new(HoriAdapter::default()));
+        let a = ControllerState {
+            a: true,
+            ..Default::default()
+        };
+        let b = ControllerState {
+            b: true,
+            ..Default::default()
+        };
+        // Players 1 and 2 on the pads in the ports, 3 and 4 on the adapter
+        bus.set_controller_state(0, a);
+        bus.set_controller_state(1, b);
+        bus.set_controller_state(2, b);
+        bus.set_controller_state(3, a);
+        bus.write(1, &beam);
+        bus.write(0, &beam);
+        let reads: Vec<(u8, u8)> = (0..8)
+            .map(|_| (bus.read_4016(&beam), bus.read_4017(&beam)))
+            .collect();
+        assert_eq!(
+            reads,
+            [
+                (0x01, 0x02),
+                (0x02, 0x01),
+                (0x00, 0x00),
+                (0x00, 0x00),
+                (0x00, 0x00),
+                (0x00, 0x00),
+                (0x00, 0x00),
+                (0x00, 0x00),
+            ]
+        );
+        // D0 runs out after 8 bits, D1 goes on to the signatures
+        let reads: Vec<(u8, u8)> = (8..24)
+            .map(|_| (bus.read_4016(&beam), bus.read_4017(&beam)))
+            .collect();
+        assert_eq!(reads[10], (0x03, 0x01));
+        assert_eq!(reads[11], (0x01, 0x03));
+        assert!(
+            reads
+                .iter()
+                .all(|&(port1, port2)| port1 & port2 & 0x01 == 1)
+        );
+    }
 }
@@ -0,0 +1,122 @@
+// A controller's buttons frame by frame, recorded and replayed by the runner.
+// As text it's one frame per line in the FM2 button order, RLDUTSBA, with a
+// letter for each pressed button and a '.' for each released one. Blank lines
+// and lines starting with '#' are skipped.
+
+use super::input::ControllerState;
+
+const BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputMacro {
+    pub frames: Vec<ControllerState>,
+}
+
+impl InputMacro {
+    pub fn to_text(&self) -> String {
+        self.frames
+            .iter()
+            .map(|state| {
+                let pressed = buttons(state);
+                let mut line: String = BUTTONS
+                    .iter()
+                    .zip(pressed)
+                    .map(|(&c, on)| if on { c as char } else { '.' })
+                    .collect();
+                line.push('\n');
+                line
+            })
+            .collect()
+    }
+
+    pub fn from_text(text: &str) -> Result<InputMacro, String> {
+        let mut frames = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.len() != BUTTONS.len() {
+                return Err(format!(
+                    "Line {}: expected {} buttons, got \"{}\"",
+                    number + 1,
+                    BUTTONS.len(),
+                    line
+                ));
+            }
+            let mut pressed = [false; 8];
+            for (i, c) in line.bytes().enumerate() {
+                pressed[i] = match c {
+                    b'.' | b' ' => false,
+                    c if c.eq_ignore_ascii_case(&BUTTONS[i]) => true,
+                    _ => {
+                        return Err(format!(
+                            "Line {}: expected {} or '.' in column {}",
+                            number + 1,
+                            BUTTONS[i] as char,
+                            i + 1
+                        ));
+                    }
+                };
+            }
+            let [right, left, down, up, start, select, b, a] = pressed;
+            frames.push(ControllerState {
+                up,
+                down,
+                right,
+                left,
+                start,
+                select,
+                a,
+                b,
+            });
+        }
+        Ok(InputMacro { frames })
+    }
+}
+
+// In BUTTONS order
+fn buttons(state: &ControllerState) -> [bool; 8] {
+    [
+        state.right,
+        state.left,
+        state.down,
+        state.up,
+        state.start,
+        state.select,
+        state.b,
+        state.a,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::input::ControllerState;
+    use super::InputMacro;
+
+    #[test]
+    fn test_input_macro() {
+        let input = InputMacro {
+            frames: vec![
+                ControllerState {
+                    start: true,
+                    ..Default::default()
+                },
+                ControllerState::default(),
+                ControllerState {
+                    right: true,
+                    a: true,
+                    ..Default::default()
+                },
+            ],
+        };
+        let text = input.to_text();
+        assert_eq!(text, "....T...\n........\nR......A\n");
+        assert_eq!(InputMacro::from_text(&text), Ok(input.clone()));
+
+        let commented = format!("# Menu skip\n\n{}", text.to_lowercase());
+        assert_eq!(InputMacro::from_text(&commented), Ok(input));
+        assert!(InputMacro::from_text("R.......A\n").is_err());
+        assert!(InputMacro::from_text("X.......\n").is_err());
+    }
+}
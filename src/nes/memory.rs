@@ -1,14 +1,10 @@
-use std::{
-    cell::RefCell,
-    rc::{Rc, Weak},
-};
-
 use super::{
-    cartridge::{self, Cartridge},
-    cpu::Cpu,
+    bus::Bus,
+    cartridge::{self, Cartridge, NoCartridge},
+    debugger::{Access, Watchpoints},
     input::InputBus,
-    memory_utils::read_word_from_buffer,
     ppu::Ppu,
+    state::{SaveState, StateReader, StateWriter},
 };
 
 const RAM_SIZE: usize = 0x0800;
@@ -41,12 +37,22 @@ pub struct MemoryMap {
     //ppu_reg: [u8; PPU_REG_SIZE],
     apu_reg: [u8; APU_REG_SIZE],
     apu_test_reg: [u8; APU_TEST_REG_SIZE],
-    cartridge: Option<Rc<RefCell<Box<dyn Cartridge>>>>,
+    cartridge: Box<dyn Cartridge>,
+
+    pub(super) ppu: Ppu,
+    pub(super) io: InputBus,
+
+    // Last value on the data bus, which reads of unmapped or write-only
+    // addresses see
+    open_bus: u8,
+
+    // CPU accesses so far in the current instruction, and how many of its
+    // cycles the PPU has been run for
+    accesses: u64,
+    ppu_cycles: u64,
+    frame_complete: bool,
 
-    // Todo: reorganize
-    ppu: Weak<RefCell<Ppu>>,
-    cpu: Weak<RefCell<Cpu>>,
-    io: Weak<RefCell<InputBus>>,
+    watchpoints: Watchpoints,
 }
 
 enum Address {
@@ -64,11 +70,18 @@ impl MemoryMap {
             // ppu_reg: [0; PPU_REG_SIZE],
             apu_reg: [0; APU_REG_SIZE],
             apu_test_reg: [0; APU_TEST_REG_SIZE],
-            cartridge: None,
+            cartridge: Box::new(NoCartridge),
 
-            ppu: Weak::new(),
-            cpu: Weak::new(),
-            io: Weak::new(),
+            ppu: Ppu::new(),
+            io: InputBus::new(),
+
+            open_bus: 0,
+
+            accesses: 0,
+            ppu_cycles: 0,
+            frame_complete: false,
+
+            watchpoints: Watchpoints::default(),
         }
     }
 
@@ -80,10 +93,10 @@ impl MemoryMap {
                 Address::Ram(address as usize - (RAM_MIR1_START_ADDR))
             }
             RAM_MIR2_START_ADDR..=RAM_MIR2_END_ADDR => {
-                Address::Ram(address as usize - (RAM_MIR1_START_ADDR))
+                Address::Ram(address as usize - (RAM_MIR2_START_ADDR))
             }
             RAM_MIR3_START_ADDR..=RAM_MIR3_END_ADDR => {
-                Address::Ram(address as usize - (RAM_MIR1_START_ADDR))
+                Address::Ram(address as usize - (RAM_MIR3_START_ADDR))
             }
             PPU_REG_START_ADDR..=PPU_MIR_END_ADDR => {
                 Address::Ppu(address as usize % (PPU_REG_SIZE))
@@ -95,64 +108,68 @@ impl MemoryMap {
                 Address::ApuTest(address as usize - (APU_TEST_START_ADDR))
             }
             CARTRIDGE_SPACE_START_ADDR..=CARTRIDGE_SPACE_END_ADDR => Address::Cartridge,
-            _ => panic!("Invalid memory read"),
+            _ => unreachable!(), // Every u16 is covered
         }
     }
 
-    pub fn read_byte(&self, address: u16) -> u8 {
+    pub fn read_byte(&mut self, address: u16) -> u8 {
+        let ppu_addr = self.watched_ppu_addr(address);
         let parsed_addr: Address = self.map_address(address);
-        match parsed_addr {
+        let val = match parsed_addr {
             Address::Ram(offset) => self.ram[offset],
-            Address::Ppu(offset) => self
-                .ppu
-                .upgrade()
-                .unwrap()
-                .borrow_mut()
-                .read_reg(offset as u16), // TODO: why does this compile??
+            Address::Ppu(offset) => self.ppu.read_reg(offset as u16, self.cartridge.as_mut()),
             Address::Apu(offset) => {
+                // Controllers only drive D0-D4
                 match offset {
-                    0x16 => self.io.upgrade().unwrap().borrow_mut().read_4016(),
-                    0x17 => self.io.upgrade().unwrap().borrow_mut().read_4017(),
-                    _ => 0, // Open bus
+                    0x16 => self.io.read_4016(&self.ppu.beam()) & 0x1F | self.open_bus & 0xE0,
+                    0x17 => self.io.read_4017(&self.ppu.beam()) & 0x1F | self.open_bus & 0xE0,
+                    _ => self.open_bus, // Write only, or no APU yet
                 }
             }
-            Address::ApuTest(offset) => self.apu_test_reg[offset],
-            Address::Cartridge => self.cartridge.as_ref().unwrap().borrow().read_byte(address),
+            Address::ApuTest(_) => self.open_bus, // Disabled on retail consoles
+            Address::Cartridge => self.cartridge.read_byte(address).unwrap_or(self.open_bus),
+        };
+        self.open_bus = val;
+        self.watchpoints.check_cpu(address, Access::Read, val);
+        if let Some(ppu_addr) = ppu_addr {
+            self.watchpoints.check_ppu(ppu_addr, Access::Read, val);
         }
+        val
     }
 
-    pub fn read_word(&self, address: u16) -> u16 {
+    // VRAM address of a $2007 access, if PPU watchpoints are set
+    fn watched_ppu_addr(&self, address: u16) -> Option<u16> {
+        let is_ppudata = (PPU_REG_START_ADDR..=PPU_MIR_END_ADDR).contains(&(address as usize))
+            && address % PPU_REG_SIZE as u16 == 7;
+        if is_ppudata && self.watchpoints.watching_ppu() {
+            Some(self.ppu.vram_addr())
+        } else {
+            None
+        }
+    }
+
+    // Read without side effects, for tracing and debugging
+    pub fn peek_byte(&self, address: u16) -> u8 {
         let parsed_addr: Address = self.map_address(address);
-        // NES 6502 CPU is little endian?
         match parsed_addr {
-            Address::Ram(offset) => read_word_from_buffer(&self.ram, offset),
-            Address::Ppu(_) => panic!("Reading word from ppu reg"),
-            Address::Apu(offset) => {
-                match offset {
-                    0x16 => {
-                        todo!("Read word from input");
-                    }
-                    0x17 => {
-                        todo!("Read word from input+1")
-                    }
-                    _ => 0, // Open bus
-                }
-            }
-            Address::ApuTest(offset) => read_word_from_buffer(&self.apu_test_reg, offset),
-            Address::Cartridge => self.cartridge.as_ref().unwrap().borrow().read_word(address),
+            Address::Ram(offset) => self.ram[offset],
+            Address::Ppu(_) | Address::Apu(_) | Address::ApuTest(_) => self.open_bus,
+            Address::Cartridge => self.cartridge.read_byte(address).unwrap_or(self.open_bus),
         }
     }
 
     pub fn write_byte(&mut self, address: u16, val: u8) -> u32 {
+        self.watchpoints.check_cpu(address, Access::Write, val);
+        self.open_bus = val;
+        if let Some(ppu_addr) = self.watched_ppu_addr(address) {
+            self.watchpoints.check_ppu(ppu_addr, Access::Write, val);
+        }
         let parsed_addr: Address = self.map_address(address);
         match parsed_addr {
             Address::Ram(offset) => self.ram[offset] = val,
             Address::Ppu(offset) => self
                 .ppu
-                .upgrade()
-                .unwrap()
-                .borrow_mut()
-                .write_reg(offset as u16, val),
+                .write_reg(offset as u16, val, self.cartridge.as_mut()),
             Address::Apu(offset) => {
                 match offset {
                     0x14 => {
@@ -162,21 +179,16 @@ impl MemoryMap {
                         for i in 0..256 {
                             data[i as usize] = self.read_byte(start_addr + i);
                         }
-                        self.ppu.upgrade().unwrap().borrow_mut().oam_dma(data);
+                        self.ppu.oam_dma(data);
                     }
                     0x16 => {
-                        self.io.upgrade().unwrap().borrow_mut().write(val);
+                        self.io.write(val, &self.ppu.beam());
                     }
                     _ => self.apu_reg[offset] = val,
                 }
             }
             Address::ApuTest(offset) => self.apu_test_reg[offset] = val,
-            Address::Cartridge => self
-                .cartridge
-                .as_mut()
-                .unwrap()
-                .borrow_mut()
-                .write_byte(address, val),
+            Address::Cartridge => self.cartridge.write_byte(address, val),
         };
 
         // hidden cycles
@@ -186,35 +198,159 @@ impl MemoryMap {
         }
     }
 
-    // todo move this
-    pub fn load_rom(&mut self, path: String) -> Result<(), String> {
-        let loaded_cartridge = Rc::new(RefCell::new(cartridge::load_rom(path)?));
-        self.ppu
-            .upgrade()
-            .unwrap()
-            .borrow_mut()
-            .set_cartridge(loaded_cartridge.clone());
-        self.cartridge = Some(loaded_cartridge);
+    // RAM and the cartridge keep their contents over a reset
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        // $4015, silences all APU channels
+        self.apu_reg[0x15] = 0;
+    }
+
+    pub fn load_rom_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.insert_cartridge(cartridge::parse_rom(bytes)?);
         Ok(())
     }
 
-    // todo: reorganize
-    pub fn set_refs(
-        &mut self,
-        ppu: Rc<RefCell<Ppu>>,
-        cpu: Rc<RefCell<Cpu>>,
-        inputs: Rc<RefCell<InputBus>>,
-    ) {
-        self.ppu = Rc::downgrade(&ppu);
-        self.cpu = Rc::downgrade(&cpu);
-        self.io = Rc::downgrade(&inputs)
+    pub fn cartridge(&self) -> &dyn Cartridge {
+        self.cartridge.as_ref()
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut dyn Cartridge {
+        self.cartridge.as_mut()
+    }
+
+    fn insert_cartridge(&mut self, cartridge: Box<dyn Cartridge>) {
+        self.ppu.set_mirroring(cartridge.get_nt_mirroring());
+        self.cartridge = cartridge;
+    }
+
+    // Each CPU access takes a cycle. The PPU is run up to the access before
+    // touching its registers, OAM DMA or the inputs that look at the beam,
+    // so they happen on the right dot
+    fn catch_up_ppu(&mut self, address: u16) {
+        self.accesses += 1;
+        if matches!(
+            self.map_address(address),
+            Address::Ppu(_) | Address::Apu(0x14 | 0x16 | 0x17)
+        ) {
+            self.run_ppu_to(self.accesses);
+        }
+    }
+
+    fn run_ppu_to(&mut self, cpu_cycles: u64) {
+        if cpu_cycles > self.ppu_cycles {
+            let dots = (cpu_cycles - self.ppu_cycles) * 3;
+            self.frame_complete |= self.ppu.advance_cycles(dots, self.cartridge.as_mut());
+            self.ppu_cycles = cpu_cycles;
+        }
     }
 
-    // pub fn get_chr(&self) -> &[u8; 0x2000] {
-    //     self.cartridge.as_ref().unwrap().read_chr()
-    // }
+    // Runs the PPU for what's left of an instruction that took cpu_cycles.
+    // Returns true when a frame was completed during the instruction
+    pub fn finish_instruction(&mut self, cpu_cycles: u64) -> bool {
+        self.run_ppu_to(cpu_cycles);
+        self.accesses = 0;
+        self.ppu_cycles = 0;
+        std::mem::take(&mut self.frame_complete)
+    }
+
+    pub fn ppu_position(&self) -> (usize, usize) {
+        self.ppu.position()
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
 
     pub fn nmi_requested(&self) -> bool {
-        self.ppu.upgrade().unwrap().borrow().nmi_requested()
+        self.ppu.nmi_requested()
+    }
+}
+
+// The CPU's accesses, as opposed to OAM DMA's, move the PPU along
+impl Bus for MemoryMap {
+    fn read_byte(&mut self, address: u16) -> u8 {
+        self.catch_up_ppu(address);
+        MemoryMap::read_byte(self, address)
+    }
+
+    fn write_byte(&mut self, address: u16, val: u8) -> u32 {
+        self.catch_up_ppu(address);
+        MemoryMap::write_byte(self, address, val)
+    }
+
+    fn nmi_requested(&self) -> bool {
+        MemoryMap::nmi_requested(self)
+    }
+}
+
+// The cartridge and PPU are saved by Nes
+impl SaveState for MemoryMap {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.bytes(&self.apu_reg);
+        w.bytes(&self.apu_test_reg);
+        w.u8(self.open_bus);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.bytes_into(&mut self.ram)?;
+        r.bytes_into(&mut self.apu_reg)?;
+        r.bytes_into(&mut self.apu_test_reg)?;
+        self.open_bus = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{bus::Bus, cartridge::nrom_image};
+    use super::MemoryMap;
+
+    #[test]
+    fn test_open_bus() {
+        let mut mem = MemoryMap::new();
+        mem.load_rom_bytes(&nrom_image(&[], 0x8000)).unwrap();
+
+        // Nothing panics, whatever gets read
+        for address in 0..=0xFFFF {
+            mem.read_byte(address);
+            Bus::read_word(&mut mem, address);
+        }
+
+        // Unmapped and write-only addresses read the last value on the bus
+        mem.write_byte(0x0010, 0x5A);
+        for address in [0x4000, 0x4014, 0x4018, 0x4020, 0x5FFF] {
+            assert_eq!(mem.read_byte(address), 0x5A);
+        }
+
+        // Controllers leave D5-D7 alone
+        mem.write_byte(0x0010, 0x40);
+        mem.read_byte(0x0010);
+        assert_eq!(mem.read_byte(0x4016) & 0xE0, 0x40);
+    }
+
+    #[test]
+    fn test_ppu_catch_up() {
+        let mut mem = MemoryMap::new();
+        mem.load_rom_bytes(&nrom_image(&[], 0x8000)).unwrap();
+
+        // LDA $2002: the PPU is run up to the fourth cycle before the read,
+        // and the rest of the instruction afterwards
+        for address in [0x8000, 0x8001, 0x8002] {
+            Bus::read_byte(&mut mem, address);
+        }
+        assert_eq!(mem.ppu_position(), (0, 0));
+        Bus::read_byte(&mut mem, 0x2002);
+        assert_eq!(mem.ppu_position(), (0, 12));
+        mem.finish_instruction(6);
+        assert_eq!(mem.ppu_position(), (0, 18));
+
+        // OAM DMA's own reads don't count
+        Bus::write_byte(&mut mem, 0x4014, 0x02);
+        assert_eq!(mem.ppu_position(), (0, 21));
     }
 }
@@ -1,67 +1,265 @@
+pub mod arkanoid;
+mod bus;
 mod cartridge;
-pub mod cpu; // temporarily public
+pub(crate) mod cpu;
+pub mod data_recorder;
+pub mod debugger;
+pub mod disasm;
+pub mod family_keyboard;
+pub mod gdb;
 pub mod input;
+pub mod input_macro;
 mod memory;
-mod memory_utils;
+pub mod multitap;
+pub mod power_pad;
 mod ppu;
+pub mod runner;
+mod state;
+#[cfg(test)]
+mod test_roms;
+mod trace;
+pub mod zapper;
 
-use std::{cell::RefCell, rc::Rc};
+use std::io::Write;
+
+pub use cartridge::{Mirroring, Region, RomInfo};
+pub use cpu::{InteruptSource, Registers};
+pub use ppu::{Palette, SYSTEM_PALETTE, parse_pal};
+pub use state::Snapshot;
 
 use cpu::Cpu;
-use input::InputBus;
+use debugger::Debugger;
+use input::{ControllerState, ExpansionDevice, InputDevice};
 use memory::MemoryMap;
-use ppu::Ppu;
+use state::SaveState;
+
+pub const FRAME_WIDTH: u32 = 256;
+pub const FRAME_HEIGHT: u32 = 240;
 
 pub struct Nes {
-    pub cpu: Rc<RefCell<Cpu>>,
-    pub ppu: Rc<RefCell<Ppu>>,
-    pub mem: Rc<RefCell<MemoryMap>>,
-    pub inputs: Rc<RefCell<InputBus>>,
+    pub(crate) cpu: Cpu,
+    // Owns the PPU, inputs and cartridge
+    pub(crate) bus: MemoryMap,
+    debugger: Debugger,
+    tracer: Option<Box<dyn Write + Send>>,
+    // Kept for power cycling
+    rom: Option<Vec<u8>>,
+    rom_info: Option<RomInfo>,
 }
 
+// So it can run on its own thread
+#[allow(unused)]
+fn assert_send<T: Send>() {}
+const _: fn() = assert_send::<Nes>;
+
 impl Nes {
     pub fn new() -> Self {
-        let mem = Rc::new(RefCell::new(MemoryMap::new()));
-
-        let ppu = Rc::new(RefCell::new(Ppu::new()));
-        let cpu = Rc::new(RefCell::new(Cpu::new(mem.clone())));
-        let inputs = Rc::new(RefCell::new(InputBus::new()));
-
-        mem.borrow_mut()
-            .set_refs(ppu.clone(), cpu.clone(), inputs.clone());
-
         Nes {
-            ppu,
-            cpu,
-            mem,
-            inputs,
+            cpu: Cpu::new(),
+            bus: MemoryMap::new(),
+            debugger: Debugger::default(),
+            tracer: None,
+            rom: None,
+            rom_info: None,
+        }
+    }
+
+    // Runs one instruction (or interrupt entry), returns the cpu cycles taken
+    // and whether a frame was completed
+    fn run_instruction(&mut self) -> (u64, bool) {
+        if self.tracer.is_some() && !self.cpu.nmi_pending(&self.bus) {
+            let line = self.trace_line();
+            if let Some(tracer) = &mut self.tracer {
+                _ = writeln!(tracer, "{}", line);
+            }
         }
+        let (cpu_cycles, _) = self.cpu.run_instruction(&mut self.bus);
+        let frame_complete = self.bus.finish_instruction(cpu_cycles);
+        (cpu_cycles, frame_complete)
     }
 
-    #[allow(unused)]
     pub fn step(&mut self) -> Option<image::RgbaImage> {
-        let (cpu_cycles, _) = self.cpu.borrow_mut().run_instruction();
-        self.ppu.borrow_mut().advance_cycles(cpu_cycles * 3);
+        self.run_instruction();
         None
     }
 
     pub fn run_frame(&mut self) -> image::RgbImage {
         let mut frame_complete = false;
         while !frame_complete {
-            let (cpu_cycles, _) = self.cpu.borrow_mut().run_instruction();
-            frame_complete = self.ppu.borrow_mut().advance_cycles(cpu_cycles * 3);
+            (_, frame_complete) = self.run_instruction();
         }
-        self.ppu.borrow().get_frame()
+        self.bus.ppu.get_frame()
+    }
+
+    // The last frame rendered, FRAME_WIDTH x FRAME_HEIGHT
+    pub fn frame(&self) -> image::RgbImage {
+        self.bus.ppu.get_frame()
     }
 
     // todo move this
     pub fn load_rom(&mut self, path: String) -> Result<(), String> {
-        let Ok(()) = self.mem.borrow_mut().load_rom(path) else {
-            panic!("Failed to load")
-        };
-        self.cpu.borrow_mut().initialize();
+        let bytes = std::fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        self.load_rom_bytes(&bytes)
+    }
+
+    pub fn load_rom_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let info = RomInfo::parse(bytes)?;
+        self.bus.load_rom_bytes(bytes)?;
+        self.rom = Some(bytes.to_vec());
+        self.rom_info = Some(info);
+        self.power_on();
         Ok(())
     }
+
+    pub fn rom_info(&self) -> Option<&RomInfo> {
+        self.rom_info.as_ref()
+    }
+
+    fn power_on(&mut self) {
+        self.cpu.initialize(&mut self.bus);
+        self.bus.finish_instruction(self.cpu.cycles());
+    }
+
+    // The console's reset button
+    pub fn reset(&mut self) {
+        let start = self.cpu.cycles();
+        self.cpu.reset(&mut self.bus);
+        self.bus.reset();
+        self.bus.finish_instruction(self.cpu.cycles() - start);
+    }
+
+    // Turns the console off and on again. Everything but the cartridge ROM is
+    // lost; breakpoints, watchpoints, the tracer and whatever is plugged into
+    // the controller ports stay.
+    pub fn power_cycle(&mut self) {
+        let watchpoints = std::mem::take(self.bus.watchpoints_mut());
+        let io = std::mem::take(&mut self.bus.io);
+        self.cpu = Cpu::new();
+        self.bus = MemoryMap::new();
+        *self.bus.watchpoints_mut() = watchpoints;
+        self.bus.io = io;
+        if let Some(rom) = &self.rom {
+            self.bus
+                .load_rom_bytes(rom)
+                .expect("ROM failed to load a second time");
+        }
+        self.power_on();
+    }
+
+    // Port 0 is $4016, port 1 is $4017
+    pub fn attach_input(
+        &mut self,
+        port: usize,
+        device: Box<dyn InputDevice>,
+    ) -> Result<(), String> {
+        self.bus.io.attach(port, device)
+    }
+
+    pub fn detach_input(&mut self, port: usize) -> Option<Box<dyn InputDevice>> {
+        self.bus.io.detach(port)
+    }
+
+    pub fn input_device_mut<T: InputDevice>(&mut self, port: usize) -> Option<&mut T> {
+        self.bus.io.device_mut(port)
+    }
+
+    // The Famicom's expansion port, read along with both controller ports
+    pub fn attach_expansion(&mut self, device: Box<dyn ExpansionDevice>) {
+        self.bus.io.attach_expansion(device);
+    }
+
+    pub fn detach_expansion(&mut self) -> Option<Box<dyn ExpansionDevice>> {
+        self.bus.io.detach_expansion()
+    }
+
+    pub fn expansion_device_mut<T: ExpansionDevice>(&mut self) -> Option<&mut T> {
+        self.bus.io.expansion_mut()
+    }
+
+    // Player 0 to 3. Does nothing unless a standard controller or Four Score
+    // is in the player's port, or for players 2 and 3 a Hori adapter is on
+    // the expansion port
+    pub fn set_controller_state(&mut self, player: usize, state: ControllerState) {
+        self.bus.io.set_controller_state(player, state);
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    // Log every instruction in nestest.log format before it runs
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Write + Send>>) {
+        self.tracer = tracer;
+    }
+
+    // The instruction at PC in nestest.log format
+    pub fn trace_line(&self) -> String {
+        trace::nestest_line(&self.cpu.registers(), &self.bus, self.cpu.cycles())
+    }
+
+    // Colours used for the frame, SYSTEM_PALETTE by default
+    pub fn set_palette(&mut self, palette: &Palette) {
+        self.bus.ppu.set_system_palette(palette);
+    }
+
+    // Debug views
+    pub fn chr_image(&self) -> image::GrayImage {
+        self.bus.ppu.render_chr(self.bus.cartridge())
+    }
+
+    pub fn nametable_image(&self) -> image::RgbImage {
+        self.bus.ppu.render_nt(self.bus.cartridge())
+    }
+
+    pub fn print_nametable(&self) {
+        self.bus.ppu.print_nametable()
+    }
+
+    pub fn save_state(&self) -> Snapshot {
+        let mut w = Snapshot::writer();
+        if let Some(info) = &self.rom_info {
+            w.u16(info.mapper);
+            w.usize(info.prg_rom_size);
+            w.usize(info.chr_rom_size);
+        }
+        self.cpu.save_state(&mut w);
+        self.bus.save_state(&mut w);
+        self.bus.ppu.save_state(&mut w);
+        self.bus.cartridge().save_state(&mut w);
+        w.finish()
+    }
+
+    // The state must come from the same ROM
+    pub fn load_state(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        if let Some(info) = &self.rom_info {
+            let mut r = snapshot.reader();
+            let saved = (r.u16()?, r.usize()?, r.usize()?);
+            if saved != (info.mapper, info.prg_rom_size, info.chr_rom_size) {
+                return Err(String::from("Save state is for a different ROM"));
+            }
+        }
+        // Put things back if the state turns out to be broken
+        let backup = self.save_state();
+        let mut r = snapshot.reader();
+        let result = self.load_components(&mut r).and_then(|_| r.finish());
+        if result.is_err() {
+            self.load_components(&mut backup.reader())
+                .expect("Failed to restore state");
+        }
+        result
+    }
+
+    fn load_components(&mut self, r: &mut state::StateReader) -> Result<(), String> {
+        if self.rom_info.is_some() {
+            r.u16()?;
+            r.usize()?;
+            r.usize()?;
+        }
+        self.cpu.load_state(r)?;
+        self.bus.load_state(r)?;
+        self.bus.ppu.load_state(r)?;
+        self.bus.cartridge_mut().load_state(r)
+    }
 }
 
 impl Default for Nes {
@@ -69,3 +267,86 @@ impl Default for Nes {
         Nes::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Nes, cartridge::nrom_image, zapper::Zapper};
+
+    #[test]
+    fn test_snapshot() {
+        let image = nrom_image(
+            &[(
+                0xC000,
+                &[
+                    0xE8, // INX
+                    0x8E, 0x00, 0x03, // STX $0300
+                    0x4C, 0x00, 0xC0, // JMP $C000
+                ],
+            )],
+            0xC000,
+        );
+        let mut nes = Nes::new();
+        nes.load_rom_bytes(&image).unwrap();
+        nes.run_frame();
+        let snapshot = nes.save_state();
+        let regs = nes.registers();
+
+        nes.run_frame();
+        assert_ne!(nes.registers(), regs);
+        nes.load_state(&snapshot).unwrap();
+        assert_eq!(nes.registers(), regs);
+        assert_eq!(nes.save_state(), snapshot);
+
+        // Round trip through bytes, and reject garbage
+        let bytes = snapshot.as_bytes().to_vec();
+        let snapshot = super::Snapshot::from_bytes(bytes.clone()).unwrap();
+        nes.load_state(&snapshot).unwrap();
+        let truncated = super::Snapshot::from_bytes(bytes[..bytes.len() - 1].to_vec()).unwrap();
+        assert!(nes.load_state(&truncated).is_err());
+        assert_eq!(nes.registers(), regs);
+
+        // A length prefix, RAM's, claiming more bytes than could exist
+        let ram_len = 0x800u64.to_le_bytes();
+        let at = bytes.windows(8).position(|w| w == ram_len).unwrap();
+        let mut huge = bytes.clone();
+        huge[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let huge = super::Snapshot::from_bytes(huge).unwrap();
+        assert!(nes.load_state(&huge).is_err());
+        assert_eq!(nes.registers(), regs);
+    }
+
+    #[test]
+    fn test_reset() {
+        let image = nrom_image(
+            &[(
+                0xC000,
+                &[
+                    0xE8, // INX
+                    0x8E, 0x00, 0x03, // STX $0300
+                    0x4C, 0x00, 0xC0, // JMP $C000
+                ],
+            )],
+            0xC000,
+        );
+        let mut nes = Nes::new();
+        nes.load_rom_bytes(&image).unwrap();
+        nes.run_frame();
+        let before = nes.registers();
+        assert_ne!(before.x, 0);
+
+        nes.reset();
+        let after = nes.registers();
+        assert_eq!(after.pc, 0xC000);
+        assert_eq!(after.s, before.s.wrapping_sub(3));
+        assert_eq!(after.x, before.x);
+        assert_ne!(after.p & 0x04, 0);
+        assert_ne!(nes.bus.peek_byte(0x0300), 0);
+
+        nes.attach_input(1, Box::<Zapper>::default()).unwrap();
+        nes.power_cycle();
+        let fresh = nes.registers();
+        assert_eq!((fresh.pc, fresh.x), (0xC000, 0));
+        assert_eq!(nes.bus.peek_byte(0x0300), 0);
+        assert!(nes.input_device_mut::<Zapper>(1).is_some());
+    }
+}
@@ -0,0 +1,177 @@
+// Four player adapters. A read shifts out 24 bits per port: two controllers,
+// then a signature games use to detect the adapter.
+//
+// The NES Four Score (and the wireless Satellite) goes in both controller
+// ports and answers on D0. Port 1's half carries players 1 and 3, port 2's
+// players 2 and 4.
+//
+// The Hori adapter sits on the Famicom expansion port instead and answers on
+// D1, leaving players 1 and 2 on the controllers in the ports. $4016 reads
+// player 3 and $4017 player 4, with nothing in the second controller's slot
+// and the two signatures swapped.
+
+use super::input::{ControllerState, ExpansionDevice, InputDevice};
+
+// Already in shift order: $4016 reads 0,0,0,1,0,0,0,0 after the controllers
+const SIGNATURES: [u8; 2] = [0x08, 0x04];
+
+fn report(first: ControllerState, second: ControllerState, signature: u8) -> u32 {
+    first.bits() as u32 | (second.bits() as u32) << 8 | (signature as u32) << 16
+}
+
+// Reads 1 once all 24 bits are out
+fn shift_out(shift_register: &mut u32) -> u8 {
+    let bit = (*shift_register & 0x01) as u8;
+    *shift_register = (*shift_register >> 1) | 0x80_0000;
+    bit
+}
+
+// One per port
+pub struct Multitap {
+    // 0 for $4016, 1 for $4017
+    port: usize,
+    states: [ControllerState; 2],
+    shift_register: u32,
+    strobe: bool,
+}
+
+impl Multitap {
+    pub fn new(port: usize) -> Self {
+        Multitap {
+            port,
+            states: [ControllerState::default(); 2],
+            shift_register: 0,
+            strobe: false,
+        }
+    }
+
+    // Which of the two controllers on this port, 0 for players 1 and 2.
+    // Anything past the second is ignored
+    pub fn set_state(&mut self, controller: usize, state: ControllerState) {
+        if let Some(held) = self.states.get_mut(controller) {
+            *held = state
+        }
+    }
+
+    fn poll(&mut self) {
+        self.shift_register = report(self.states[0], self.states[1], SIGNATURES[self.port]);
+    }
+}
+
+impl InputDevice for Multitap {
+    fn write(&mut self, val: u8) {
+        self.strobe = val & 0x01 != 0;
+        if self.strobe {
+            self.poll()
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            self.poll();
+        }
+        shift_out(&mut self.shift_register)
+    }
+}
+
+#[derive(Default)]
+pub struct HoriAdapter {
+    // Players 3 and 4
+    states: [ControllerState; 2],
+    shift_registers: [u32; 2],
+    strobe: bool,
+}
+
+impl HoriAdapter {
+    // 0 for player 3, 1 for player 4. Anything else is ignored
+    pub fn set_state(&mut self, controller: usize, state: ControllerState) {
+        if let Some(held) = self.states.get_mut(controller) {
+            *held = state
+        }
+    }
+
+    fn poll(&mut self) {
+        for (port, shift_register) in self.shift_registers.iter_mut().enumerate() {
+            let signature = SIGNATURES[1 - port];
+            *shift_register = report(self.states[port], ControllerState::default(), signature);
+        }
+    }
+}
+
+impl ExpansionDevice for HoriAdapter {
+    fn write(&mut self, val: u8) {
+        self.strobe = val & 0x01 != 0;
+        if self.strobe {
+            self.poll()
+        }
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        if self.strobe {
+            self.poll();
+        }
+        match self.shift_registers.get_mut(port) {
+            Some(shift_register) => shift_out(shift_register) << 1,
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::input::{ControllerState, ExpansionDevice, InputDevice};
+    use super::{HoriAdapter, Multitap};
+
+    fn read_all(tap: &mut Multitap) -> Vec<u8> {
+        tap.write(1);
+        tap.write(0);
+        (0..26).map(|_| tap.read()).collect()
+    }
+
+    #[test]
+    fn test_multitap() {
+        let mut tap = Multitap::new(0);
+        let a = ControllerState {
+            a: true,
+            ..Default::default()
+        };
+        let right = ControllerState {
+            right: true,
+            ..Default::default()
+        };
+        tap.set_state(0, a);
+        tap.set_state(1, right);
+        let mut expected = vec![0; 26];
+        expected[0] = 1;
+        expected[15] = 1;
+        expected[19] = 1;
+        expected[24] = 1;
+        expected[25] = 1;
+        assert_eq!(read_all(&mut tap), expected);
+
+        let mut tap = Multitap::new(1);
+        let bits = read_all(&mut tap);
+        assert_eq!(bits.iter().position(|&b| b == 1), Some(18));
+    }
+
+    #[test]
+    fn test_hori() {
+        let mut hori = HoriAdapter::default();
+        let a = ControllerState {
+            a: true,
+            ..Default::default()
+        };
+        hori.set_state(0, a);
+        hori.set_state(2, a);
+        hori.write(1);
+        hori.write(0);
+        // Same protocol on D1, signatures swapped
+        let bits: Vec<u8> = (0..25).map(|_| hori.read(0)).collect();
+        let set: Vec<usize> = (0..25).filter(|&i| bits[i] != 0).collect();
+        assert_eq!(set, [0, 18, 24]);
+        assert!(bits.iter().all(|&b| b & !0x02 == 0));
+        let bits: Vec<u8> = (0..25).map(|_| hori.read(1)).collect();
+        let set: Vec<usize> = (0..25).filter(|&i| bits[i] != 0).collect();
+        assert_eq!(set, [19, 24]);
+    }
+}
@@ -0,0 +1,134 @@
+// Power Pad floor mat, twelve buttons numbered as printed on side B:
+//
+//     1  2  3  4
+//     5  6  7  8
+//     9 10 11 12
+//
+// The NES version goes in port 2 and shifts out two streams at once, eight
+// buttons on D4 and four on D3. The Famicom's Family Trainer is the same mat
+// on the expansion port, scanned as a matrix: $4016 bits 0 to 2 pick rows
+// (a 0 selects) and $4017 D1 to D4 read that row's buttons, 0 when pressed.
+
+use super::input::{ExpansionDevice, InputDevice};
+
+pub const POWER_PAD_BUTTONS: usize = 12;
+
+// Button numbers in the order they're shifted out
+const D4_ORDER: [usize; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const D3_ORDER: [usize; 4] = [4, 3, 12, 8];
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct PowerPadState {
+    // Button 1 first
+    pub buttons: [bool; POWER_PAD_BUTTONS],
+}
+
+impl PowerPadState {
+    fn bits(&self, order: &[usize]) -> u8 {
+        order
+            .iter()
+            .enumerate()
+            .filter(|(_, button)| self.buttons[**button - 1])
+            .fold(0, |val, (bit, _)| val | 1 << bit)
+    }
+}
+
+#[derive(Default)]
+pub struct PowerPad {
+    state: PowerPadState,
+    d4_register: u8,
+    d3_register: u8,
+    strobe: bool,
+    // Family Trainer row select from the last $4016 write
+    rows: u8,
+}
+
+impl PowerPad {
+    pub fn set_state(&mut self, state: PowerPadState) {
+        self.state = state
+    }
+
+    fn poll(&mut self) {
+        self.d4_register = self.state.bits(&D4_ORDER);
+        // Reads 1 after the four buttons
+        self.d3_register = self.state.bits(&D3_ORDER) | 0xF0;
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn write(&mut self, val: u8) {
+        self.strobe = val & 0x01 != 0;
+        if self.strobe {
+            self.poll()
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            self.poll();
+        }
+        let val = (self.d4_register & 0x01) << 4 | (self.d3_register & 0x01) << 3;
+        self.d4_register = self.d4_register >> 1 | 0x80;
+        self.d3_register = self.d3_register >> 1 | 0x80;
+        val
+    }
+}
+
+impl ExpansionDevice for PowerPad {
+    fn write(&mut self, val: u8) {
+        self.rows = val;
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        if port == 0 {
+            return 0;
+        }
+        let mut val = 0x1E;
+        // Bit 2 selects buttons 1 to 4, bit 0 buttons 9 to 12
+        for (row, select) in [0x04, 0x02, 0x01].into_iter().enumerate() {
+            if self.rows & select != 0 {
+                continue;
+            }
+            for column in 0..4 {
+                if self.state.buttons[row * 4 + column] {
+                    val &= !(0x02 << column);
+                }
+            }
+        }
+        val
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::input::{ExpansionDevice, InputDevice};
+    use super::{PowerPad, PowerPadState};
+
+    #[test]
+    fn test_power_pad() {
+        let mut pad = PowerPad::default();
+        let mut state = PowerPadState::default();
+        // Buttons 1, 8 and 12
+        state.buttons[0] = true;
+        state.buttons[7] = true;
+        state.buttons[11] = true;
+        pad.set_state(state);
+
+        InputDevice::write(&mut pad, 1);
+        InputDevice::write(&mut pad, 0);
+        let reads: Vec<u8> = (0..9).map(|_| InputDevice::read(&mut pad)).collect();
+        assert_eq!(
+            reads,
+            [0x00, 0x10, 0x08, 0x08, 0x08, 0x08, 0x08, 0x08, 0x18]
+        );
+
+        // Rows 1 and 3 selected
+        ExpansionDevice::write(&mut pad, 0x02);
+        assert_eq!(ExpansionDevice::read(&mut pad, 0), 0);
+        assert_eq!(ExpansionDevice::read(&mut pad, 1), 0x1E & !0x02 & !0x10);
+        ExpansionDevice::write(&mut pad, 0x05);
+        assert_eq!(ExpansionDevice::read(&mut pad, 1), 0x1E & !0x10);
+        ExpansionDevice::write(&mut pad, 0x07);
+        assert_eq!(ExpansionDevice::read(&mut pad, 1), 0x1E);
+    }
+}
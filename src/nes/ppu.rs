@@ -1,13 +1,39 @@
-use std::{cell::RefCell, rc::Rc};
-
-use super::{cartridge::Cartridge, cartridge::Mirroring};
+use super::{
+    cartridge::Cartridge,
+    cartridge::Mirroring,
+    input::Beam,
+    state::{SaveState, StateReader, StateWriter},
+};
 
 // use eframe::glow::MAX_FRAGMENT_ATOMIC_COUNTERS;
 // use iced::alignment::Horizontal;
 use image::{self, RgbImage};
 // use show_image;
 
-static SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
+// NTSC timing, ignoring the dot skipped on odd frames
+const DOTS_PER_SCANLINE: u64 = 341;
+const SCANLINES_PER_FRAME: u64 = 262;
+
+// RGB for each of the 64 colour ids
+pub type Palette = [(u8, u8, u8); 64];
+
+// Reads a .pal file: 64 RGB triples, optionally followed by the emphasis
+// variants, which we don't use
+pub fn parse_pal(bytes: &[u8]) -> Result<Palette, String> {
+    if bytes.len() != 64 * 3 && bytes.len() != 512 * 3 {
+        return Err(format!(
+            "Palette files are 192 or 1536 bytes, not {}",
+            bytes.len()
+        ));
+    }
+    let mut palette = [(0, 0, 0); 64];
+    for (color, rgb) in palette.iter_mut().zip(bytes.chunks(3)) {
+        *color = (rgb[0], rgb[1], rgb[2]);
+    }
+    Ok(palette)
+}
+
+pub static SYSTEM_PALETTE: Palette = [
     (0x80, 0x80, 0x80),
     (0x00, 0x3D, 0xA6),
     (0x00, 0x12, 0xB0),
@@ -74,6 +100,66 @@ static SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
     (0x11, 0x11, 0x11),
 ];
 
+// Bits on the PPU's I/O bus fade to 0 about 600ms after last being driven
+const OPEN_BUS_DECAY_DOTS: u64 = 36 * SCANLINES_PER_FRAME * DOTS_PER_SCANLINE;
+
+// The PPU's I/O bus, which holds the last value driven on it. Reads from
+// write-only registers see it, as do the bits a register doesn't drive
+#[derive(Default)]
+struct OpenBus {
+    value: u8,
+    refreshed: [u64; 8], // Dot each bit was last driven
+}
+impl OpenBus {
+    fn read(&mut self, now: u64) -> u8 {
+        for (bit, refreshed) in self.refreshed.iter().enumerate() {
+            if now.saturating_sub(*refreshed) > OPEN_BUS_DECAY_DOTS {
+                self.value &= !(1 << bit);
+            }
+        }
+        self.value
+    }
+
+    // Drives the bits in mask, returning what a read sees
+    fn drive(&mut self, val: u8, mask: u8, now: u64) -> u8 {
+        self.read(now);
+        self.value = (self.value & !mask) | (val & mask);
+        for (bit, refreshed) in self.refreshed.iter_mut().enumerate() {
+            if mask & (1 << bit) != 0 {
+                *refreshed = now;
+            }
+        }
+        self.value
+    }
+}
+
+// How much an emphasis bit darkens the two colours it doesn't name
+const EMPHASIS_DIM: f32 = 0.75;
+
+// The palette under each combination of the PPUMASK emphasis bits, red, green
+// and blue from bit 0. The blacks in the last two columns aren't affected
+fn emphasis_palettes(palette: &Palette) -> [Palette; 8] {
+    let mut palettes = [*palette; 8];
+    for (emphasis, tinted) in palettes.iter_mut().enumerate() {
+        let [red, green, blue] = [1, 2, 4].map(|bit| emphasis & bit != 0);
+        let dim = |c: u8, others: bool| {
+            if others {
+                (c as f32 * EMPHASIS_DIM) as u8
+            } else {
+                c
+            }
+        };
+        for (id, (r, g, b)) in tinted.iter_mut().enumerate() {
+            if id & 0x0E != 0x0E {
+                *r = dim(*r, green || blue);
+                *g = dim(*g, red || blue);
+                *b = dim(*b, red || green);
+            }
+        }
+    }
+    palettes
+}
+
 #[derive(Clone, Copy)]
 enum VramInc {
     Inc1,
@@ -124,6 +210,24 @@ impl From<u8> for PpuCtrl {
         }
     }
 }
+// Loses the sprite table bit in 8x16 mode, where it isn't used
+impl From<&PpuCtrl> for u8 {
+    fn from(c: &PpuCtrl) -> Self {
+        (if let VramInc::Inc32 = c.vram_inc {
+            0x04
+        } else {
+            0
+        }) | (if c.sprite_pt_addr != 0 { 0x08 } else { 0 })
+            | (if c.bg_pt_addr != 0 { 0x10 } else { 0 })
+            | (if let SpriteSize::Sprite8x16 = c.sprite_size {
+                0x20
+            } else {
+                0
+            })
+            | (if c.ext_out { 0x40 } else { 0 })
+            | (if c.nmi { 0x80 } else { 0 })
+    }
+}
 impl Default for PpuCtrl {
     fn default() -> Self {
         Self::from(0)
@@ -138,8 +242,8 @@ struct PpuMask {
     show_bg: bool,
     show_sprites: bool,
     emph_red: bool,
-    emph_blue: bool,
     emph_green: bool,
+    emph_blue: bool,
 }
 impl From<u8> for PpuMask {
     fn from(value: u8) -> Self {
@@ -150,11 +254,23 @@ impl From<u8> for PpuMask {
             show_bg: (value & 0x08) != 0,
             show_sprites: (value & 0x10) != 0,
             emph_red: (value & 0x20) != 0,
-            emph_blue: (value & 0x40) != 0,
-            emph_green: (value & 0x80) != 0,
+            emph_green: (value & 0x40) != 0,
+            emph_blue: (value & 0x80) != 0,
         }
     }
 }
+impl From<&PpuMask> for u8 {
+    fn from(m: &PpuMask) -> Self {
+        (m.grayscale as u8)
+            | (m.show_left_bg as u8) << 1
+            | (m.show_left_sprite as u8) << 2
+            | (m.show_bg as u8) << 3
+            | (m.show_sprites as u8) << 4
+            | (m.emph_red as u8) << 5
+            | (m.emph_green as u8) << 6
+            | (m.emph_blue as u8) << 7
+    }
+}
 impl Default for PpuMask {
     fn default() -> Self {
         Self::from(0)
@@ -412,7 +528,11 @@ fn map_ppu_addr(addr: u16) -> PpuAddress {
     match addr {
         0x0000..=0x1FFF => PpuAddress::Chr(addr),
         0x2000..=0x3EFF => PpuAddress::Nametable((addr - 0x2000) % 0x1000),
-        0x3F00..=0x3FFF => PpuAddress::Pallette((addr - 0x3F00) % 0x20),
+        // The sprite palettes' transparent entries are the background ones
+        0x3F00..=0x3FFF => match (addr - 0x3F00) % 0x20 {
+            offset @ (0x10 | 0x14 | 0x18 | 0x1C) => PpuAddress::Pallette(offset - 0x10),
+            offset => PpuAddress::Pallette(offset),
+        },
         _ => panic!("Invalid ppu addr"),
     }
 }
@@ -465,6 +585,42 @@ impl Pipeline {
     }
 }
 
+// A sprite fetched for the line being drawn
+#[derive(Default, Clone, Copy)]
+struct SpriteSlot {
+    pattern_lo: u8,
+    pattern_hi: u8,
+    attr: u8,
+    x: u8,
+}
+
+#[derive(Default, Clone, Copy, PartialEq)]
+enum EvalPhase {
+    #[default]
+    Search,
+    Overflow,
+    Done,
+}
+impl From<u8> for EvalPhase {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => EvalPhase::Overflow,
+            2 => EvalPhase::Done,
+            _ => EvalPhase::Search,
+        }
+    }
+}
+
+// Sprite evaluation for the next line, in progress
+#[derive(Default)]
+struct SpriteEval {
+    phase: EvalPhase,
+    latch: u8, // Last byte on the OAM bus, what $2004 reads while rendering
+    sec_addr: usize,
+    copying: u8,
+    sprite0: bool,
+}
+
 #[derive(Default)]
 struct PpuState {
     frame: u32,
@@ -473,20 +629,27 @@ struct PpuState {
     pipeline: Pipeline,
     num_2oam: usize,
     sprite0_det: bool,
+    sprites: [SpriteSlot; 8],
+    eval: SpriteEval,
+    vbl_suppressed: bool,
+    // The NMI line as the CPU sees it. It stays up after a $2002 read clears
+    // vblank, as the CPU has latched the edge by then
+    nmi_output: bool,
 }
 
 pub struct Ppu {
     reg: PpuRegisters,
-    cartridge: Option<Rc<RefCell<Box<dyn Cartridge>>>>,
     nametable1: [u8; 0x400],
     nametable2: [u8; 0x400],
     oam: [u8; 256],
     pallette: [u8; 0x20],
     state: PpuState,
     fb: RgbImage,
+    palettes: [Palette; 8], // By emphasis bits
     mirroring: Mirroring,
-    secondary_oam: [[u8; 4]; 8],
+    secondary_oam: [u8; 32],
     read_buf: u8,
+    io_bus: OpenBus,
 }
 
 struct SpriteAttributes {
@@ -509,7 +672,6 @@ impl From<u8> for SpriteAttributes {
 impl Ppu {
     pub fn new() -> Self {
         Self {
-            cartridge: None,
             reg: PpuRegisters::default(),
             nametable1: [0; 0x400],
             nametable2: [0; 0x400],
@@ -517,59 +679,82 @@ impl Ppu {
             pallette: [0; 0x20],
             state: PpuState::default(),
             fb: RgbImage::new(256, 240),
+            palettes: emphasis_palettes(&SYSTEM_PALETTE),
             mirroring: Mirroring::Horizontal,
-            secondary_oam: [[0; 4]; 8],
+            secondary_oam: [0; 32],
             read_buf: 0,
+            io_bus: OpenBus::default(),
         }
     }
 
-    #[allow(clippy::let_and_return)]
-    pub fn read_reg(&mut self, addr: u16) -> u8 {
-        // TODO: invalid reads?
-        let ret = match addr {
-            0x00 => panic!(),
-            0x01 => panic!(),
+    pub fn read_reg(&mut self, addr: u16, cart: &mut dyn Cartridge) -> u8 {
+        let now = self.beam().dots;
+        match addr & 0x07 {
             0x02 => {
-                // TODO: unlatching, clearing
-                let ret: u8 = (&self.reg.ppustatus).into();
+                // Reading a dot before vblank starts means the flag, and so
+                // the NMI, never comes this frame. On the dot it starts or
+                // the one after, the flag reads set but the NMI is lost
+                match self.position() {
+                    (241, 0) => self.state.vbl_suppressed = true,
+                    (241, 1) | (241, 2) => self.state.nmi_output = false,
+                    _ => {}
+                }
+                let status: u8 = (&self.reg.ppustatus).into();
                 self.reg.ppustatus.vblank = false;
                 self.reg.internal.unlatch();
-                ret
+                self.io_bus.drive(status, 0xE0, now)
+            }
+            0x04 => {
+                let val = self.read_oam_data();
+                self.io_bus.drive(val, 0xFF, now)
             }
-            0x03 => panic!(),
-            0x04 => todo!(), // OAMDATA,
-            0x05 => panic!(),
-            0x06 => panic!(),
             0x07 => {
-                let ret = self.read_buf;
+                // PPUDATA
                 let addr = self.reg.internal.get_addr();
-                self.read_buf = self.read_ppu_byte(addr);
-                // println!("read {:2x} from {:4x} (buf {:x})",self.read_buf,addr,ret);
+                let ret = if addr >= 0x3F00 {
+                    // Palette reads skip the buffer, which gets the
+                    // nametable byte underneath instead
+                    self.read_buf = self.read_ppu_byte(addr - 0x1000, cart);
+                    let mut color = self.read_ppu_byte(addr, cart);
+                    if self.reg.ppumask.grayscale {
+                        color &= 0x30;
+                    }
+                    self.io_bus.drive(color, 0x3F, now)
+                } else {
+                    let ret = self.read_buf;
+                    self.read_buf = self.read_ppu_byte(addr, cart);
+                    self.io_bus.drive(ret, 0xFF, now)
+                };
                 self.reg.internal.inc_addr(self.reg.ppuctrl.vram_inc);
-                // println!("now {:4x}",self.reg.internal.get_addr());
                 ret
-            } // PPUDATA
-
-            _ => panic!("Invalid ppu read from {:x}", addr),
-        };
-        //println!("R PPU REG 0x20{:2x} => {:2x}", addr, ret);
-        ret
+            }
+            // Write-only registers read back what's left on the bus
+            _ => self.io_bus.read(now),
+        }
     }
 
-    pub fn write_reg(&mut self, addr: u16, val: u8) {
+    pub fn write_reg(&mut self, addr: u16, val: u8, cart: &mut dyn Cartridge) {
         // println!("W PPU REG 0x20{:2x} => {:2x}", addr, val);
+        let now = self.beam().dots;
+        self.io_bus.drive(val, 0xFF, now);
         match addr {
             0x00 => {
                 self.reg.internal.write_nt(val);
-                self.reg.ppuctrl = val.into()
+                self.reg.ppuctrl = val.into();
+                // Enabling NMI during vblank raises the line again
+                self.state.nmi_output = self.reg.ppuctrl.nmi && self.reg.ppustatus.vblank;
             }
             0x01 => self.reg.ppumask = val.into(),
             0x02 => {} // Can't write to status
             0x03 => self.reg.oamaddr = val,
             0x04 => {
-                // OAMDATA,
-                self.oam[self.reg.oamaddr as usize] = val;
-                self.reg.oamaddr = self.reg.oamaddr.wrapping_add(1)
+                // OAMDATA, which only bumps the sprite index while rendering
+                if self.rendering_oam() {
+                    self.reg.oamaddr = self.reg.oamaddr.wrapping_add(4);
+                } else {
+                    self.oam[self.reg.oamaddr as usize] = val;
+                    self.reg.oamaddr = self.reg.oamaddr.wrapping_add(1)
+                }
             }
             0x05 => self.reg.internal.write_scroll(val),
             0x06 => self.reg.internal.write_addr(val),
@@ -577,7 +762,7 @@ impl Ppu {
                 // PPUDATA
                 let addr = self.reg.internal.get_addr();
                 // println!("W {:2x} to ppu {:4x}",val, addr);
-                self.write_ppu_byte(addr, val);
+                self.write_ppu_byte(addr, val, cart);
                 self.reg.internal.inc_addr(self.reg.ppuctrl.vram_inc);
                 // println!("now {:4x}",self.reg.internal.get_addr());
             }
@@ -586,16 +771,34 @@ impl Ppu {
         }
     }
 
-    pub fn write_ppu_byte(&mut self, addr: u16, val: u8) {
+    fn rendering_enabled(&self) -> bool {
+        self.reg.ppumask.show_bg || self.reg.ppumask.show_sprites
+    }
+
+    // Whether sprite evaluation owns OAM right now
+    fn rendering_oam(&self) -> bool {
+        self.rendering_enabled() && (self.state.scanline < 240 || self.state.scanline == 261)
+    }
+
+    fn read_oam_data(&self) -> u8 {
+        if self.rendering_oam() {
+            return self.state.eval.latch;
+        }
+        let val = self.oam[self.reg.oamaddr as usize];
+        // The attribute byte has no bits 2-4
+        if self.reg.oamaddr % 4 == 2 {
+            val & 0xE3
+        } else {
+            val
+        }
+    }
+
+    pub fn write_ppu_byte(&mut self, addr: u16, val: u8, cart: &mut dyn Cartridge) {
         let parsed_addr = map_ppu_addr(addr);
         match parsed_addr {
             PpuAddress::Chr(offset) => {
                 //panic!("PPU writing to chr"),
-                self.cartridge
-                    .as_ref()
-                    .unwrap()
-                    .borrow_mut()
-                    .write_byte_chr(offset, val);
+                cart.write_byte_chr(offset, val);
             }
             PpuAddress::Nametable(offset) => {
                 let nt = match self.mirroring {
@@ -617,22 +820,14 @@ impl Ppu {
                 };
                 nt[offset as usize & 0x3FF] = val
             }
-            PpuAddress::Pallette(offset) => {
-                let offset = offset as usize;
-                self.pallette[offset] = val;
-                if offset == 0x10 {
-                    self.pallette[0] = val;
-                }
-            }
+            PpuAddress::Pallette(offset) => self.pallette[offset as usize] = val,
         }
     }
 
-    pub fn read_ppu_byte(&self, addr: u16) -> u8 {
+    pub fn read_ppu_byte(&self, addr: u16, cart: &dyn Cartridge) -> u8 {
         let parsed_addr = map_ppu_addr(addr);
         match parsed_addr {
-            PpuAddress::Chr(offset) => {
-                self.cartridge.as_ref().unwrap().borrow().get_chr()[offset as usize]
-            }
+            PpuAddress::Chr(offset) => cart.get_chr()[offset as usize],
             PpuAddress::Nametable(offset) => {
                 let nt = match self.mirroring {
                     Mirroring::Horizontal => {
@@ -654,14 +849,7 @@ impl Ppu {
                 let offset = offset % 0x400;
                 nt[offset as usize]
             }
-            PpuAddress::Pallette(offset) => {
-                let offset = offset as usize;
-                if offset == 0x10 {
-                    self.pallette[0]
-                } else {
-                    self.pallette[offset]
-                }
-            }
+            PpuAddress::Pallette(offset) => self.pallette[offset as usize],
         }
     }
 
@@ -672,10 +860,10 @@ impl Ppu {
         }
     }
 
-    fn fetch_nametable(&self) -> u8 {
+    fn fetch_nametable(&self, cart: &dyn Cartridge) -> u8 {
         //, x: usize, y: usize) -> u8 {
         let addr = self.reg.internal.get_tile_addr();
-        self.read_ppu_byte(addr)
+        self.read_ppu_byte(addr, cart)
     }
     fn read_nametable(&self, x: usize, y: usize) -> u8 {
         let tile_x = x / 8;
@@ -706,10 +894,10 @@ impl Ppu {
 
         nt[tile_id] // TODO: base NT address
     }
-    fn fetch_attribute_table(&self) -> u8 {
+    fn fetch_attribute_table(&self, cart: &dyn Cartridge) -> u8 {
         let addr = self.reg.internal.get_attr_addr();
         // TODO fine x
-        let attr_byte = self.read_ppu_byte(addr);
+        let attr_byte = self.read_ppu_byte(addr, cart);
 
         let sub_x = (self.reg.internal.v.coarse_x & 0x02) >> 1;
         let sub_y = (self.reg.internal.v.coarse_y & 0x02) >> 1;
@@ -781,51 +969,156 @@ impl Ppu {
         (attr_byte & (0x3 << shift)) >> shift
     }
 
-    fn sprite_evaluation(&mut self) {
-        for s in self.secondary_oam.iter_mut() {
-            s.fill(0);
+    fn sprite_height(&self) -> u8 {
+        match self.reg.ppuctrl.sprite_size {
+            SpriteSize::Sprite8x8 => 8,
+            SpriteSize::Sprite8x16 => 16,
+        }
+    }
+
+    // Sprite work for one dot of a rendering scanline: secondary OAM is
+    // cleared over dots 1-64, filled with the next line's sprites over dots
+    // 65-256, and the pattern data for them is fetched over dots 257-320.
+    // The evaluation walks OAM through OAMADDR, so a non-zero OAMADDR
+    // starts it part way through.
+    fn sprite_cycle(&mut self, cart: &dyn Cartridge) {
+        let cycle = self.state.cycle;
+        let visible = self.state.scanline < 240;
+        match cycle {
+            1..=64 if visible => {
+                self.state.eval.latch = 0xFF;
+                if cycle.is_multiple_of(2) {
+                    self.secondary_oam[cycle / 2 - 1] = 0xFF;
+                }
+            }
+            65..=256 if visible => {
+                if cycle == 65 {
+                    self.state.eval = SpriteEval::default();
+                }
+                if cycle % 2 == 1 {
+                    self.state.eval.latch = self.oam[self.reg.oamaddr as usize];
+                } else {
+                    self.sprite_evaluation(cycle == 66);
+                }
+            }
+            257..=320 => {
+                self.reg.oamaddr = 0;
+                self.fetch_sprite(cycle - 257, cart);
+            }
+            321..=340 | 0 => self.state.eval.latch = self.secondary_oam[0],
+            _ => {}
         }
-        self.state.num_2oam = 0;
+    }
 
+    // One write slot of sprite evaluation, with the byte read on the dot
+    // before in the latch
+    fn sprite_evaluation(&mut self, first: bool) {
         let y = self.state.scanline + 1; // Assess next row
+        let sprite_height = self.sprite_height() as usize;
+        let in_range = |sprite_y: u8| {
+            let sprite_y = sprite_y as usize + 1;
+            sprite_y <= y && y < sprite_y + sprite_height
+        };
 
-        // for s in self.oam.chunks(4) {
-        //     let sprite_y = s[0] as usize + 1;
-        //     // if (sprite_y..sprite_y+8).contains(&y) { // TODO: 8x16 sprites
-        //     if sprite_y <= y && y < sprite_y+8 {
-        //         self.secondary_oam[self.state.num_2oam].copy_from_slice(s);
-        //         self.state.num_2oam += 1;
-        //         if self.state.num_2oam >= 8 {
-        //             break;
-        //         }
-        //     }
-        //     // TODO: sprite overflow
-        // }
-
-        self.state.sprite0_det = false;
-        for s_id in 0..64 {
-            let addr = s_id * 4;
-            let sprite_y = self.oam[addr] as usize + 1;
-            // if (sprite_y..sprite_y+8).contains(&y) { // TODO: 8x16 sprites
-            let sprite_height = match self.reg.ppuctrl.sprite_size {
-                SpriteSize::Sprite8x8 => 8,
-                SpriteSize::Sprite8x16 => 16,
-            };
-            if sprite_y <= y && y < sprite_y + sprite_height {
-                self.secondary_oam[self.state.num_2oam].copy_from_slice(&self.oam[addr..addr + 4]);
-                if s_id == 0 {
-                    self.state.sprite0_det = true;
+        let eval = &mut self.state.eval;
+        let addr = self.reg.oamaddr;
+        let (next, wrapped) = match eval.phase {
+            EvalPhase::Search => {
+                self.secondary_oam[eval.sec_addr] = eval.latch;
+                if eval.copying > 0 || in_range(eval.latch) {
+                    if eval.copying == 0 {
+                        eval.copying = 3;
+                        eval.sprite0 = first;
+                    } else {
+                        eval.copying -= 1;
+                    }
+                    eval.sec_addr += 1;
+                    if eval.sec_addr == self.secondary_oam.len() {
+                        eval.phase = EvalPhase::Overflow;
+                    }
+                    addr.overflowing_add(1)
+                } else {
+                    addr.overflowing_add(4)
                 }
-                self.state.num_2oam += 1;
-                if self.state.num_2oam >= 8 {
-                    break;
+            }
+            // Once secondary OAM is full the hardware keeps looking for a
+            // ninth sprite, but steps the byte index along with the sprite
+            // index on every miss, so it reads tiles, attributes and x
+            // positions as y
+            EvalPhase::Overflow => {
+                if eval.copying > 0 {
+                    eval.copying -= 1;
+                    if eval.copying == 0 {
+                        eval.phase = EvalPhase::Done;
+                    }
+                    addr.overflowing_add(1)
+                } else if in_range(eval.latch) {
+                    self.reg.ppustatus.sprite_overflow = true;
+                    eval.copying = 3;
+                    addr.overflowing_add(1)
+                } else {
+                    let (n, wrapped) = (addr & 0xFC).overflowing_add(4);
+                    (n | (addr.wrapping_add(1) & 0x03), wrapped)
                 }
             }
+            EvalPhase::Done => ((addr & 0xFC).wrapping_add(4), false),
+        };
+        if wrapped {
+            eval.phase = EvalPhase::Done;
+        }
+        self.reg.oamaddr = next;
+    }
+
+    // One dot of the sprite fetches, eight per sprite: the four bytes from
+    // secondary OAM and then the two pattern bytes
+    fn fetch_sprite(&mut self, dot: usize, cart: &dyn Cartridge) {
+        let slot = dot / 8;
+        let step = dot % 8;
+        let base = slot * 4;
+        self.state.eval.latch = self.secondary_oam[base + step.min(3)];
+        if dot == 0 {
+            // The pre-render line doesn't evaluate, so line 0 has no sprites
+            if self.state.scanline == 261 {
+                self.state.num_2oam = 0;
+                self.state.sprite0_det = false;
+            } else {
+                self.state.num_2oam = self.state.eval.sec_addr / 4;
+                self.state.sprite0_det = self.state.eval.sprite0;
+            }
+        }
+        if step != 5 && step != 7 {
+            return;
+        }
+
+        let sprite_y = self.secondary_oam[base];
+        let chr_id = self.secondary_oam[base + 1];
+        let attr = SpriteAttributes::from(self.secondary_oam[base + 2]);
+        let height = self.sprite_height();
+        let mut row = (self.state.scanline as u8).wrapping_sub(sprite_y) % height;
+        if attr.flip_vert {
+            row = height - 1 - row;
+        }
+        let addr = self.sprite_pattern_addr(chr_id, row) + if step == 7 { 0x08 } else { 0 };
+        let mut pattern = self.read_ppu_byte(addr, cart);
+        if attr.flip_horz {
+            pattern = pattern.reverse_bits();
+        }
+        // Unused slots still fetch, from tile $FF, but draw nothing
+        if slot >= self.state.num_2oam {
+            pattern = 0;
+        }
+
+        let sprite = &mut self.state.sprites[slot];
+        if step == 5 {
+            sprite.pattern_lo = pattern;
+        } else {
+            sprite.pattern_hi = pattern;
+            sprite.attr = self.secondary_oam[base + 2];
+            sprite.x = self.secondary_oam[base + 3];
         }
-        // TODO: proper prefetching
     }
 
-    fn lookup_chr_bg(&self, chr_id: u8, row: u8) -> (u8, u8) {
+    fn lookup_chr_bg(&self, chr_id: u8, row: u8, cart: &dyn Cartridge) -> (u8, u8) {
         let chr_addr = {
             let mut chr_addr = self.reg.ppuctrl.bg_pt_addr as usize; // Which pattern table
             chr_addr += (chr_id as usize) << 4; // Which sprite
@@ -833,53 +1126,48 @@ impl Ppu {
             chr_addr
         };
 
-        let cart_binding = self.cartridge.as_ref().unwrap().borrow();
-        let chr = cart_binding.get_chr();
+        let chr = cart.get_chr();
 
         let chr_lo = chr[chr_addr];
         let chr_hi = chr[chr_addr + 0x08];
         (chr_hi, chr_lo)
     }
-    fn lookup_chr_sprite(&self, chr_id: u8, row: u8, col: u8) -> u8 {
-        let chr_addr = match self.reg.ppuctrl.sprite_size {
+    fn sprite_pattern_addr(&self, chr_id: u8, row: u8) -> u16 {
+        match self.reg.ppuctrl.sprite_size {
             SpriteSize::Sprite8x8 => {
-                // TODO: 8x16 sprites
-                let mut chr_addr = self.reg.ppuctrl.sprite_pt_addr as usize; // Which pattern table
-                chr_addr += (chr_id as usize) << 4; // Which sprite
-                chr_addr += row as usize; // Which row within the tile
+                let mut chr_addr = self.reg.ppuctrl.sprite_pt_addr; // Which pattern table
+                chr_addr += (chr_id as u16) << 4; // Which sprite
+                chr_addr += row as u16; // Which row within the tile
                 chr_addr
             }
             SpriteSize::Sprite8x16 => {
                 let mut chr_addr = if chr_id & 0x01 == 0 { 0x0000 } else { 0x1000 };
-                chr_addr += ((chr_id & 0xFE) as usize) << 4;
-                chr_addr += (row % 8) as usize;
+                chr_addr += ((chr_id & 0xFE) as u16) << 4;
+                chr_addr += (row % 8) as u16;
                 if row >= 8 {
                     chr_addr += 1 << 4
                 }
                 chr_addr
             }
-        };
-
-        let bit_num = 7 - (col % 8);
-        // let mem_binding = self.memory.borrow();
-        // let chr = self.cartridge.unwrap().borrow().get_chr();
-        let cart_binding = self.cartridge.as_ref().unwrap().borrow();
-        let chr = cart_binding.get_chr();
-        // let chr = mem_binding.get_chr();
-        let chr_lo = (chr[chr_addr] & (1 << bit_num)) >> bit_num;
-        let chr_hi = (chr[chr_addr + 0x08] & (1 << bit_num)) >> bit_num;
-        chr_hi << 1 | chr_lo
+        }
     }
 
-    pub fn run_cycle(&mut self) {
+    pub fn run_cycle(&mut self, cart: &mut dyn Cartridge) {
         if self.state.scanline >= 240 && self.state.scanline < 261 {
             return;
         }
 
         let cycle = self.state.cycle;
 
-        let enable = self.reg.ppumask.show_bg || self.reg.ppumask.show_sprites;
+        let enable = self.rendering_enabled();
         if enable {
+            // Rendering starting with OAMADDR at 8 or above copies that row
+            // of OAM over the first
+            if self.state.scanline == 261 && cycle == 1 && self.reg.oamaddr >= 8 {
+                let row = (self.reg.oamaddr & 0xF8) as usize;
+                self.oam.copy_within(row..row + 8, 0);
+            }
+            self.sprite_cycle(cart);
             if cycle == 257 {
                 self.reg.internal.inc_y();
             }
@@ -891,9 +1179,9 @@ impl Ppu {
             }
             if ((1..=257).contains(&cycle) && cycle % 8 == 1) || (cycle == 329) {
                 //|| cycle == 329 {
-                let chr_id = self.fetch_nametable();
-                let (pt_hi, pt_lo) = self.lookup_chr_bg(chr_id, self.reg.internal.v.fine_y);
-                let at = self.fetch_attribute_table();
+                let chr_id = self.fetch_nametable(cart);
+                let (pt_hi, pt_lo) = self.lookup_chr_bg(chr_id, self.reg.internal.v.fine_y, cart);
+                let at = self.fetch_attribute_table(cart);
                 // println!("{},{}: got at {:02x}",self.state.scanline, cycle, at);
                 self.state.pipeline.transfer(pt_hi, pt_lo, at);
                 self.reg.internal.inc_x();
@@ -908,7 +1196,7 @@ impl Ppu {
                 // println!("Rendering {},{}",x,y);
 
                 let (bg_val, bg_palette) = self.state.pipeline.read(self.reg.internal.x);
-                if x % 8 == 0 {
+                if x.is_multiple_of(8) {
                     // println!("read:{},{}",bg_val,bg_palette);
                 }
                 // {
@@ -943,35 +1231,16 @@ impl Ppu {
 
                 let sprite_data = {
                     let mut sprite_data = None;
-                    if y > 0 {
-                        for i in 0..self.state.num_2oam {
-                            let s = &self.secondary_oam[i];
-                            let sx = s[3] as usize;
-                            // if (sx..sx+8).contains(&x) {
-                            if sx <= x && x < sx + 8 {
-                                let sy = s[0] as usize + 1;
-                                let chr_id = s[1];
-                                let attr = SpriteAttributes::from(s[2]);
-                                // println!("x: {}, sx: {}", x, sx);
-                                let cx = match attr.flip_horz {
-                                    false => x - sx,
-                                    true => 7 - (x - sx),
-                                } as u8;
-                                // println!("y: {}, sy: {}", y, sy);
-                                let cy = match attr.flip_vert {
-                                    false => y - sy,
-                                    true => {
-                                        (match self.reg.ppuctrl.sprite_size {
-                                            SpriteSize::Sprite8x8 => 7,
-                                            SpriteSize::Sprite8x16 => 15,
-                                        }) - (y - sy)
-                                    }
-                                } as u8;
-                                let chr_val = self.lookup_chr_sprite(chr_id, cy, cx);
-                                if chr_val != 0 {
-                                    sprite_data = Some((i, chr_val, attr));
-                                    break;
-                                }
+                    for (i, s) in self.state.sprites[..self.state.num_2oam].iter().enumerate() {
+                        let sx = s.x as usize;
+                        if sx <= x && x < sx + 8 {
+                            let bit_num = 7 - (x - sx);
+                            let chr_lo = (s.pattern_lo >> bit_num) & 1;
+                            let chr_hi = (s.pattern_hi >> bit_num) & 1;
+                            let chr_val = chr_hi << 1 | chr_lo;
+                            if chr_val != 0 {
+                                sprite_data = Some((i, chr_val, SpriteAttributes::from(s.attr)));
+                                break;
                             }
                         }
                     }
@@ -993,59 +1262,47 @@ impl Ppu {
                     self.reg.ppumask.show_bg && (x >= 8 || self.reg.ppumask.show_left_bg);
                 let sprite_enable =
                     self.reg.ppumask.show_sprites && (x >= 8 || self.reg.ppumask.show_left_sprite);
+                // Clipped or hidden layers are transparent
+                let bg_val = if bg_enable { bg_val } else { 0 };
+                let (sprite_id, sprite_val, sprite_attr) = sprite_data;
+                let sprite_val = if sprite_enable { sprite_val } else { 0 };
+
+                let sprite0_hit = self.state.sprite0_det
+                    && sprite_id == 0
+                    && sprite_val != 0
+                    && bg_val != 0
+                    && x != 255;
+                if sprite0_hit {
+                    self.reg.ppustatus.sprite_0_hit = true;
+                }
 
-                let mut color_id = if !self.reg.ppumask.show_bg && !self.reg.ppumask.show_sprites {
-                    if (0x3f00..=0x3fff).contains(&self.reg.internal.get_addr()) {
-                        self.pallette[(self.reg.internal.get_addr() as usize - 0x3f00) % 0x20]
-                    } else {
-                        self.pallette[0] // TODO: correct?
-                    }
-                } else if sprite_enable {
-                    // Consider sprite
-                    let (sprite_id, sprite_val, sprite_attr) = sprite_data;
-                    if bg_enable && sprite_enable {
-                        let sprite0_hit = self.state.sprite0_det
-                            && sprite_id == 0
-                            && sprite_val != 0
-                            && bg_val != 0;
-                        if sprite0_hit {
-                            self.reg.ppustatus.sprite_0_hit = true;
-                            println!("Sprite0 hit! {} @ {},{}", sprite_id, x, y);
-                        }
-                    }
-
-                    if bg_enable {
-                        if sprite_val == 0 && bg_val == 0 {
-                            self.pallette[0]
-                        } else if bg_val == 0 {
-                            self.pallette
-                                [0x10 + sprite_attr.palette as usize * 4 + sprite_val as usize]
-                        } else if sprite_val == 0 || sprite_attr.bg_priority {
-                            self.pallette[4 * bg_palette as usize + bg_val as usize]
-                        } else {
-                            self.pallette
-                                [0x10 + sprite_attr.palette as usize * 4 + sprite_val as usize]
-                        }
+                let mut color_id = if !enable {
+                    // With rendering off the backdrop shows, or the palette
+                    // entry v points at
+                    let addr = self.reg.internal.get_addr();
+                    if addr >= 0x3F00 {
+                        self.read_ppu_byte(addr, cart)
                     } else {
-                        // TODO?
-                        self.pallette[0x10 + sprite_attr.palette as usize * 4 + sprite_val as usize]
+                        self.pallette[0]
                     }
-                } else {
-                    // No sprite
+                } else if sprite_val != 0 && (bg_val == 0 || !sprite_attr.bg_priority) {
+                    self.pallette[0x10 + sprite_attr.palette as usize * 4 + sprite_val as usize]
+                } else if bg_val != 0 {
                     self.pallette[4 * bg_palette as usize + bg_val as usize]
+                } else {
+                    self.pallette[0]
                 };
 
-                // Apply grayscale
                 if self.reg.ppumask.grayscale {
                     color_id &= 0x30;
                 }
-                // TODO: Apply color emphasis
 
                 // Render
                 if color_id > 0x3f {
                     println!("weird color {}", color_id)
                 }
-                let color = SYSTEM_PALETTE[(color_id & 0x3f) as usize];
+                let emphasis = (u8::from(&self.reg.ppumask) >> 5) as usize;
+                let color = self.palettes[emphasis][(color_id & 0x3f) as usize];
                 self.fb
                     .put_pixel(x as u32, y as u32, image::Rgb([color.0, color.1, color.2]));
 
@@ -1064,9 +1321,6 @@ impl Ppu {
                 //     //     println!("Color id {}, color {},{},{}",color_id, color.0, color.1, color.2);
                 //     // }
                 // }
-            } else if x == 257 && (0..=240).contains(&y) {
-                self.sprite_evaluation();
-                self.reg.oamaddr = 0; // TODO: technically when x= 257 until 320
             }
 
             // TODO: more correct timing with shift registers
@@ -1128,39 +1382,40 @@ impl Ppu {
         }
     }
 
-    pub fn advance_cycles(&mut self, cycles: u64) -> bool {
+    pub fn advance_cycles(&mut self, cycles: u64, cart: &mut dyn Cartridge) -> bool {
         let mut frame_complete = false;
         for _ in 0..cycles {
             self.state.cycle += 1;
 
             if self.state.scanline == 241 && self.state.cycle == 1 {
-                self.reg.ppustatus.vblank = true;
+                self.reg.ppustatus.vblank = !self.state.vbl_suppressed;
+                self.state.vbl_suppressed = false;
+                self.state.nmi_output = self.reg.ppuctrl.nmi && self.reg.ppustatus.vblank;
                 // TODO: Send interrupt
                 frame_complete = true;
                 //println!("Vblank! nmi: {}", self.reg.ppuctrl.nmi);
             }
             if self.state.scanline == 261 && self.state.cycle == 1 {
                 self.reg.ppustatus.vblank = false;
+                self.state.nmi_output = false;
                 self.reg.ppustatus.sprite_0_hit = false;
+                self.reg.ppustatus.sprite_overflow = false;
             }
             if self.state.cycle == 341
                 || (self.state.scanline == 261
                     && self.state.cycle == 340
-                    && self.state.frame % 2 == 1)
+                    && self.state.frame % 2 == 1
+                    && self.rendering_enabled())
             {
                 self.state.scanline += 1;
                 self.state.cycle = 0;
-                if [24, 32, 128].contains(&self.state.scanline) {
-                    println!("Starting Line {}", self.state.scanline);
-                }
             }
 
             if self.state.scanline > 261 {
                 self.state.frame += 1;
                 self.state.scanline = 0;
-                println!("Starting Line 0");
             }
-            self.run_cycle()
+            self.run_cycle(cart)
         }
         frame_complete
     }
@@ -1169,10 +1424,8 @@ impl Ppu {
         self.fb.clone()
     }
 
-    pub fn render_chr(&self) -> image::GrayImage {
-        let binding = self.cartridge.as_ref().unwrap().borrow();
-        // let chr_data = binding.get_chr();
-        let chr_data = binding.get_chr();
+    pub fn render_chr(&self, cart: &dyn Cartridge) -> image::GrayImage {
+        let chr_data = cart.get_chr();
         let mut chr_img = image::GrayImage::new(16 * 9, 32 * 9);
         for tilenum in 0..512 {
             let bit1 = &chr_data[tilenum * 16..tilenum * 16 + 8];
@@ -1203,7 +1456,7 @@ impl Ppu {
         chr_img.save("imgs/chr.bmp").unwrap();
         chr_img
     }
-    pub fn render_nt(&self) -> image::RgbImage {
+    pub fn render_nt(&self, cart: &dyn Cartridge) -> image::RgbImage {
         let size: (usize, usize) = match self.mirroring {
             Mirroring::Horizontal => (256, 480),
             Mirroring::Vertical => (512, 240),
@@ -1219,7 +1472,7 @@ impl Ppu {
                     // let palette_id = self.read_attribute_table(x, y);
 
                     // let chr_val = self.lookup_chr_bg(chr_id, y as u8 % 8, x as u8 % 8);
-                    let (chr_hi, chr_lo) = self.lookup_chr_bg(chr_id, y as u8 % 8);
+                    let (chr_hi, chr_lo) = self.lookup_chr_bg(chr_id, y as u8 % 8, cart);
                     let bit_num = 7 - (x % 8);
                     let chr_lo = (chr_lo & (1 << bit_num)) >> bit_num;
                     let chr_hi = (chr_hi & (1 << bit_num)) >> bit_num;
@@ -1240,7 +1493,7 @@ impl Ppu {
                 // } else if x == x_min + 255 || y == y_min + 239 {
                 //     (200,200,0)
                 } else {
-                    SYSTEM_PALETTE[(color_id & 0x3f) as usize]
+                    self.palettes[0][(color_id & 0x3f) as usize]
                 };
                 nt_img.put_pixel(x as u32, y as u32, image::Rgb([color.0, color.1, color.2]));
             }
@@ -1295,12 +1548,363 @@ impl Ppu {
         }
     }
 
+    // Reset clears the control registers, scroll and write latch. VRAM, OAM
+    // and the palette keep their contents
+    pub fn reset(&mut self) {
+        self.reg.ppuctrl = PpuCtrl::default();
+        self.state.nmi_output = false;
+        self.reg.ppumask = PpuMask::default();
+        self.reg.internal.t = VRamAddr::default();
+        self.reg.internal.x = 0;
+        self.reg.internal.unlatch();
+        self.read_buf = 0;
+    }
+
+    pub fn set_system_palette(&mut self, palette: &Palette) {
+        self.palettes = emphasis_palettes(palette);
+    }
+
+    pub fn beam(&self) -> Beam<'_> {
+        Beam {
+            frame: &self.fb,
+            scanline: self.state.scanline,
+            dot: self.state.cycle,
+            dots: (self.state.frame as u64 * SCANLINES_PER_FRAME + self.state.scanline as u64)
+                * DOTS_PER_SCANLINE
+                + self.state.cycle as u64,
+        }
+    }
+
+    // (scanline, dot)
+    pub fn position(&self) -> (usize, usize) {
+        (self.state.scanline, self.state.cycle)
+    }
+
+    // Address the next $2007 access goes to
+    pub fn vram_addr(&self) -> u16 {
+        self.reg.internal.get_addr()
+    }
+
     pub fn nmi_requested(&self) -> bool {
-        self.reg.ppuctrl.nmi && self.reg.ppustatus.vblank
+        self.state.nmi_output
+    }
+
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+}
+
+impl SaveState for Ppu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8((&self.reg.ppuctrl).into());
+        w.u8((&self.reg.ppumask).into());
+        w.u8((&self.reg.ppustatus).into());
+        w.u8(self.reg.oamaddr);
+        w.u16((&self.reg.internal.v).into());
+        w.u16((&self.reg.internal.t).into());
+        w.u8(self.reg.internal.x);
+        w.bool(self.reg.internal.w);
+
+        w.bytes(&self.nametable1);
+        w.bytes(&self.nametable2);
+        w.bytes(&self.oam);
+        w.bytes(&self.pallette);
+        w.bytes(&self.secondary_oam);
+        w.u8(self.read_buf);
+        w.mirroring(self.mirroring);
+
+        w.u32(self.state.frame);
+        w.usize(self.state.scanline);
+        w.usize(self.state.cycle);
+        let p = &self.state.pipeline;
+        w.u8(p.at_hi);
+        w.u8(p.at_lo);
+        w.u16(p.pt_lo);
+        w.u16(p.pt_hi);
+        w.u8(p.at_latch_hi);
+        w.u8(p.at_latch_lo);
+        w.usize(self.state.num_2oam);
+        w.bool(self.state.sprite0_det);
+        for sprite in &self.state.sprites {
+            w.u8(sprite.pattern_lo);
+            w.u8(sprite.pattern_hi);
+            w.u8(sprite.attr);
+            w.u8(sprite.x);
+        }
+        let eval = &self.state.eval;
+        w.u8(eval.phase as u8);
+        w.u8(eval.latch);
+        w.usize(eval.sec_addr);
+        w.u8(eval.copying);
+        w.bool(eval.sprite0);
+        w.bool(self.state.vbl_suppressed);
+        w.bool(self.state.nmi_output);
+        w.u8(self.io_bus.value);
+        for refreshed in self.io_bus.refreshed {
+            w.u64(refreshed);
+        }
+        w.bytes(self.fb.as_raw());
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.reg.ppuctrl = r.u8()?.into();
+        self.reg.ppumask = r.u8()?.into();
+        let status = r.u8()?;
+        self.reg.ppustatus = PpuStatus {
+            sprite_overflow: status & 0x20 != 0,
+            sprite_0_hit: status & 0x40 != 0,
+            vblank: status & 0x80 != 0,
+        };
+        self.reg.oamaddr = r.u8()?;
+        self.reg.internal.v = r.u16()?.into();
+        self.reg.internal.t = r.u16()?.into();
+        self.reg.internal.x = r.u8()?;
+        self.reg.internal.w = r.bool()?;
+
+        r.bytes_into(&mut self.nametable1)?;
+        r.bytes_into(&mut self.nametable2)?;
+        r.bytes_into(&mut self.oam)?;
+        r.bytes_into(&mut self.pallette)?;
+        r.bytes_into(&mut self.secondary_oam)?;
+        self.read_buf = r.u8()?;
+        self.mirroring = r.mirroring()?;
+
+        self.state.frame = r.u32()?;
+        self.state.scanline = r.usize()?;
+        self.state.cycle = r.usize()?;
+        self.state.pipeline = Pipeline {
+            at_hi: r.u8()?,
+            at_lo: r.u8()?,
+            pt_lo: r.u16()?,
+            pt_hi: r.u16()?,
+            at_latch_hi: r.u8()?,
+            at_latch_lo: r.u8()?,
+        };
+        self.state.num_2oam = r.usize()?;
+        self.state.sprite0_det = r.bool()?;
+        for sprite in self.state.sprites.iter_mut() {
+            *sprite = SpriteSlot {
+                pattern_lo: r.u8()?,
+                pattern_hi: r.u8()?,
+                attr: r.u8()?,
+                x: r.u8()?,
+            };
+        }
+        self.state.eval = SpriteEval {
+            phase: r.u8()?.into(),
+            latch: r.u8()?,
+            sec_addr: r.usize()?,
+            copying: r.u8()?,
+            sprite0: r.bool()?,
+        };
+        self.state.vbl_suppressed = r.bool()?;
+        self.state.nmi_output = r.bool()?;
+        self.io_bus.value = r.u8()?;
+        for refreshed in self.io_bus.refreshed.iter_mut() {
+            *refreshed = r.u64()?;
+        }
+        r.bytes_into(&mut self.fb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::cartridge::{Cartridge, NoCartridge, nrom_image, parse_rom};
+    use super::{Ppu, SYSTEM_PALETTE};
+
+    fn run_to(ppu: &mut Ppu, cart: &mut dyn Cartridge, scanline: usize, dot: usize) {
+        while ppu.position() != (scanline, dot) {
+            ppu.advance_cycles(1, cart);
+        }
+    }
+
+    fn overflow_on_line(sprites: &[(usize, [u8; 4])]) -> bool {
+        let mut ppu = Ppu::new();
+        let mut oam = [0xF0; 256];
+        for (n, sprite) in sprites {
+            oam[n * 4..n * 4 + 4].copy_from_slice(sprite);
+        }
+        ppu.oam_dma(oam);
+        ppu.write_reg(0x01, 0x18, &mut NoCartridge);
+        run_to(&mut ppu, &mut NoCartridge, 20, 0);
+        ppu.read_reg(0x02, &mut NoCartridge) & 0x20 != 0
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let on_line = |n| (n, [10, 0xF0, 0xF0, 0xF0]);
+        let eight: Vec<_> = (0..8).map(on_line).collect();
+        assert!(!overflow_on_line(&eight));
+
+        let nine: Vec<_> = (0..9).map(on_line).collect();
+        assert!(overflow_on_line(&nine));
+
+        // After the eighth sprite, a miss moves on to byte 1 of the next
+        // sprite: a tile number in range is a false positive...
+        let mut tile = eight.clone();
+        tile.push((9, [0xF0, 10, 0xF0, 0xF0]));
+        assert!(overflow_on_line(&tile));
+
+        // ...and a ninth sprite whose y is skipped is missed
+        let mut skipped = eight;
+        skipped.push((9, [10, 0xF0, 0xF0, 0xF0]));
+        assert!(!overflow_on_line(&skipped));
+
+        // Cleared at the start of the pre-render line
+        let mut ppu = Ppu::new();
+        ppu.oam_dma([10; 256]);
+        ppu.write_reg(0x01, 0x18, &mut NoCartridge);
+        run_to(&mut ppu, &mut NoCartridge, 261, 0);
+        assert!(ppu.reg.ppustatus.sprite_overflow);
+        run_to(&mut ppu, &mut NoCartridge, 261, 2);
+        assert!(!ppu.reg.ppustatus.sprite_overflow);
+    }
+
+    #[test]
+    fn test_sprite_evaluation_timing() {
+        let mut ppu = Ppu::new();
+        let mut oam = [0xF0; 256];
+        oam[8..12].copy_from_slice(&[10, 0x42, 0x3D, 0x80]);
+        ppu.oam_dma(oam);
+        ppu.write_reg(0x01, 0x18, &mut NoCartridge);
+
+        // Secondary OAM is cleared first, and $2004 reads $FF meanwhile
+        run_to(&mut ppu, &mut NoCartridge, 10, 40);
+        assert_eq!(ppu.read_reg(0x04, &mut NoCartridge), 0xFF);
+        assert_eq!(ppu.secondary_oam[..20], [0xFF; 20]);
+
+        // Evaluation copies the sprite and walks OAMADDR through OAM
+        run_to(&mut ppu, &mut NoCartridge, 10, 76);
+        assert_eq!(ppu.secondary_oam[..4], [10, 0x42, 0x3D, 0x80]);
+        assert_eq!(ppu.reg.oamaddr, 12);
+        run_to(&mut ppu, &mut NoCartridge, 10, 257);
+        assert_eq!(ppu.reg.oamaddr, 0);
+
+        // Fetches fill one slot; changing OAM now only affects later lines
+        run_to(&mut ppu, &mut NoCartridge, 10, 321);
+        ppu.oam[8] = 0xF0;
+        assert_eq!(ppu.state.num_2oam, 1);
+        assert_eq!(ppu.state.sprites[0].x, 0x80);
+        assert!(!ppu.state.sprite0_det);
+        run_to(&mut ppu, &mut NoCartridge, 11, 321);
+        assert_eq!(ppu.state.num_2oam, 0);
+
+        // Writes while rendering skip to the next sprite instead
+        ppu.write_reg(0x04, 0x55, &mut NoCartridge);
+        assert_eq!(ppu.reg.oamaddr, 4);
+        assert_eq!(ppu.oam[0], 0xF0);
+
+        // Outside rendering, reads come from OAM
+        run_to(&mut ppu, &mut NoCartridge, 250, 0);
+        ppu.write_reg(0x03, 0x0A, &mut NoCartridge);
+        assert_eq!(ppu.read_reg(0x04, &mut NoCartridge), 0x21);
+
+        // Rendering starts by copying OAMADDR's row over the first eight bytes
+        ppu.oam[0x10..0x18].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        ppu.write_reg(0x03, 0x13, &mut NoCartridge);
+        run_to(&mut ppu, &mut NoCartridge, 261, 2);
+        assert_eq!(ppu.oam[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
     }
 
-    pub fn set_cartridge(&mut self, cartridge: Rc<RefCell<Box<dyn Cartridge>>>) {
-        self.mirroring = cartridge.borrow().get_nt_mirroring();
-        self.cartridge = Some(cartridge);
+    #[test]
+    fn test_ppumask() {
+        // Tile 0, which fills the nametables, is solid colour 1
+        let mut image = nrom_image(&[], 0x8000);
+        image[16 + 0x4000..16 + 0x4008].fill(0xFF);
+        let mut cart = parse_rom(&image).unwrap();
+        let cart = cart.as_mut();
+        let mut ppu = Ppu::new();
+        for (addr, val) in [(0x3F00, 0x0F), (0x3F01, 0x16), (0x3F02, 0x2A)] {
+            ppu.write_ppu_byte(addr, val, cart);
+        }
+        let pixel = |ppu: &Ppu, x| ppu.fb.get_pixel(x, 100).0;
+        let color = |id: usize| {
+            let (r, g, b) = SYSTEM_PALETTE[id];
+            [r, g, b]
+        };
+
+        // Background without the left column
+        ppu.write_reg(0x01, 0x08, cart);
+        run_to(&mut ppu, cart, 101, 0);
+        assert_eq!(pixel(&ppu, 7), color(0x0F));
+        assert_eq!(pixel(&ppu, 8), color(0x16));
+
+        // Grayscale, and red emphasis dimming green and blue
+        ppu.write_reg(0x01, 0x2A | 0x01, cart);
+        run_to(&mut ppu, cart, 100, 0);
+        run_to(&mut ppu, cart, 101, 0);
+        let [r, g, b] = pixel(&ppu, 0);
+        let gray = color(0x10);
+        assert_eq!(r, gray[0]);
+        assert!(g < gray[1] && b < gray[2]);
+
+        // With rendering off v stays put, and shows the colour it points at
+        ppu.write_reg(0x01, 0x00, cart);
+        ppu.write_reg(0x06, 0x3F, cart);
+        ppu.write_reg(0x06, 0x02, cart);
+        run_to(&mut ppu, cart, 100, 0);
+        run_to(&mut ppu, cart, 101, 0);
+        assert_eq!(pixel(&ppu, 0), color(0x2A));
+        assert_eq!(ppu.vram_addr(), 0x3F02);
+    }
+
+    #[test]
+    fn test_register_reads() {
+        let mut ppu = Ppu::new();
+        let cart = &mut NoCartridge;
+
+        // Write-only registers, and the bits $2002 doesn't drive, read back
+        // the last value written until it fades
+        ppu.write_reg(0x02, 0x5A, cart);
+        for reg in [0x00, 0x01, 0x03, 0x05, 0x06] {
+            assert_eq!(ppu.read_reg(reg, cart), 0x5A);
+        }
+        assert_eq!(ppu.read_reg(0x02, cart), 0x1A);
+        ppu.state.frame += 40;
+        assert_eq!(ppu.read_reg(0x00, cart), 0);
+
+        // Palette reads skip the buffer but fill it from the nametable
+        ppu.write_ppu_byte(0x2F00, 0x77, cart);
+        ppu.write_ppu_byte(0x3F00, 0x0F, cart);
+        ppu.write_reg(0x06, 0x3F, cart);
+        ppu.write_reg(0x06, 0x00, cart);
+        assert_eq!(ppu.read_reg(0x07, cart), 0x0F);
+        ppu.write_reg(0x06, 0x20, cart);
+        ppu.write_reg(0x06, 0x00, cart);
+        assert_eq!(ppu.read_reg(0x07, cart), 0x77);
+
+        // Reading $2002 just before vblank starts loses it for the frame
+        run_to(&mut ppu, cart, 241, 0);
+        assert_eq!(ppu.read_reg(0x02, cart) & 0x80, 0);
+        run_to(&mut ppu, cart, 241, 5);
+        assert_eq!(ppu.read_reg(0x02, cart) & 0x80, 0);
+        run_to(&mut ppu, cart, 240, 0);
+        run_to(&mut ppu, cart, 241, 5);
+        ppu.write_reg(0x00, 0x80, cart);
+        assert!(ppu.nmi_requested());
+    }
+
+    #[test]
+    fn test_nmi_race() {
+        let mut ppu = Ppu::new();
+        let cart = &mut NoCartridge;
+        ppu.write_reg(0x00, 0x80, cart);
+
+        // Reading on the dot vblank starts, or the next, sees the flag but
+        // cancels the NMI
+        for dot in [1, 2] {
+            run_to(&mut ppu, cart, 241, dot);
+            assert!(ppu.nmi_requested());
+            assert_ne!(ppu.read_reg(0x02, cart) & 0x80, 0);
+            assert!(!ppu.nmi_requested());
+            run_to(&mut ppu, cart, 240, 0);
+        }
+
+        // Any later and the NMI has already gone out
+        run_to(&mut ppu, cart, 241, 3);
+        assert_ne!(ppu.read_reg(0x02, cart) & 0x80, 0);
+        assert!(ppu.nmi_requested());
+        run_to(&mut ppu, cart, 261, 1);
+        assert!(!ppu.nmi_requested());
     }
 }
@@ -0,0 +1,694 @@
+// Runs a Nes on its own thread, paced to the console's frame rate. Frames and
+// events come back over bounded channels; when the consumer falls behind they
+// are dropped rather than stalling emulation. Audio will get a channel of its
+// own once there is an APU producing samples.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::Nes;
+use super::cartridge::Region;
+use super::input::ControllerState;
+use super::input_macro::InputMacro;
+use super::state::Snapshot;
+
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+pub const PAL_FRAME_RATE: f64 = 50.007;
+
+const COMMAND_QUEUE: usize = 64;
+const FRAME_QUEUE: usize = 2;
+const EVENT_QUEUE: usize = 16;
+
+// Give up on catching up after falling this many frames behind
+const MAX_LAG_FRAMES: u32 = 4;
+
+// Speed::Scaled is clamped to this range, so a zero or negative scale can't
+// stall or panic the pacer
+const MIN_SPEED_SCALE: f64 = 0.01;
+const MAX_SPEED_SCALE: f64 = 100.0;
+
+// A snapshot every 4 frames, 10 seconds worth. Rewinding steps back one
+// snapshot per frame, so it plays back at 4x
+const REWIND_INTERVAL: u32 = 4;
+const REWIND_SNAPSHOTS: usize = 150;
+
+// Players 1 to 4, as many as a multitap takes
+const PLAYERS: usize = 4;
+const DEFAULT_TURBO_RATE: f64 = 15.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    // Multiple of the console's frame rate, 1.0 is normal speed
+    Scaled(f64),
+    Uncapped,
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed::Scaled(1.0)
+    }
+}
+
+// Measured over the last second
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub fps: f64,
+    // Relative to the console's frame rate
+    pub speed: f64,
+}
+
+pub fn frame_rate(region: Region) -> f64 {
+    match region {
+        Region::Pal => PAL_FRAME_RATE,
+        Region::Ntsc | Region::Dual => NTSC_FRAME_RATE,
+    }
+}
+
+pub enum Command {
+    // Player (0 to 3) and their buttons
+    Input(usize, ControllerState),
+    // Buttons the player is holding with autofire
+    Turbo(usize, ControllerState),
+    // Turbo presses per second
+    SetTurboRate(f64),
+    // Takes over the player's buttons until the macro runs out
+    PlayMacro(usize, InputMacro),
+    // Captures the player's buttons each frame, turbo and macros included
+    RecordMacro(usize),
+    // Replies with what was captured since RecordMacro, None if nothing was
+    // being recorded
+    StopRecording(mpsc::Sender<Option<InputMacro>>),
+    Pause,
+    Resume,
+    // Runs a single frame while paused
+    StepFrame,
+    Reset,
+    PowerCycle,
+    SetSpeed(Speed),
+    // Paces as this region instead of what the ROM header says
+    SetRegion(Option<Region>),
+    // Runs backwards through recent history while true
+    Rewind(bool),
+    LoadState(Snapshot),
+    // Runs on the emulation thread, between frames
+    Run(Box<dyn FnOnce(&mut Nes) + Send>),
+    Quit,
+}
+
+// Things that happen on the emulation thread the frontend should know about
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    // A command failed, with the message to show
+    Error(String),
+}
+
+pub struct Runner {
+    commands: SyncSender<Command>,
+    frames: Receiver<image::RgbImage>,
+    events: Receiver<Event>,
+    stats: Arc<Mutex<Stats>>,
+    thread: Option<JoinHandle<Nes>>,
+}
+
+impl Runner {
+    pub fn spawn(nes: Nes) -> Runner {
+        let (commands, command_rx) = mpsc::sync_channel(COMMAND_QUEUE);
+        let (frame_tx, frames) = mpsc::sync_channel(FRAME_QUEUE);
+        let (event_tx, events) = mpsc::sync_channel(EVENT_QUEUE);
+        let stats = Arc::new(Mutex::new(Stats::default()));
+        let thread_stats = stats.clone();
+        let thread = thread::Builder::new()
+            .name(String::from("emulation"))
+            .spawn(move || run(nes, command_rx, frame_tx, event_tx, thread_stats))
+            .expect("Failed to spawn emulation thread");
+        Runner {
+            commands,
+            frames,
+            events,
+            stats,
+            thread: Some(thread),
+        }
+    }
+
+    // Does nothing if the emulation thread has gone away
+    pub fn send(&self, command: Command) {
+        _ = self.commands.send(command);
+    }
+
+    // Runs `f` on the emulation thread and waits for the result
+    pub fn with_nes<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Nes) -> R + Send + 'static,
+    ) -> Option<R> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Command::Run(Box::new(move |nes| _ = tx.send(f(nes)))));
+        rx.recv().ok()
+    }
+
+    // Err when the emulation thread has gone away, Ok(None) when nothing
+    // was being recorded
+    pub fn stop_recording(&self) -> Result<Option<InputMacro>, String> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Command::StopRecording(tx));
+        rx.recv()
+            .map_err(|_| String::from("Emulation thread stopped"))
+    }
+
+    // The newest frame since the last call, if any
+    pub fn latest_frame(&self) -> Option<image::RgbImage> {
+        self.frames.try_iter().last()
+    }
+
+    pub fn take_events(&self) -> Vec<Event> {
+        self.events.try_iter().collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        *self.stats.lock().unwrap()
+    }
+
+    // Stops the thread and hands the Nes back
+    pub fn stop(mut self) -> Option<Nes> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Option<Nes> {
+        self.send(Command::Quit);
+        self.thread.take()?.join().ok()
+    }
+}
+
+impl Drop for Runner {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+fn run(
+    mut nes: Nes,
+    commands: Receiver<Command>,
+    frames: SyncSender<image::RgbImage>,
+    events: SyncSender<Event>,
+    stats: Arc<Mutex<Stats>>,
+) -> Nes {
+    let mut pacer = Pacer::new();
+    let mut counter = FrameCounter::new();
+    let mut speed = Speed::default();
+    let mut region = None;
+    let mut paused = false;
+    let mut rewind = RewindBuffer::new();
+    let mut rewinding = false;
+    let mut mixer = InputMixer::new();
+    loop {
+        loop {
+            let command = if paused {
+                match commands.recv() {
+                    Ok(command) => command,
+                    Err(_) => return nes,
+                }
+            } else {
+                match commands.try_recv() {
+                    Ok(command) => command,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return nes,
+                }
+            };
+            match command {
+                Command::Input(player, state) => mixer.set(player, state, false),
+                Command::Turbo(player, state) => mixer.set(player, state, true),
+                Command::SetTurboRate(rate) => mixer.turbo_rate = rate,
+                Command::PlayMacro(player, input) => mixer.play(player, input),
+                Command::RecordMacro(player) => {
+                    mixer.recording = Some((player, InputMacro::default()))
+                }
+                Command::StopRecording(reply) => {
+                    _ = reply.send(mixer.recording.take().map(|(_, input)| input))
+                }
+                Command::Pause => paused = true,
+                Command::Resume => {
+                    paused = false;
+                    pacer.restart();
+                    counter.restart();
+                }
+                // Later commands wait until the frame is done
+                Command::StepFrame if paused => break,
+                Command::StepFrame => (),
+                Command::Reset => nes.reset(),
+                Command::PowerCycle => nes.power_cycle(),
+                Command::SetSpeed(new_speed) => {
+                    speed = new_speed;
+                    pacer.restart();
+                }
+                Command::SetRegion(new_region) => region = new_region,
+                Command::Rewind(on) => rewinding = on,
+                Command::LoadState(snapshot) => {
+                    if let Err(e) = nes.load_state(&snapshot) {
+                        let message = format!("Failed to load state: {}", e);
+                        _ = events.try_send(Event::Error(message));
+                    }
+                }
+                Command::Run(f) => f(&mut nes),
+                Command::Quit => return nes,
+            }
+        }
+
+        let rom_region = nes.rom_info().map_or(Region::Ntsc, |info| info.region);
+        let rate = frame_rate(region.unwrap_or(rom_region));
+
+        // Full queues mean the consumer is behind, skip rather than wait
+        if rewinding {
+            rewind.step_back(&mut nes);
+            _ = frames.try_send(nes.frame());
+        } else {
+            mixer.frame(&mut nes, rate);
+            _ = frames.try_send(nes.run_frame());
+            rewind.frame_done(&nes);
+        }
+
+        if let Some(fps) = counter.frame() {
+            *stats.lock().unwrap() = Stats {
+                fps,
+                speed: fps / rate,
+            };
+        }
+        if !paused {
+            pacer.wait(rate, speed);
+        }
+    }
+}
+
+// Where the pacer gets the time from, so tests can run it without sleeping
+trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&mut self, duration: Duration);
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// Sleeps until deadlines spaced exactly one frame apart, so rounding errors
+// don't accumulate
+struct Pacer<C = SystemClock> {
+    clock: C,
+    deadline: Instant,
+}
+
+impl Pacer {
+    fn new() -> Self {
+        Pacer::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> Pacer<C> {
+    fn with_clock(clock: C) -> Self {
+        let deadline = clock.now();
+        Pacer { clock, deadline }
+    }
+
+    fn restart(&mut self) {
+        self.deadline = self.clock.now();
+    }
+
+    fn wait(&mut self, frame_rate: f64, speed: Speed) {
+        let Speed::Scaled(scale) = speed else {
+            self.restart();
+            return;
+        };
+        let scale = if scale.is_nan() {
+            1.0
+        } else {
+            scale.clamp(MIN_SPEED_SCALE, MAX_SPEED_SCALE)
+        };
+        let period = Duration::from_secs_f64(1.0 / (frame_rate * scale));
+        self.deadline += period;
+        let now = self.clock.now();
+        if self.deadline > now {
+            self.clock.sleep(self.deadline - now);
+        } else if now - self.deadline > period * MAX_LAG_FRAMES {
+            self.deadline = now;
+        }
+    }
+}
+
+struct RewindBuffer {
+    snapshots: VecDeque<Snapshot>,
+    frames: u32,
+}
+
+impl RewindBuffer {
+    fn new() -> Self {
+        RewindBuffer {
+            snapshots: VecDeque::with_capacity(REWIND_SNAPSHOTS),
+            frames: 0,
+        }
+    }
+
+    fn frame_done(&mut self, nes: &Nes) {
+        self.frames += 1;
+        if self.frames < REWIND_INTERVAL {
+            return;
+        }
+        self.frames = 0;
+        if self.snapshots.len() == REWIND_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(nes.save_state());
+    }
+
+    // Stays on the oldest snapshot once the history runs out
+    fn step_back(&mut self, nes: &mut Nes) {
+        let snapshot = if self.snapshots.len() > 1 {
+            self.snapshots.pop_back()
+        } else {
+            self.snapshots.back().cloned()
+        };
+        if let Some(snapshot) = snapshot
+            && nes.load_state(&snapshot).is_err()
+        {
+            // From a different ROM
+            self.snapshots.clear();
+        }
+        self.frames = 0;
+    }
+}
+
+// Turbo and macros sit between the frontend and the Nes, so they work the
+// same whatever the buttons come from
+struct InputMixer {
+    held: [ControllerState; PLAYERS],
+    turbo: [ControllerState; PLAYERS],
+    // Frames since each player's turbo buttons went down
+    turbo_frames: [u32; PLAYERS],
+    turbo_rate: f64,
+    // Macro and the next frame of it
+    playing: [Option<(InputMacro, usize)>; PLAYERS],
+    recording: Option<(usize, InputMacro)>,
+}
+
+impl InputMixer {
+    fn new() -> Self {
+        InputMixer {
+            held: [ControllerState::default(); PLAYERS],
+            turbo: [ControllerState::default(); PLAYERS],
+            turbo_frames: [0; PLAYERS],
+            turbo_rate: DEFAULT_TURBO_RATE,
+            playing: Default::default(),
+            recording: None,
+        }
+    }
+
+    fn set(&mut self, player: usize, state: ControllerState, turbo: bool) {
+        let states = if turbo {
+            &mut self.turbo
+        } else {
+            &mut self.held
+        };
+        if let Some(held) = states.get_mut(player) {
+            *held = state;
+        }
+    }
+
+    fn play(&mut self, player: usize, input: InputMacro) {
+        if let Some(playing) = self.playing.get_mut(player) {
+            *playing = Some((input, 0));
+        }
+    }
+
+    // Hands the Nes everyone's buttons for the coming frame
+    fn frame(&mut self, nes: &mut Nes, frame_rate: f64) {
+        for player in 0..PLAYERS {
+            let mut state = self.held[player];
+            if self.turbo[player] == ControllerState::default() {
+                self.turbo_frames[player] = 0;
+            } else {
+                // Pressed for the first half of each period
+                let half_periods = self.turbo_frames[player] as f64 * self.turbo_rate * 2.0;
+                if ((half_periods / frame_rate) as u64).is_multiple_of(2) {
+                    state = state | self.turbo[player];
+                }
+                self.turbo_frames[player] += 1;
+            }
+            if let Some((input, next)) = &mut self.playing[player] {
+                match input.frames.get(*next) {
+                    Some(frame) => {
+                        state = *frame;
+                        *next += 1;
+                    }
+                    None => self.playing[player] = None,
+                }
+            }
+            if let Some((recording, input)) = &mut self.recording
+                && *recording == player
+            {
+                input.frames.push(state);
+            }
+            nes.set_controller_state(player, state);
+        }
+    }
+}
+
+struct FrameCounter {
+    start: Instant,
+    frames: u32,
+}
+
+impl FrameCounter {
+    fn new() -> Self {
+        FrameCounter {
+            start: Instant::now(),
+            frames: 0,
+        }
+    }
+
+    fn restart(&mut self) {
+        *self = FrameCounter::new();
+    }
+
+    // Returns the frame rate once a second
+    fn frame(&mut self) -> Option<f64> {
+        self.frames += 1;
+        let elapsed = self.start.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return None;
+        }
+        let fps = self.frames as f64 / elapsed.as_secs_f64();
+        self.restart();
+        Some(fps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::super::input::ControllerState;
+    use super::super::input_macro::InputMacro;
+    use super::super::state::Snapshot;
+    use super::super::{Nes, cartridge::nrom_image};
+    use super::{
+        Clock, Command, Event, InputMixer, MAX_LAG_FRAMES, MAX_SPEED_SCALE, MIN_SPEED_SCALE, Pacer,
+        REWIND_INTERVAL, RewindBuffer, Runner, Speed,
+    };
+
+    fn test_nes() -> Nes {
+        let image = nrom_image(
+            &[(
+                0xC000,
+                &[
+                    0xE8, // INX
+                    0x4C, 0x00, 0xC0, // JMP $C000
+                ],
+            )],
+            0xC000,
+        );
+        let mut nes = Nes::new();
+        nes.load_rom_bytes(&image).unwrap();
+        nes
+    }
+
+    // Only moves when the pacer sleeps or the test advances it
+    struct FakeClock {
+        now: Instant,
+        slept: Vec<Duration>,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.now += duration;
+            self.slept.push(duration);
+        }
+    }
+
+    #[test]
+    fn test_pacer() {
+        let mut pacer = Pacer::with_clock(FakeClock {
+            now: Instant::now(),
+            slept: Vec::new(),
+        });
+        let period = Duration::from_secs_f64(1.0 / 1000.0);
+        for _ in 0..50 {
+            pacer.wait(500.0, Speed::Scaled(2.0));
+        }
+        assert_eq!(pacer.clock.slept, [period; 50]);
+
+        // Running two frames late is made up for by not sleeping for two
+        pacer.clock.slept.clear();
+        pacer.clock.now += period * 2;
+        for _ in 0..3 {
+            pacer.wait(500.0, Speed::Scaled(2.0));
+        }
+        assert_eq!(pacer.clock.slept, [period]);
+
+        // Falling too far behind gives up on catching up
+        pacer.clock.slept.clear();
+        pacer.clock.now += period * (MAX_LAG_FRAMES + 2);
+        pacer.wait(500.0, Speed::Scaled(2.0));
+        pacer.wait(500.0, Speed::Scaled(2.0));
+        assert_eq!(pacer.clock.slept, [period]);
+
+        pacer.clock.slept.clear();
+        for _ in 0..1000 {
+            pacer.wait(500.0, Speed::Uncapped);
+        }
+        assert!(pacer.clock.slept.is_empty());
+
+        // Nonsense scales get clamped instead of panicking
+        for scale in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            pacer.restart();
+            pacer.clock.slept.clear();
+            pacer.wait(100.0, Speed::Scaled(scale));
+            let clamped = if scale.is_nan() {
+                1.0
+            } else {
+                scale.clamp(MIN_SPEED_SCALE, MAX_SPEED_SCALE)
+            };
+            let period = Duration::from_secs_f64(1.0 / (100.0 * clamped));
+            assert_eq!(pacer.clock.slept, [period]);
+        }
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut nes = test_nes();
+        let mut rewind = RewindBuffer::new();
+        let mut saved = Vec::new();
+        for _ in 0..3 {
+            for _ in 0..REWIND_INTERVAL {
+                nes.run_frame();
+                rewind.frame_done(&nes);
+            }
+            saved.push(nes.registers());
+        }
+        nes.run_frame();
+
+        rewind.step_back(&mut nes);
+        assert_eq!(nes.registers(), saved[2]);
+        rewind.step_back(&mut nes);
+        assert_eq!(nes.registers(), saved[1]);
+        // Holds at the oldest one
+        rewind.step_back(&mut nes);
+        rewind.step_back(&mut nes);
+        assert_eq!(nes.registers(), saved[0]);
+    }
+
+    #[test]
+    fn test_input_mixer() {
+        let mut nes = test_nes();
+        let mut mixer = InputMixer::new();
+        let a = ControllerState {
+            a: true,
+            ..Default::default()
+        };
+        let start = ControllerState {
+            start: true,
+            ..Default::default()
+        };
+        mixer.recording = Some((0, InputMacro::default()));
+        // 15 presses a second at 60 frames: two on, two off
+        mixer.turbo_rate = 15.0;
+        mixer.set(0, a, true);
+        for _ in 0..6 {
+            mixer.frame(&mut nes, 60.0);
+        }
+        mixer.set(0, ControllerState::default(), true);
+        mixer.set(0, start, false);
+        mixer.play(0, InputMacro { frames: vec![a] });
+        mixer.frame(&mut nes, 60.0);
+        mixer.frame(&mut nes, 60.0);
+
+        let (_, recorded) = mixer.recording.take().unwrap();
+        let none = ControllerState::default();
+        assert_eq!(recorded.frames, [a, a, none, none, a, a, a, start]);
+    }
+
+    // Blocks until the emulation thread sends a frame, with a bound generous
+    // enough for a loaded CI machine
+    fn next_frame(runner: &Runner) -> image::RgbImage {
+        runner
+            .frames
+            .recv_timeout(Duration::from_secs(10))
+            .expect("No frame from the emulation thread")
+    }
+
+    #[test]
+    fn test_runner() {
+        let runner = Runner::spawn(test_nes());
+        next_frame(&runner);
+
+        // Commands are handled in order and a paused runner doesn't run
+        // frames between them
+        runner.send(Command::Pause);
+        let paused = runner.with_nes(|nes| nes.registers()).unwrap();
+        assert_eq!(runner.with_nes(|nes| nes.registers()), Some(paused));
+
+        let snapshot = runner.with_nes(|nes| nes.save_state()).unwrap();
+        let snapshot_bytes = snapshot.as_bytes().to_vec();
+        runner.send(Command::StepFrame);
+        assert_ne!(runner.with_nes(|nes| nes.registers()), Some(paused));
+
+        runner.latest_frame();
+        runner.send(Command::Resume);
+        next_frame(&runner);
+        runner.send(Command::Pause);
+        runner.send(Command::LoadState(snapshot));
+        assert_eq!(runner.with_nes(|nes| nes.registers()), Some(paused));
+        assert!(runner.take_events().is_empty());
+
+        // Cut short, so the header is fine but loading fails
+        let mut bytes = snapshot_bytes;
+        bytes.truncate(bytes.len() / 2);
+        runner.send(Command::LoadState(Snapshot::from_bytes(bytes).unwrap()));
+        runner.with_nes(|_| ());
+        assert!(matches!(runner.take_events()[..], [Event::Error(_)]));
+
+        let nes = runner.stop().unwrap();
+        assert_eq!(nes.registers(), paused);
+    }
+
+    #[test]
+    fn test_stop_recording() {
+        let runner = Runner::spawn(test_nes());
+        runner.send(Command::Pause);
+        assert_eq!(runner.stop_recording(), Ok(None));
+        runner.send(Command::RecordMacro(0));
+        runner.send(Command::StepFrame);
+        let recorded = runner.stop_recording().unwrap().unwrap();
+        assert_eq!(recorded.frames.len(), 1);
+    }
+}
@@ -0,0 +1,175 @@
+// Save states. Each component writes its fields in a fixed order into a flat
+// byte buffer, and reads them back in the same order.
+
+use super::cartridge::Mirroring;
+
+const MAGIC: [u8; 4] = *b"RNES";
+const VERSION: u8 = 5;
+
+// A saved emulator state, see Nes::save_state
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    data: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, String> {
+        if bytes.len() < MAGIC.len() + 1 || bytes[..MAGIC.len()] != MAGIC {
+            return Err(String::from("Not a save state"));
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(format!(
+                "Unsupported save state version {}",
+                bytes[MAGIC.len()]
+            ));
+        }
+        Ok(Self { data: bytes })
+    }
+
+    pub(super) fn writer() -> StateWriter {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        StateWriter { data }
+    }
+
+    pub(super) fn reader(&self) -> StateReader<'_> {
+        StateReader {
+            data: &self.data,
+            pos: MAGIC.len() + 1,
+        }
+    }
+}
+
+pub(super) trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.data.extend(v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend(v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.data.extend(v.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, v: usize) {
+        self.u64(v as u64);
+    }
+
+    // Length prefixed
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.usize(v.len());
+        self.data.extend(v);
+    }
+
+    pub fn mirroring(&mut self, v: Mirroring) {
+        self.u8(match v {
+            Mirroring::OneScreen => 0,
+            Mirroring::UpperBank => 1,
+            Mirroring::Vertical => 2,
+            Mirroring::Horizontal => 3,
+        });
+    }
+
+    pub fn finish(self) -> Snapshot {
+        Snapshot { data: self.data }
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl StateReader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        // len can come from a corrupt length prefix
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| String::from("Save state is truncated"))?;
+        let ret = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(ret)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, String> {
+        Ok(self.u64()? as usize)
+    }
+
+    pub fn bytes(&mut self) -> Result<&[u8], String> {
+        let len = self.usize()?;
+        self.take(len)
+    }
+
+    // For fixed size buffers, which must match in length
+    pub fn bytes_into(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        let bytes = self.bytes()?;
+        if bytes.len() != buf.len() {
+            return Err(String::from("Save state doesn't match this cartridge"));
+        }
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn mirroring(&mut self) -> Result<Mirroring, String> {
+        match self.u8()? {
+            0 => Ok(Mirroring::OneScreen),
+            1 => Ok(Mirroring::UpperBank),
+            2 => Ok(Mirroring::Vertical),
+            3 => Ok(Mirroring::Horizontal),
+            m => Err(format!("Invalid mirroring {} in save state", m)),
+        }
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(String::from("Unexpected data at the end of the save state"))
+        }
+    }
+}
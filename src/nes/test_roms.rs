@@ -0,0 +1,360 @@
+// Test ROM harness for the roms in the nes-test-roms submodule.
+//
+// Most of blargg's test roms (and the ones built on his framework) report
+// their result through cartridge RAM:
+//   $6000      status: $80 = running, $81 = press reset after 100ms, else result code
+//   $6001-6003 signature $DE $B0 $61, written once the status byte is valid
+//   $6004..    null-terminated result text
+//
+// Every rom under TEST_ROM_DIR is run. The ones that don't write the
+// signature within SIGNATURE_FRAMES, or don't load, aren't blargg roms and
+// are skipped. Older roms in LEGACY_ROMS, like sprite_hit_tests, only leave
+// a result code at $F8 (1 = passed) and never say when they're done, so they
+// get a fixed number of frames.
+//
+// Each rom gets a line of output. Roms that are known to fail are listed in
+// EXPECTED_FAILURES, any other failure is a regression and a rom that starts
+// passing has to be taken off the list. The tests are #[ignore]d since they
+// need the submodule checked out, run them with
+// `cargo test --release -- --ignored --nocapture`. A missing rom directory,
+// or a listed rom that isn't there, fails the test.
+
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use super::Nes;
+
+const TEST_ROM_DIR: &str = "nes-test-roms";
+
+const STATUS_ADDR: u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const TEXT_ADDR: u16 = 0x6004;
+const MAX_TEXT_LEN: u16 = 0x1000;
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET_REQUESTED: u8 = 0x81;
+const STATUS_PASSED: u8 = 0x00;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const MAX_FRAMES: usize = 60 * 60;
+const SIGNATURE_FRAMES: usize = 5 * 60;
+const RESET_DELAY_FRAMES: usize = 10; // Has to be at least 100ms
+
+const LEGACY_RESULT_ADDR: u16 = 0x00F8;
+const LEGACY_PASSED: u8 = 1;
+const LEGACY_FRAMES: usize = 10 * 60;
+
+// Roms that don't pass yet. Paths are relative to TEST_ROM_DIR.
+const EXPECTED_FAILURES: &[&str] = &[
+    // No APU yet (length counters, frame irq, dmc)
+    "apu_test/rom_singles/1-len_ctr.nes",
+    "apu_test/rom_singles/2-len_table.nes",
+    "apu_test/rom_singles/3-irq_flag.nes",
+    "apu_test/rom_singles/4-jitter.nes",
+    "apu_test/rom_singles/5-len_timing.nes",
+    "apu_test/rom_singles/6-irq_flag_timing.nes",
+    "apu_test/rom_singles/7-dmc_basics.nes",
+    "apu_test/rom_singles/8-dmc_rates.nes",
+    "instr_misc/rom_singles/04-dummy_reads_apu.nes",
+    "instr_timing/rom_singles/1-instr_timing.nes",
+    "instr_timing/rom_singles/2-branch_timing.nes",
+    // No IRQ line
+    "cpu_interrupts_v2/rom_singles/1-cli_latency.nes",
+    "cpu_interrupts_v2/rom_singles/2-nmi_and_brk.nes",
+    "cpu_interrupts_v2/rom_singles/3-nmi_and_irq.nes",
+    "cpu_interrupts_v2/rom_singles/4-irq_and_dma.nes",
+    "cpu_interrupts_v2/rom_singles/5-branch_delays_irq.nes",
+    // Reset only reloads the reset vector
+    "cpu_reset/registers.nes",
+    "cpu_reset/ram_after_reset.nes",
+    // PPU timing and register quirks
+    "oam_read/oam_read.nes",
+    "oam_stress/oam_stress.nes",
+    "cpu_dummy_writes/cpu_dummy_writes_oam.nes",
+    "cpu_dummy_writes/cpu_dummy_writes_ppumem.nes",
+    "cpu_exec_space/test_cpu_exec_space_ppuio.nes",
+    "cpu_exec_space/test_cpu_exec_space_apu.nes",
+    // Unsupported mapper (MMC3)
+    "mmc3_test_2/rom_singles/1-clocking.nes",
+    "mmc3_test_2/rom_singles/2-details.nes",
+    "mmc3_test_2/rom_singles/3-A12_clocking.nes",
+    "mmc3_test_2/rom_singles/4-scanline_timing.nes",
+    "mmc3_test_2/rom_singles/5-MMC3.nes",
+    "mmc3_test_2/rom_singles/6-MMC3_alt.nes",
+    // Not yet seen passing, the test-roms CI job reports which of these do
+    "sprite_overflow_tests/1.Basics.nes",
+    "sprite_overflow_tests/2.Details.nes",
+    "sprite_overflow_tests/3.Timing.nes",
+    "sprite_overflow_tests/4.Obscure.nes",
+    "sprite_overflow_tests/5.Emulator.nes",
+    "sprite_hit_tests_2005.10.05/01.basics.nes",
+    "sprite_hit_tests_2005.10.05/02.alignment.nes",
+    "sprite_hit_tests_2005.10.05/03.corners.nes",
+    "sprite_hit_tests_2005.10.05/04.flip.nes",
+    "sprite_hit_tests_2005.10.05/05.left_clip.nes",
+    "sprite_hit_tests_2005.10.05/06.right_edge.nes",
+    "sprite_hit_tests_2005.10.05/07.screen_bottom.nes",
+    "sprite_hit_tests_2005.10.05/08.double_height.nes",
+    "sprite_hit_tests_2005.10.05/09.timing_basics.nes",
+    "sprite_hit_tests_2005.10.05/10.timing_order.nes",
+    "sprite_hit_tests_2005.10.05/11.edge_timing.nes",
+];
+
+// Roms that report through $F8. Paths are relative to TEST_ROM_DIR.
+const LEGACY_ROMS: &[&str] = &[
+    "sprite_overflow_tests/1.Basics.nes",
+    "sprite_overflow_tests/2.Details.nes",
+    "sprite_overflow_tests/3.Timing.nes",
+    "sprite_overflow_tests/4.Obscure.nes",
+    "sprite_overflow_tests/5.Emulator.nes",
+    "sprite_hit_tests_2005.10.05/01.basics.nes",
+    "sprite_hit_tests_2005.10.05/02.alignment.nes",
+    "sprite_hit_tests_2005.10.05/03.corners.nes",
+    "sprite_hit_tests_2005.10.05/04.flip.nes",
+    "sprite_hit_tests_2005.10.05/05.left_clip.nes",
+    "sprite_hit_tests_2005.10.05/06.right_edge.nes",
+    "sprite_hit_tests_2005.10.05/07.screen_bottom.nes",
+    "sprite_hit_tests_2005.10.05/08.double_height.nes",
+    "sprite_hit_tests_2005.10.05/09.timing_basics.nes",
+    "sprite_hit_tests_2005.10.05/10.timing_order.nes",
+    "sprite_hit_tests_2005.10.05/11.edge_timing.nes",
+];
+
+#[derive(Debug)]
+enum BlarggResult {
+    Passed,
+    Failed(u8, String),
+    Timeout(String),
+    Crashed,
+    LoadFailed(String),
+    // Not a rom this harness knows how to read
+    Skipped(&'static str),
+}
+
+fn read_status(nes: &Nes) -> Option<u8> {
+    let mem = &nes.bus;
+    let signature = [
+        mem.peek_byte(SIGNATURE_ADDR),
+        mem.peek_byte(SIGNATURE_ADDR + 1),
+        mem.peek_byte(SIGNATURE_ADDR + 2),
+    ];
+    if signature == SIGNATURE {
+        Some(mem.peek_byte(STATUS_ADDR))
+    } else {
+        None
+    }
+}
+
+fn read_text(nes: &Nes) -> String {
+    let mem = &nes.bus;
+    let mut text = String::new();
+    for i in 0..MAX_TEXT_LEN {
+        let c = mem.peek_byte(TEXT_ADDR + i);
+        if c == 0 {
+            break;
+        }
+        text.push(c as char);
+    }
+    text
+}
+
+fn load_test_rom(path: &Path) -> Result<Nes, String> {
+    let mut nes = Nes::new();
+    nes.load_rom(path.to_string_lossy().into_owned())?;
+    Ok(nes)
+}
+
+// Returns false if the emulator panicked
+fn run_frame(nes: &mut Nes) -> bool {
+    panic::catch_unwind(AssertUnwindSafe(|| nes.run_frame())).is_ok()
+}
+
+fn run_blargg_rom(path: &Path) -> BlarggResult {
+    let Ok(mut nes) = load_test_rom(path) else {
+        return BlarggResult::Skipped("doesn't load");
+    };
+    let mut signed = false;
+    let mut reset_countdown = None;
+    for frame in 0..MAX_FRAMES {
+        if !run_frame(&mut nes) {
+            if signed {
+                return BlarggResult::Crashed;
+            }
+            return BlarggResult::Skipped("crashed before writing the signature");
+        }
+        let status = read_status(&nes);
+        signed |= status.is_some();
+        match status {
+            None if frame >= SIGNATURE_FRAMES => {
+                return BlarggResult::Skipped("no signature at $6001");
+            }
+            None | Some(STATUS_RUNNING) => {}
+            Some(STATUS_RESET_REQUESTED) => {
+                reset_countdown = match reset_countdown {
+                    None => Some(RESET_DELAY_FRAMES),
+                    Some(0) => {
+                        nes.reset();
+                        None
+                    }
+                    Some(n) => Some(n - 1),
+                }
+            }
+            Some(STATUS_PASSED) => return BlarggResult::Passed,
+            Some(code) => return BlarggResult::Failed(code, read_text(&nes)),
+        }
+    }
+    BlarggResult::Timeout(read_text(&nes))
+}
+
+fn run_legacy_rom(path: &Path) -> BlarggResult {
+    let mut nes = match load_test_rom(path) {
+        Ok(nes) => nes,
+        Err(e) => return BlarggResult::LoadFailed(e),
+    };
+    for _ in 0..LEGACY_FRAMES {
+        if !run_frame(&mut nes) {
+            return BlarggResult::Crashed;
+        }
+    }
+    match nes.bus.peek_byte(LEGACY_RESULT_ADDR) {
+        LEGACY_PASSED => BlarggResult::Passed,
+        code => BlarggResult::Failed(code, String::new()),
+    }
+}
+
+// Every .nes file under dir, relative to TEST_ROM_DIR
+fn find_roms(dir: &Path, roms: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("nes"))
+        {
+            let rom = path.strip_prefix(TEST_ROM_DIR).unwrap_or(&path);
+            let parts: Vec<_> = rom.iter().map(|part| part.to_string_lossy()).collect();
+            roms.push(parts.join("/"));
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs the nes-test-roms submodule"]
+fn blargg_roms() {
+    let mut roms = Vec::new();
+    find_roms(Path::new(TEST_ROM_DIR), &mut roms);
+    roms.sort();
+    assert!(
+        !roms.is_empty(),
+        "No roms in {}, check out the nes-test-roms submodule",
+        TEST_ROM_DIR
+    );
+    for listed in EXPECTED_FAILURES.iter().chain(LEGACY_ROMS) {
+        assert!(
+            roms.iter().any(|rom| rom == listed),
+            "{} is listed but not in {}",
+            listed,
+            TEST_ROM_DIR
+        );
+    }
+
+    let mut problems = Vec::new();
+    for rom in &roms {
+        let path = Path::new(TEST_ROM_DIR).join(rom);
+        let result = if LEGACY_ROMS.contains(&rom.as_str()) {
+            run_legacy_rom(&path)
+        } else {
+            run_blargg_rom(&path)
+        };
+        let expected_failure = EXPECTED_FAILURES.contains(&rom.as_str());
+
+        let problem = match (result, expected_failure) {
+            (BlarggResult::Skipped(reason), _) => {
+                println!("{}: skipped, {}", rom, reason);
+                None
+            }
+            (BlarggResult::Passed, false) => {
+                println!("{}: passed", rom);
+                None
+            }
+            (BlarggResult::Passed, true) => Some(format!(
+                "{} passes now, remove it from EXPECTED_FAILURES",
+                rom
+            )),
+            (result, true) => {
+                println!("{}: failed as expected, {:?}", rom, result);
+                None
+            }
+            (BlarggResult::Failed(code, text), false) => {
+                Some(format!("{} failed with code {}:\n{}", rom, code, text))
+            }
+            (BlarggResult::Timeout(text), false) => Some(format!(
+                "{} didn't finish in {} frames:\n{}",
+                rom, MAX_FRAMES, text
+            )),
+            (BlarggResult::Crashed, false) => Some(format!("{} crashed the emulator", rom)),
+            (BlarggResult::LoadFailed(e), false) => Some(format!("{} failed to load: {}", rom, e)),
+        };
+        if let Some(problem) = problem {
+            println!("{}: FAILED", rom);
+            problems.push(problem);
+        }
+    }
+    assert!(problems.is_empty(), "{}", problems.join("\n\n"));
+}
+
+// nestest.nes runs without a ppu from $C000 and its log is the reference
+// trace for the cpu.
+const NESTEST_ROM: &str = "other/nestest.nes";
+const NESTEST_LOG: &str = "other/nestest.log";
+const NESTEST_START: u16 = 0xC000;
+
+// Older copies of nestest.log use "CYC:x SL:y" for ppu timing, only the
+// registers can be compared against those.
+fn cpu_state(line: &str) -> &str {
+    line.split(" PPU:").next().unwrap().split(" CYC:").next().unwrap()
+}
+
+#[test]
+#[ignore = "needs the nes-test-roms submodule"]
+fn nestest() {
+    let rom = Path::new(TEST_ROM_DIR).join(NESTEST_ROM);
+    let log = fs::read_to_string(Path::new(TEST_ROM_DIR).join(NESTEST_LOG))
+        .expect("nestest.log not found, check out the nes-test-roms submodule");
+
+    let mut nes = Nes::new();
+    nes.load_rom(rom.to_string_lossy().into_owned())
+        .expect("Failed to load nestest");
+    nes.cpu.set_pc(NESTEST_START);
+
+    for (line_no, expected) in log.lines().enumerate() {
+        let expected = expected.trim_end();
+        let actual = nes.trace_line();
+        let matches = if expected.contains(" PPU:") {
+            actual == expected
+        } else {
+            cpu_state(&actual) == cpu_state(expected)
+        };
+        assert!(
+            matches,
+            "nestest diverged at line {}:\nexpected: {}\nactual:   {}",
+            line_no + 1,
+            expected,
+            actual
+        );
+        // Stop at the first instruction the cpu can't run rather than
+        // unwinding out of the middle of the comparison
+        if panic::catch_unwind(AssertUnwindSafe(|| nes.step())).is_err() {
+            panic!(
+                "nestest stopped at line {}, instruction not supported:\n{}",
+                line_no + 1,
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,83 @@
+// Instruction tracing in the format of nestest.log:
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+
+use super::cpu::{AddressMode, Registers};
+use super::disasm::{self, Instruction, Symbols};
+use super::memory::MemoryMap;
+
+fn peek_word_zpg(mem: &MemoryMap, addr: u8) -> u16 {
+    // Pointers in the zero page wrap around within it
+    (mem.peek_byte(addr as u16) as u16) | ((mem.peek_byte(addr.wrapping_add(1) as u16) as u16) << 8)
+}
+
+// nestest shows the effective address and the value there
+fn annotation(inst: &Instruction, regs: &Registers, mem: &MemoryMap) -> String {
+    let Some(target) = inst.target else {
+        return String::new();
+    };
+    match inst.mode {
+        AddressMode::Zpg => format!(" = {:02X}", mem.peek_byte(target)),
+        AddressMode::ZpgX | AddressMode::ZpgY => {
+            let index = if inst.mode == AddressMode::ZpgX { regs.x } else { regs.y };
+            let addr = (target as u8).wrapping_add(index);
+            format!(" @ {:02X} = {:02X}", addr, mem.peek_byte(addr as u16))
+        }
+        AddressMode::Abs if inst.mnemonic == "JMP" || inst.mnemonic == "JSR" => String::new(),
+        AddressMode::Abs => format!(" = {:02X}", mem.peek_byte(target)),
+        AddressMode::AbsX | AddressMode::AbsY => {
+            let index = if inst.mode == AddressMode::AbsX { regs.x } else { regs.y };
+            let addr = target.wrapping_add(index as u16);
+            format!(" @ {:04X} = {:02X}", addr, mem.peek_byte(addr))
+        }
+        AddressMode::Ind => {
+            // Same page wrapping bug as the cpu
+            let hi_loc = (target & 0xFF00) | (target.wrapping_add(1) & 0x00FF);
+            let addr = (mem.peek_byte(target) as u16) | ((mem.peek_byte(hi_loc) as u16) << 8);
+            format!(" = {:04X}", addr)
+        }
+        AddressMode::IndX => {
+            let ptr = (target as u8).wrapping_add(regs.x);
+            let addr = peek_word_zpg(mem, ptr);
+            format!(" @ {:02X} = {:04X} = {:02X}", ptr, addr, mem.peek_byte(addr))
+        }
+        AddressMode::IndY => {
+            let base = peek_word_zpg(mem, target as u8);
+            let addr = base.wrapping_add(regs.y as u16);
+            format!(" = {:04X} @ {:04X} = {:02X}", base, addr, mem.peek_byte(addr))
+        }
+        _ => String::new(),
+    }
+}
+
+pub(super) fn nestest_line(regs: &Registers, mem: &MemoryMap, cycles: u64) -> String {
+    let pc = regs.pc;
+    let raw = [
+        mem.peek_byte(pc),
+        mem.peek_byte(pc.wrapping_add(1)),
+        mem.peek_byte(pc.wrapping_add(2)),
+    ];
+    let inst = disasm::disassemble(pc, &raw, &Symbols::new());
+    let bytes = inst
+        .bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let text = format!("{}{}", inst, annotation(&inst, regs, mem));
+    let (scanline, dot) = mem.ppu_position();
+    format!(
+        "{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        pc,
+        bytes,
+        if inst.official { ' ' } else { '*' },
+        text,
+        regs.a,
+        regs.x,
+        regs.y,
+        regs.p,
+        regs.s,
+        scanline,
+        dot,
+        cycles
+    )
+}
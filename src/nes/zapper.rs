@@ -0,0 +1,130 @@
+// Zapper light gun, normally in port 2. $4017 reads give the light sensor in
+// D3 (0 when it sees light) and the trigger in D4. The photodiode only
+// reacts while the beam passes under the aimed spot and keeps reporting
+// light for a few scanlines after, so games flash targets white and poll
+// during the next frame.
+
+use super::input::{Beam, InputDevice};
+
+// How long the sensor stays lit after the beam passes the aimed pixel
+const LIGHT_SCANLINES: usize = 26;
+// The sensor sees a small area rather than one pixel
+const SENSE_RADIUS: i64 = 2;
+// Brightness (0 to 255) that counts as light
+const LIGHT_THRESHOLD: u32 = 0xA0;
+const VISIBLE_SCANLINES: usize = 240;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct ZapperState {
+    // Pixel on the 256x240 frame, None when pointing off screen
+    pub aim: Option<(u32, u32)>,
+    pub trigger: bool,
+}
+
+#[derive(Default)]
+pub struct Zapper {
+    state: ZapperState,
+    light: bool,
+}
+
+impl Zapper {
+    pub fn set_state(&mut self, state: ZapperState) {
+        self.state = state
+    }
+}
+
+fn brightness(beam: &Beam, x: i64, y: i64) -> u32 {
+    let (width, height) = beam.frame.dimensions();
+    if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+        return 0;
+    }
+    let [r, g, b] = beam.frame.get_pixel(x as u32, y as u32).0;
+    (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000
+}
+
+// Whether the beam drew (x, y) recently enough for the sensor to notice
+fn recently_drawn(beam: &Beam, x: i64, y: i64) -> bool {
+    let (scanline, dot) = (beam.scanline as i64, beam.dot as i64);
+    if beam.scanline >= VISIBLE_SCANLINES + LIGHT_SCANLINES {
+        return false;
+    }
+    // Dot 0 is idle, pixel x comes out on dot x + 1
+    (scanline == y && dot > x + 1) || (scanline > y && scanline < y + LIGHT_SCANLINES as i64)
+}
+
+impl InputDevice for Zapper {
+    fn write(&mut self, _val: u8) {}
+
+    fn read(&mut self) -> u8 {
+        let mut val = 0;
+        if !self.light {
+            val |= 0x08;
+        }
+        if self.state.trigger {
+            val |= 0x10;
+        }
+        val
+    }
+
+    fn sense_light(&mut self, beam: &Beam) {
+        let Some((x, y)) = self.state.aim else {
+            self.light = false;
+            return;
+        };
+        let (x, y) = (x as i64, y as i64);
+        self.light = (-SENSE_RADIUS..=SENSE_RADIUS).any(|dy| {
+            (-SENSE_RADIUS..=SENSE_RADIUS).any(|dx| {
+                recently_drawn(beam, x + dx, y + dy)
+                    && brightness(beam, x + dx, y + dy) >= LIGHT_THRESHOLD
+            })
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::super::input::{Beam, InputDevice};
+    use super::{Zapper, ZapperState};
+
+    #[test]
+    fn test_zapper() {
+        let mut frame = RgbImage::new(256, 240);
+        for y in 100..110 {
+            for x in 50..60 {
+                frame.put_pixel(x, y, Rgb([0xFF, 0xFF, 0xFF]));
+            }
+        }
+        let mut zapper = Zapper::default();
+        let read = |zapper: &mut Zapper, scanline, dot| {
+            zapper.sense_light(&Beam {
+                frame: &frame,
+                scanline,
+                dot,
+                dots: 0,
+            });
+            zapper.read()
+        };
+        assert_eq!(read(&mut zapper, 120, 0), 0x08);
+
+        zapper.set_state(ZapperState {
+            aim: Some((55, 105)),
+            trigger: true,
+        });
+        // Not drawn yet this frame
+        assert_eq!(read(&mut zapper, 90, 0), 0x18);
+        assert_eq!(read(&mut zapper, 103, 10), 0x18);
+        // The edge of the target comes into view
+        assert_eq!(read(&mut zapper, 103, 60), 0x10);
+        assert_eq!(read(&mut zapper, 120, 0), 0x10);
+        // Faded again
+        assert_eq!(read(&mut zapper, 140, 0), 0x18);
+
+        zapper.set_state(ZapperState {
+            aim: Some((150, 105)),
+            trigger: false,
+        });
+        assert_eq!(read(&mut zapper, 120, 0), 0x08);
+    }
+}